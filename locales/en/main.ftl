@@ -0,0 +1,17 @@
+# Shown in the navigation bar.
+nav-signed-in = Signed in as { $email }
+
+# Page titles, also used as nav labels and breadcrumbs.
+page-home = Home
+page-about = About
+# Only the login handler uses it.
+# lint: used
+page-login = Log in
+
+# An example of plural selection, not shown on any page yet.
+# lint: used
+cart_items = { $count ->
+    [0] Your cart is empty
+    [one] One item in your cart
+   *[other] { $count } items in your cart
+}
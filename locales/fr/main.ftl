@@ -0,0 +1,17 @@
+# Shown in the navigation bar.
+nav-signed-in = Connecté en tant que { $email }
+
+# Page titles, also used as nav labels and breadcrumbs.
+page-home = Accueil
+page-about = À propos
+# Only the login handler uses it.
+# lint: used
+page-login = Connexion
+
+# An example of plural selection, not shown on any page yet.
+# lint: used
+cart_items = { $count ->
+    [0] Votre panier est vide
+    [one] { $count } article dans votre panier
+   *[other] { $count } articles dans votre panier
+}
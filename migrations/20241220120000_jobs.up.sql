@@ -0,0 +1,27 @@
+-- Durable background jobs, claimed by workers with `FOR UPDATE SKIP LOCKED`.
+create table jobs
+(
+    job_id       uuid primary key     default uuid_generate_v4(),
+    name         text        not null,
+    payload      jsonb       not null default 'null',
+    status       text        not null default 'pending'
+        check (status in ('pending', 'running', 'succeeded', 'dead')),
+    -- Jobs sharing a key are deduplicated while one of them is still pending or running.
+    unique_key   text,
+    attempts     int         not null default 0,
+    max_attempts int         not null default 3,
+    scheduled_at timestamptz not null default now(),
+    locked_at    timestamptz,
+    locked_by    text,
+    last_error   text,
+    created_at   timestamptz not null default now(),
+    updated_at   timestamptz
+);
+
+select trigger_updated_at('jobs');
+
+create unique index jobs_unique_key_idx on jobs (unique_key)
+    where unique_key is not null and status in ('pending', 'running');
+
+create index jobs_pending_idx on jobs (scheduled_at)
+    where status = 'pending';
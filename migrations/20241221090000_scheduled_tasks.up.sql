@@ -0,0 +1,11 @@
+-- Last run of each recurring task, so runs missed during downtime can be detected.
+create table scheduled_tasks
+(
+    name         text primary key,
+    last_run_at  timestamptz,
+    last_outcome jsonb,
+    created_at   timestamptz not null default now(),
+    updated_at   timestamptz
+);
+
+select trigger_updated_at('scheduled_tasks');
@@ -0,0 +1,25 @@
+create table users
+(
+    user_id       uuid primary key     default uuid_generate_v4(),
+    email         text collate "case_insensitive" unique not null,
+    password_hash text        not null,
+    created_at    timestamptz not null default now(),
+    updated_at    timestamptz
+);
+
+select trigger_updated_at('users');
+
+-- The session ID is the value of the signed session cookie.
+create table sessions
+(
+    session_id uuid primary key     default uuid_generate_v4(),
+    user_id    uuid        not null references users (user_id) on delete cascade,
+    expires_at timestamptz not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz
+);
+
+select trigger_updated_at('sessions');
+
+create index sessions_user_id_idx on sessions (user_id);
+create index sessions_expires_at_idx on sessions (expires_at);
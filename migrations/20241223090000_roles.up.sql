@@ -0,0 +1,45 @@
+create table roles
+(
+    name        text primary key,
+    description text        not null default '',
+    created_at  timestamptz not null default now(),
+    updated_at  timestamptz
+);
+
+select trigger_updated_at('roles');
+
+create table permissions
+(
+    name        text primary key,
+    description text        not null default '',
+    created_at  timestamptz not null default now(),
+    updated_at  timestamptz
+);
+
+select trigger_updated_at('permissions');
+
+create table role_permissions
+(
+    role       text        not null references roles (name) on delete cascade,
+    permission text        not null references permissions (name) on delete cascade,
+    created_at timestamptz not null default now(),
+    primary key (role, permission)
+);
+
+create table user_roles
+(
+    user_id    uuid        not null references users (user_id) on delete cascade,
+    role       text        not null references roles (name) on delete cascade,
+    created_at timestamptz not null default now(),
+    primary key (user_id, role)
+);
+
+insert into roles (name, description)
+values ('admin', 'Full access to admin pages and debugging routes');
+
+insert into permissions (name, description)
+values ('admin.read', 'View admin pages'),
+       ('debug.panic', 'Trigger the test panic route');
+
+insert into role_permissions (role, permission)
+select 'admin', name from permissions;
@@ -0,0 +1,15 @@
+-- Panics caught while handling requests.
+create table incidents
+(
+    incident_id    uuid primary key,
+    message        text        not null,
+    -- `file:line:column` of the panic, when known.
+    location       text        not null default 'unknown',
+    backtrace      text        not null default '',
+    method         text        not null,
+    path           text        not null,
+    correlation_id text        not null,
+    created_at     timestamptz not null default now()
+);
+
+create index incidents_location_idx on incidents (location, created_at desc);
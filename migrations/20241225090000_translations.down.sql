@@ -0,0 +1,2 @@
+delete from permissions where name = 'translations.reload';
+drop table if exists translations;
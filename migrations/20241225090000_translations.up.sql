@@ -0,0 +1,21 @@
+-- Copy shown on the site, editable without a redeploy.
+create table translations
+(
+    locale     text        not null,
+    key        text        not null,
+    value      text        not null,
+    updated_at timestamptz not null default now(),
+    primary key (locale, key)
+);
+
+select trigger_updated_at('translations');
+
+insert into translations (locale, key, value)
+values ('en', 'site_name_short', 'Nosferatu'),
+       ('en', 'site_description', 'Static site with Axum and Askama');
+
+insert into permissions (name, description)
+values ('translations.reload', 'Reload translations from the database');
+
+insert into role_permissions (role, permission)
+values ('admin', 'translations.reload');
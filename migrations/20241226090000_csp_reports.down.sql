@@ -0,0 +1 @@
+drop table if exists csp_reports;
@@ -0,0 +1,21 @@
+-- Content-Security-Policy violations reported by browsers.
+create table csp_reports
+(
+    id                  bigint generated always as identity primary key,
+    document_uri        text        not null default '',
+    blocked_uri         text        not null default '',
+    -- The directive that was violated, e.g. `script-src-elem`.
+    effective_directive text        not null default '',
+    -- `enforce`, or `report` under Content-Security-Policy-Report-Only.
+    disposition         text        not null default 'enforce',
+    source_file         text,
+    line_number         integer,
+    column_number       integer,
+    sample              text,
+    user_agent          text,
+    -- The report as sent.
+    body                jsonb       not null,
+    created_at          timestamptz not null default now()
+);
+
+create index csp_reports_directive_idx on csp_reports (effective_directive, created_at desc);
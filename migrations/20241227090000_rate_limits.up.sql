@@ -0,0 +1,7 @@
+-- Rate limiter state shared between nodes: the theoretical arrival time of
+-- the next request per key, as in GCRA.
+create table rate_limits
+(
+    key text primary key,
+    tat timestamptz not null
+);
@@ -0,0 +1,2 @@
+-- The request a job was enqueued from, to trace its failures back to it.
+alter table jobs add column correlation_id text;
@@ -0,0 +1,7 @@
+-- Each session has its own CSRF token, so logging in replaces whatever token the
+-- browser held before. Existing sessions get a random one.
+alter table sessions
+    add column csrf_token text not null
+        default replace(uuid_generate_v4()::text || uuid_generate_v4()::text, '-', '');
+
+alter table sessions alter column csrf_token drop default;
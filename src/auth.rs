@@ -0,0 +1,224 @@
+//! User authentication with server-side sessions.
+//!
+//! Logging in stores a row in the `sessions` table and hands the browser its
+//! ID in a signed, `HttpOnly` cookie. Handlers receive the logged in user
+//! through the [`CurrentUser`] and [`MaybeUser`] extractors; the session is
+//! looked up at most once per request, see [`Sessions::resolve`].
+
+use crate::config::layers::FromConfigValue;
+use crate::error::Error;
+use crate::models::roles;
+use crate::models::users::{self, Session, User};
+use crate::mpsc::jobs::{Job, JobContext};
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::{request::Parts, HeaderMap};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum_extra::extract::cookie::{Cookie, Key, SameSite, SignedCookieJar};
+use serde_json::Value;
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub mod csrf;
+pub mod password;
+pub mod permissions;
+
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// Signs session cookies; at least 64 bytes. A random key is generated when
+    /// unset, which logs everyone out on restart.
+    pub session_secret: Option<Key>,
+    /// Seconds a session lasts after logging in.
+    pub session_ttl: u64,
+    pub cookie_name: String,
+    /// Only send the session cookie over HTTPS.
+    pub cookie_secure: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            session_secret: None,
+            session_ttl: 14 * 24 * 60 * 60,
+            cookie_name: "nosferatu_session".to_string(),
+            cookie_secure: false,
+        }
+    }
+}
+
+impl FromConfigValue for Key {
+    fn from_text(text: &str) -> Result<Self, String> {
+        Key::try_from(text.trim().as_bytes())
+            .map_err(|_| "expected a secret of at least 64 bytes".to_string())
+    }
+}
+
+/// Creates, resolves and ends sessions; shared with handlers as an
+/// `Extension`.
+#[derive(Clone)]
+pub struct Sessions {
+    pool: PgPool,
+    key: Key,
+    config: AuthConfig,
+}
+
+impl Sessions {
+    pub fn new(pool: PgPool, config: &AuthConfig) -> Self {
+        let key = config.session_secret.clone().unwrap_or_else(|| {
+            tracing::warn!("auth.session_secret is not set; sessions will not survive a restart");
+            Key::generate()
+        });
+
+        Self {
+            pool,
+            key,
+            config: config.clone(),
+        }
+    }
+
+    pub fn jar(&self, headers: &HeaderMap) -> SignedCookieJar {
+        SignedCookieJar::from_headers(headers, self.key.clone())
+    }
+
+    /// Check the credentials and start a session, returning the jar holding its
+    /// cookie. `None` when the email or password is wrong.
+    pub async fn login(
+        &self,
+        jar: SignedCookieJar,
+        email: &str,
+        password: &str,
+    ) -> Result<Option<(SignedCookieJar, User)>, Error> {
+        let found = users::find_credentials(&self.pool, email).await?;
+        let (user, hash) = match found {
+            Some((user, hash)) => (Some(user), Some(hash)),
+            None => (None, None),
+        };
+
+        let verified = password::verify_password(password.to_string(), hash).await?;
+        let Some(user) = user.filter(|_| verified) else {
+            return Ok(None);
+        };
+
+        let ttl = Duration::from_secs(self.config.session_ttl);
+        let csrf_token = csrf::CsrfToken::generate();
+        let session_id =
+            users::create_session(&self.pool, user.user_id, ttl, &csrf_token.0).await?;
+        let cookie = Cookie::build((self.config.cookie_name.clone(), session_id.to_string()))
+            .path("/")
+            .http_only(true)
+            .secure(self.config.cookie_secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(self.config.session_ttl as i64));
+
+        Ok(Some((csrf::rotate(jar).add(cookie), user)))
+    }
+
+    /// End the session in `jar`, if any, and remove its cookie.
+    pub async fn logout(&self, jar: SignedCookieJar) -> Result<SignedCookieJar, Error> {
+        if let Some(session_id) = self.session_id(&jar) {
+            users::delete_session(&self.pool, session_id).await?;
+        }
+
+        let jar = jar.remove(Cookie::build(self.config.cookie_name.clone()).path("/"));
+
+        Ok(csrf::rotate(jar))
+    }
+
+    /// The user owning the session in `jar`, if it is valid.
+    pub async fn user(&self, jar: &SignedCookieJar) -> Result<Option<User>, Error> {
+        Ok(self.session(jar).await?.map(|session| session.user))
+    }
+
+    /// The session in `jar`, if it is valid.
+    pub async fn session(&self, jar: &SignedCookieJar) -> Result<Option<Session>, Error> {
+        match self.session_id(jar) {
+            Some(session_id) => users::find_session(&self.pool, session_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// The session of the request, looked up on first use and kept in its
+    /// extensions for the layers and extractors after.
+    pub async fn resolve(&self, parts: &mut Parts) -> Result<Option<Session>, Error> {
+        if let Some(ResolvedSession(session)) = parts.extensions.get::<ResolvedSession>() {
+            return Ok(session.clone());
+        }
+
+        let session = self.session(&self.jar(&parts.headers)).await?;
+        parts.extensions.insert(ResolvedSession(session.clone()));
+
+        Ok(session)
+    }
+
+    /// Every permission granted to `user` through their roles.
+    pub async fn permissions(&self, user: &User) -> Result<HashSet<String>, Error> {
+        roles::permissions_for(&self.pool, user.user_id).await
+    }
+
+    fn session_id(&self, jar: &SignedCookieJar) -> Option<Uuid> {
+        jar.get(&self.config.cookie_name)
+            .and_then(|cookie| cookie.value().parse().ok())
+    }
+}
+
+#[derive(Clone)]
+struct ResolvedSession(Option<Session>);
+
+/// The logged in user, if any.
+#[derive(Debug, Clone)]
+pub struct MaybeUser(pub Option<User>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for MaybeUser {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let sessions = parts
+            .extensions
+            .get::<Sessions>()
+            .cloned()
+            .ok_or_else(|| Error::new("Sessions extension is missing"))?;
+        let session = sessions.resolve(parts).await?;
+
+        Ok(MaybeUser(session.map(|session| session.user)))
+    }
+}
+
+/// The logged in user; anonymous requests are redirected to `/login`.
+#[derive(Debug, Clone)]
+pub struct CurrentUser(pub User);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match MaybeUser::from_request_parts(parts, state).await {
+            Ok(MaybeUser(Some(user))) => Ok(CurrentUser(user)),
+            Ok(MaybeUser(None)) => Err(Redirect::to("/login").into_response()),
+            Err(err) => Err(err.into_response()),
+        }
+    }
+}
+
+/// Delete expired sessions; schedule it as a recurring task.
+pub struct PruneSessions;
+
+#[async_trait]
+impl Job for PruneSessions {
+    const NAME: &'static str = "prune_sessions";
+    type Payload = ();
+
+    async fn run(&self, ctx: &JobContext, _payload: ()) -> Result<Value, Error> {
+        let pool = ctx
+            .config
+            .pg_pool
+            .as_ref()
+            .ok_or_else(|| Error::new("Postgres is not configured"))?;
+        let deleted = users::delete_expired_sessions(pool).await?;
+
+        Ok(Value::from(deleted))
+    }
+}
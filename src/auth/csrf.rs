@@ -0,0 +1,428 @@
+//! Cross-site request forgery protection.
+//!
+//! Every session has its own random token, stored with it and created when
+//! someone logs in, so no token a browser held before survives logging in.
+//! Visitors without a session get one in a signed cookie instead, which is
+//! replaced when they log in or out. Unsafe requests must echo the token back,
+//! either as the `csrf_token` form field or in the `X-CSRF-Token` header; never
+//! in the URL, where it would leak into logs and `Referer` headers. Templates
+//! render the field with [`csrf_input`](crate::content::templates::csrf_input).
+//!
+//! Requests under a path registered with [`CsrfLayer::allow_bearer`] skip the
+//! check when they carry an `Authorization: Bearer` header, since browsers never
+//! attach one on their own; those routes must authenticate the token themselves.
+//! Paths registered with [`CsrfLayer::exempt`] are never checked.
+
+use super::Sessions;
+use crate::content::templates::page::PageContext;
+use crate::content::templates::{CsrfErrorTemplate, HtmlTemplate};
+use crate::error::Error;
+use crate::models::users::Session;
+use crate::server::common::wants_json;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::body::{self, Body};
+use axum::extract::Request;
+use axum::http::{header, request::Parts, HeaderMap, HeaderName};
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::{Cookie, SameSite, SignedCookieJar};
+use futures::future::BoxFuture;
+use hyper::StatusCode;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+pub const CSRF_COOKIE: &str = "nosferatu_csrf";
+pub const CSRF_FIELD: &str = "csrf_token";
+pub static CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+
+tokio::task_local! {
+    static CURRENT_TOKEN: CsrfToken;
+}
+
+/// The token expected back from the current browser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+impl CsrfToken {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+
+        CsrfToken(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// The token of the request being handled, if it passed through
+    /// [`CsrfLayer`].
+    pub fn current() -> Option<CsrfToken> {
+        CURRENT_TOKEN.try_with(|token| token.clone()).ok()
+    }
+
+    /// The session's token, or else the one in the visitor's cookie.
+    fn expected(session: Option<&Session>, issued: Option<CsrfToken>) -> Self {
+        match session {
+            Some(session) => CsrfToken(session.csrf_token.clone()),
+            None => issued.unwrap_or_else(CsrfToken::generate),
+        }
+    }
+
+    fn matches(&self, submitted: &str) -> bool {
+        let (expected, submitted) = (self.0.as_bytes(), submitted.as_bytes());
+
+        // Compare every byte so timing does not reveal the matching prefix.
+        !expected.is_empty()
+            && expected.len() == submitted.len()
+            && expected
+                .iter()
+                .zip(submitted)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Remove the visitor token cookie, so that a fresh one is issued on the next
+/// request without a session.
+pub fn rotate(jar: SignedCookieJar) -> SignedCookieJar {
+    jar.remove(Cookie::build(CSRF_COOKIE).path("/").http_only(true))
+}
+
+#[derive(Clone)]
+pub struct CsrfLayer {
+    sessions: Sessions,
+    body_limit: usize,
+    bearer_paths: Arc<Vec<&'static str>>,
+    exempt_paths: Arc<Vec<&'static str>>,
+}
+
+impl CsrfLayer {
+    /// `body_limit` caps how much of a form body is buffered to find the token.
+    pub fn new(sessions: Sessions, body_limit: usize) -> Self {
+        Self {
+            sessions,
+            body_limit,
+            bearer_paths: Arc::new(Vec::new()),
+            exempt_paths: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Let requests under `prefix` opt out with an `Authorization: Bearer` header.
+    pub fn allow_bearer(mut self, prefix: &'static str) -> Self {
+        Arc::make_mut(&mut self.bearer_paths).push(prefix);
+        self
+    }
+
+    /// Skip the check for `path`, for endpoints browsers post to without a
+    /// token, such as CSP reports; they must not act on the user's behalf.
+    pub fn exempt(mut self, path: &'static str) -> Self {
+        Arc::make_mut(&mut self.exempt_paths).push(path);
+        self
+    }
+}
+
+impl<S> Layer<S> for CsrfLayer {
+    type Service = CsrfService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CsrfService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CsrfService<S> {
+    inner: S,
+    layer: CsrfLayer,
+}
+
+impl<S> Service<Request> for CsrfService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let session = match layer.sessions.resolve(&mut parts).await {
+                Ok(session) => session,
+                Err(err) if parts.method.is_safe() => {
+                    // Nothing to protect; handlers needing the user will retry.
+                    tracing::warn!("Unable to look up the session: {}", err);
+                    None
+                }
+                Err(err) => return Ok(err.into_response()),
+            };
+            let request = Request::from_parts(parts, body);
+
+            let jar = layer.sessions.jar(request.headers());
+            let issued = jar
+                .get(CSRF_COOKIE)
+                .map(|cookie| CsrfToken(cookie.value().to_string()));
+            let token = CsrfToken::expected(session.as_ref(), issued.clone());
+
+            let request = if request.method().is_safe()
+                || layer.bearer_exempt(&request)
+                || layer.exempt_paths.contains(&request.uri().path())
+            {
+                request
+            } else {
+                let (parts, body) = request.into_parts();
+                let (submitted, body) = match submitted_token(&parts, body, layer.body_limit).await
+                {
+                    Ok(found) => found,
+                    Err(status) => return Ok(status.into_response()),
+                };
+
+                if !submitted.is_some_and(|submitted| token.matches(&submitted)) {
+                    tracing::warn!(
+                        "Rejected {} {} with a missing or invalid CSRF token",
+                        parts.method,
+                        parts.uri.path()
+                    );
+                    return Ok(rejected(&parts.headers));
+                }
+
+                Request::from_parts(parts, body)
+            };
+
+            let response = CURRENT_TOKEN
+                .scope(token.clone(), inner.call(request))
+                .await?;
+
+            if session.is_some() || issued.is_some() {
+                return Ok(response);
+            }
+
+            let cookie = Cookie::build((CSRF_COOKIE, token.0))
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax);
+
+            Ok((jar.add(cookie), response).into_response())
+        })
+    }
+}
+
+impl CsrfLayer {
+    fn bearer_exempt(&self, request: &Request) -> bool {
+        let path = request.uri().path();
+        let bearer = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("Bearer "));
+
+        bearer
+            && self
+                .bearer_paths
+                .iter()
+                .any(|prefix| path.starts_with(prefix))
+    }
+}
+
+#[derive(Deserialize)]
+struct Submitted {
+    csrf_token: Option<String>,
+}
+
+/// Find the submitted token in the header or a urlencoded body, handing back
+/// the body for the inner service.
+async fn submitted_token(
+    parts: &Parts,
+    body: Body,
+    limit: usize,
+) -> Result<(Option<String>, Body), StatusCode> {
+    if let Some(value) = parts.headers.get(&CSRF_HEADER) {
+        return Ok((value.to_str().ok().map(str::to_string), body));
+    }
+
+    let is_form = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((None, body));
+    }
+
+    let bytes = body::to_bytes(body, limit)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    let submitted = serde_urlencoded::from_bytes::<Submitted>(&bytes)
+        .ok()
+        .and_then(|submitted| submitted.csrf_token);
+
+    Ok((submitted, Body::from(bytes)))
+}
+
+fn rejected(headers: &HeaderMap) -> Response {
+    if wants_json(headers) {
+        return Error::forbidden("Missing or invalid CSRF token").into_response();
+    }
+
+    (
+        StatusCode::FORBIDDEN,
+        HtmlTemplate(CsrfErrorTemplate {
+            user: None,
+            page: PageContext::new("error").title("Form expired").noindex(),
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::AuthConfig;
+    use axum::routing::post;
+    use axum::Router;
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    fn app() -> (Router, Sessions) {
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let sessions = Sessions::new(pool, &AuthConfig::default());
+        let layer = CsrfLayer::new(sessions.clone(), 1024).allow_bearer("/api/");
+
+        let router = Router::new()
+            .route(
+                "/form",
+                post(|| async { CsrfToken::current().unwrap().0 }).get(|| async { "form" }),
+            )
+            .route("/api/items", post(|| async { "created" }))
+            .layer(layer);
+
+        (router, sessions)
+    }
+
+    /// Issue a token with a GET, returning it and its signed cookie.
+    async fn issue(router: &Router, sessions: &Sessions) -> (String, String) {
+        let response = router
+            .clone()
+            .oneshot(Request::get("/form").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, cookie.parse().unwrap());
+        let token = sessions.jar(&headers).get(CSRF_COOKIE).unwrap();
+
+        (token.value().to_string(), cookie)
+    }
+
+    fn form_post(uri: &str, cookie: &str, body: String) -> Request {
+        Request::post(uri)
+            .header(header::COOKIE, cookie)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_only_the_issued_token() {
+        let (router, sessions) = app();
+        let (token, cookie) = issue(&router, &sessions).await;
+
+        let response = router
+            .clone()
+            .oneshot(form_post(
+                "/form",
+                &cookie,
+                format!("a=1&csrf_token={}", token),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(body, token.as_bytes());
+
+        for body in ["a=1".to_string(), "csrf_token=forged".to_string()] {
+            let response = router
+                .clone()
+                .oneshot(form_post("/form", &cookie, body))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+
+        // Tokens in the URL leak into logs, so they do not count.
+        let response = router
+            .clone()
+            .oneshot(form_post(
+                &format!("/form?csrf_token={}", token),
+                &cookie,
+                "a=1".to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = Request::post("/form")
+            .header(header::COOKIE, &cookie)
+            .header(&CSRF_HEADER, &token)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn sessions_use_their_own_token() {
+        let planted = CsrfToken("planted".to_string());
+        let session = Session {
+            user: crate::models::users::User {
+                user_id: uuid::Uuid::new_v4(),
+                email: "orlok@example.com".to_string(),
+                created_at: chrono::Utc::now(),
+            },
+            csrf_token: "session".to_string(),
+        };
+
+        assert_eq!(
+            CsrfToken::expected(Some(&session), Some(planted.clone())).0,
+            "session"
+        );
+        assert_eq!(CsrfToken::expected(None, Some(planted.clone())), planted);
+        assert!(!CsrfToken(String::new()).matches(""));
+    }
+
+    #[tokio::test]
+    async fn bearer_requests_opt_out_only_where_allowed() {
+        let (router, _) = app();
+
+        for (uri, status) in [
+            ("/api/items", StatusCode::OK),
+            ("/form", StatusCode::FORBIDDEN),
+        ] {
+            let request = Request::post(uri)
+                .header(header::AUTHORIZATION, "Bearer abc")
+                .header(header::ACCEPT, "application/json")
+                .body(Body::empty())
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+    }
+}
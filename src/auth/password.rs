@@ -0,0 +1,62 @@
+//! Argon2 password hashing.
+//!
+//! Hashing is deliberately slow, so it runs on the blocking thread pool.
+
+use crate::error::Error;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordVerifier};
+use std::sync::LazyLock;
+
+/// Verified against when an email is unknown, so that failed logins take as
+/// long whether or not the account exists.
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_blocking("not a real password").expect("Hashes the dummy password"));
+
+pub async fn hash_password(password: String) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || hash_blocking(&password)).await?
+}
+
+/// Check `password` against `hash`; a missing hash never verifies.
+pub async fn verify_password(password: String, hash: Option<String>) -> Result<bool, Error> {
+    tokio::task::spawn_blocking(move || {
+        let known = hash.is_some();
+        let hash = hash.unwrap_or_else(|| DUMMY_HASH.clone());
+        let parsed = PasswordHash::new(&hash).map_err(Error::new)?;
+        let verified = Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok();
+
+        Ok(known && verified)
+    })
+    .await?
+}
+
+fn hash_blocking(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(Error::new)?;
+
+    Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn verifies_only_the_hashed_password() {
+        let hash = hash_password("hunter22".to_string()).await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+
+        assert!(verify_password("hunter22".to_string(), Some(hash.clone()))
+            .await
+            .unwrap());
+        assert!(!verify_password("hunter2".to_string(), Some(hash))
+            .await
+            .unwrap());
+        assert!(!verify_password("not a real password".to_string(), None)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,190 @@
+//! Role-based route guards.
+//!
+//! Attach [`RequirePermission`] to a route with `route_layer`, or take
+//! [`Grants`] in a handler to check permissions by hand. Either way the
+//! user's grants are loaded once per request.
+
+use super::{MaybeUser, Sessions};
+use crate::error::Error;
+use crate::models::users::User;
+use async_trait::async_trait;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use futures::future::BoxFuture;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// The current user and the permissions their roles grant.
+#[derive(Debug, Clone, Default)]
+pub struct Grants {
+    pub user: Option<User>,
+    permissions: Arc<HashSet<String>>,
+}
+
+impl Grants {
+    pub fn allows(&self, permission: &str) -> bool {
+        self.permissions.contains(permission)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Grants {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(grants) = parts.extensions.get::<Grants>() {
+            return Ok(grants.clone());
+        }
+
+        let MaybeUser(user) = MaybeUser::from_request_parts(parts, state).await?;
+        let permissions = match (&user, parts.extensions.get::<Sessions>()) {
+            (Some(user), Some(sessions)) => sessions.permissions(user).await?,
+            _ => HashSet::new(),
+        };
+
+        let grants = Grants {
+            user,
+            permissions: Arc::new(permissions),
+        };
+        parts.extensions.insert(grants.clone());
+
+        Ok(grants)
+    }
+}
+
+/// Reject requests from users without the named permission with a
+/// [`Forbidden`](crate::error::ErrorKind::Forbidden) error.
+#[derive(Debug, Clone, Copy)]
+pub struct RequirePermission(pub &'static str);
+
+impl<S> Layer<S> for RequirePermission {
+    type Service = RequirePermissionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequirePermissionService {
+            inner,
+            permission: self.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequirePermissionService<S> {
+    inner: S,
+    permission: &'static str,
+}
+
+impl<S> Service<Request> for RequirePermissionService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Call the instance that was polled ready, leaving a fresh clone behind.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let permission = self.permission;
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let grants = match Grants::from_request_parts(&mut parts, &()).await {
+                Ok(grants) => grants,
+                Err(err) => return Ok(err.into_response()),
+            };
+
+            if !grants.allows(permission) {
+                tracing::warn!(
+                    "Denied {} {} without {}",
+                    parts.method,
+                    parts.uri.path(),
+                    permission
+                );
+                let err = Error::forbidden(format!("Requires the {} permission", permission));
+                return Ok(err.into_response());
+            }
+
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::server::errors::render_errors;
+    use axum::http::{header, StatusCode};
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app(grants: Grants) -> Router {
+        Router::new()
+            .route(
+                "/admin",
+                get(|| async { "secret" }).route_layer(RequirePermission("admin.read")),
+            )
+            // Stands in for a request whose grants were already resolved.
+            .layer(axum::Extension(grants))
+            .layer(axum::middleware::from_fn(render_errors))
+            .layer(axum::Extension(AppConfig::default()))
+    }
+
+    fn request(accept: &str) -> Request {
+        Request::builder()
+            .uri("/admin")
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn denies_without_the_permission() {
+        let response = app(Grants::default())
+            .oneshot(request("application/json"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+
+        let response = app(Grants::default())
+            .oneshot(request("text/html,application/xhtml+xml"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let page = String::from_utf8_lossy(&body);
+        assert!(page.contains("403 Error"));
+        assert!(page.contains("You need to log in"));
+    }
+
+    #[tokio::test]
+    async fn allows_with_the_permission() {
+        let grants = Grants {
+            user: None,
+            permissions: Arc::new(HashSet::from(["admin.read".to_string()])),
+        };
+        let response = app(grants).oneshot(request("*/*")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::auth::password;
+use crate::config::{AppConfig, ConfigSources};
+use crate::content::translations::{self, lint};
+use crate::error::{Error, ErrorKind};
+use crate::models::{roles, users};
+use crate::server::compression;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(name = "nosferatu", version, about)]
+pub struct Cli {
+    /// Path to a TOML configuration file.
+    #[arg(short, long, env = "NOSFERATU_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Path to a dotenv file, read as a configuration layer.
+    #[arg(long, value_name = "PATH")]
+    pub env_file: Option<PathBuf>,
+
+    /// Shorthand for `--set server.host=<HOST>`.
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Shorthand for `--set server.port=<PORT>`.
+    #[arg(long)]
+    pub port: Option<String>,
+
+    /// Override any configuration key, e.g. `--set database.max_connections=10`.
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+
+    /// Run a maintenance command instead of the server.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create a user account.
+    CreateUser {
+        email: String,
+
+        #[arg(long, env = "NOSFERATU_USER_PASSWORD", hide_env_values = true)]
+        password: String,
+    },
+    /// Give an existing user a role, e.g. `admin`.
+    GrantRole { email: String, role: String },
+    /// Set the copy for a translation key; running servers pick it up on
+    /// their next reload.
+    SetTranslation {
+        locale: String,
+        key: String,
+        value: String,
+    },
+    /// Check the translations against the templates: keys missing from a
+    /// locale, keys no template uses and placeholders that differ between
+    /// locales. Exits non-zero when there is any problem.
+    LintTranslations {
+        /// Directory of Askama templates to scan.
+        #[arg(long, default_value = "templates")]
+        templates: PathBuf,
+    },
+    /// Write `.br` and `.gz` copies of the text files under `assets.dir`, for
+    /// the public server to send instead; run after building the CSS. Needs no
+    /// database.
+    CompressAssets {
+        /// Skip files smaller than this many bytes; defaults to
+        /// `compression.min_size`.
+        #[arg(long)]
+        min_size: Option<u64>,
+    },
+}
+
+impl Cli {
+    pub fn sources(&self) -> ConfigSources {
+        let mut overrides = Vec::new();
+        if let Some(host) = &self.host {
+            overrides.push(("server.host".to_string(), host.clone()));
+        }
+        if let Some(port) = &self.port {
+            overrides.push(("server.port".to_string(), port.clone()));
+        }
+        overrides.extend(self.overrides.iter().cloned());
+
+        ConfigSources {
+            file: self.config.clone(),
+            dotenv: self.env_file.clone(),
+            overrides,
+        }
+    }
+}
+
+impl Command {
+    /// Whether `run` needs a connection to Postgres in its config.
+    pub fn needs_database(&self) -> bool {
+        !matches!(self, Command::CompressAssets { .. })
+    }
+
+    pub async fn run(self, config: &AppConfig) -> Result<(), Error> {
+        let pool = || {
+            config
+                .pg_pool
+                .as_ref()
+                .ok_or_else(|| Error::new("Postgres is not configured"))
+        };
+
+        match self {
+            Command::CreateUser { email, password } => {
+                let hash = password::hash_password(password).await?;
+                let user =
+                    users::create_user(pool()?, &email, &hash)
+                        .await
+                        .map_err(|err| match err.kind() {
+                            ErrorKind::Conflict => {
+                                Error::conflict(format!("{} is already registered", email))
+                            }
+                            _ => err,
+                        })?;
+                println!("Created user {} <{}>", user.user_id, user.email);
+            }
+            Command::GrantRole { email, role } => {
+                if !roles::grant_role(pool()?, &email, &role).await? {
+                    return Err(Error::new(format!("No user is registered as {}", email)));
+                }
+                println!("Granted {} to {}", role, email);
+            }
+            Command::SetTranslation { locale, key, value } => {
+                crate::models::translations::upsert(pool()?, &locale, &key, &value).await?;
+                println!("Set {} in {}", key, locale);
+            }
+            Command::LintTranslations { templates } => {
+                let bundle = translations::load(pool()?, &config.i18n).await?;
+                let mut used = lint::template_keys(&templates)?;
+                for (key, places) in lint::marked_used(&config.i18n.catalog_dir)? {
+                    used.entry(key).or_default().extend(places);
+                }
+                let problems = lint::check(&bundle, &config.i18n, &used);
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+
+                if !problems.is_empty() {
+                    return Err(Error::new(format!(
+                        "Found {} translation problems",
+                        problems.len()
+                    )));
+                }
+                println!("Checked {} keys used by templates", used.len());
+            }
+            Command::CompressAssets { min_size } => {
+                let min_size = min_size.unwrap_or(config.compression.min_size.into());
+                let compressed = compression::compress_dir(&config.assets.dir, min_size)?;
+                for path in &compressed {
+                    println!("Compressed {}", path.display());
+                }
+                println!(
+                    "{} assets compressed in {}",
+                    compressed.len(),
+                    config.assets.dir.display()
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {:?}", arg))
+}
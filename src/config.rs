@@ -1,63 +1,503 @@
+//! Typed application configuration.
+//!
+//! Values are resolved in layers, each overriding the one before it: built-in
+//! defaults, a TOML file, a `.env` file, environment variables and finally CLI
+//! flags. Every bad or missing key is reported in a single error.
+
+use crate::auth::AuthConfig;
+use crate::content::assets::AssetConfig;
+use crate::content::translations::I18nConfig;
 use crate::error::Error;
 use crate::models::postgres::config::PgConfig;
+use crate::observability::ObservabilityConfig;
+use crate::scheduler::SchedulerConfig;
+use crate::server::compression::CompressionConfig;
+use crate::server::cors::{CorsConfig, CorsProfile};
+use crate::server::rate_limit::RateLimitConfig;
+use crate::server::security::{CspNonce, SecurityConfig, SecurityHeaders};
+use crate::server::tls::TlsConfig;
+use axum::http::HeaderValue;
+use layers::{FromConfigValue, Key, Layers};
 use regex::Captures;
 use regex::Regex;
-use std::env;
+use std::path::PathBuf;
+
+pub mod layers;
+
+/// Loaded when no `--config` file is given, if present.
+pub const DEFAULT_CONFIG_FILE: &str = "nosferatu.toml";
+pub const DEFAULT_DOTENV_FILE: &str = ".env.development";
+
+/// Every key understood by the loader, with the environment variable it can
+/// also be read from.
+pub const KEYS: &[Key] = &[
+    ("server.host", "SERVER_BIND_HOST"),
+    ("server.port", "SERVER_BIND_PORT"),
+    ("server.public_host", "PUBLIC_BIND_HOST"),
+    ("server.public_port", "PUBLIC_BIND_PORT"),
+    ("server.cors_origins", "CORS_ORIGINS"),
+    ("server.body_limit", "SERVER_BODY_LIMIT"),
+    ("server.debug_errors", "SERVER_DEBUG_ERRORS"),
+    ("server.shutdown_grace", "SERVER_SHUTDOWN_GRACE"),
+    ("server.site_url", "SITE_URL"),
+    ("server.single_port", "SERVER_SINGLE_PORT"),
+    ("database.url", "DATABASE_URL"),
+    ("database.connect_timeout", "POSTGRES_CONNECT_TIMEOUT"),
+    ("database.idle_timeout", "POSTGRES_IDLE_TIMEOUT"),
+    ("database.max_lifetime", "POSTGRES_MAX_LIFETIME"),
+    ("database.min_connections", "POSTGRES_MIN_CONNECTIONS"),
+    ("database.max_connections", "POSTGRES_MAX_CONNECTIONS"),
+    ("jobs.backend", "JOBS_BACKEND"),
+    ("jobs.concurrency", "JOBS_CONCURRENCY"),
+    ("jobs.queue_capacity", "JOBS_QUEUE_CAPACITY"),
+    ("jobs.timeout", "JOBS_TIMEOUT"),
+    ("jobs.max_attempts", "JOBS_MAX_ATTEMPTS"),
+    ("jobs.backoff_min_ms", "JOBS_BACKOFF_MIN_MS"),
+    ("jobs.backoff_max_ms", "JOBS_BACKOFF_MAX_MS"),
+    ("jobs.poll_interval_ms", "JOBS_POLL_INTERVAL_MS"),
+    ("jobs.lock_timeout", "JOBS_LOCK_TIMEOUT"),
+    ("scheduler.enabled", "SCHEDULER_ENABLED"),
+    ("scheduler.timezone", "SCHEDULER_TIMEZONE"),
+    ("scheduler.tasks", "SCHEDULER_TASKS"),
+    ("auth.session_secret", "SESSION_SECRET"),
+    ("auth.session_ttl", "SESSION_TTL"),
+    ("auth.cookie_name", "SESSION_COOKIE_NAME"),
+    ("auth.cookie_secure", "SESSION_COOKIE_SECURE"),
+    ("i18n.default_locale", "I18N_DEFAULT_LOCALE"),
+    ("i18n.locales", "I18N_LOCALES"),
+    ("i18n.catalog_dir", "I18N_CATALOG_DIR"),
+    ("i18n.watch_interval", "I18N_WATCH_INTERVAL"),
+    ("cors.profile", "CORS_PROFILE"),
+    ("cors.profiles", "CORS_PROFILES"),
+    ("cors.routes", "CORS_ROUTES"),
+    ("security.enabled", "SECURITY_HEADERS_ENABLED"),
+    ("security.csp", "SECURITY_CSP"),
+    ("security.csp_report_only", "SECURITY_CSP_REPORT_ONLY"),
+    ("security.csp_report", "SECURITY_CSP_REPORT"),
+    (
+        "security.csp_report_retention_days",
+        "SECURITY_CSP_REPORT_RETENTION_DAYS",
+    ),
+    ("security.frame_options", "SECURITY_FRAME_OPTIONS"),
+    ("security.referrer_policy", "SECURITY_REFERRER_POLICY"),
+    ("security.permissions_policy", "SECURITY_PERMISSIONS_POLICY"),
+    ("security.cross_origin_opener_policy", "SECURITY_COOP"),
+    ("security.cross_origin_embedder_policy", "SECURITY_COEP"),
+    ("rate_limit.enabled", "RATE_LIMIT_ENABLED"),
+    ("rate_limit.store", "RATE_LIMIT_STORE"),
+    ("rate_limit.trusted_proxies", "RATE_LIMIT_TRUSTED_PROXIES"),
+    ("rate_limit.policies", "RATE_LIMIT_POLICIES"),
+    ("rate_limit.max_in_flight", "RATE_LIMIT_MAX_IN_FLIGHT"),
+    ("compression.enabled", "COMPRESSION_ENABLED"),
+    ("compression.min_size", "COMPRESSION_MIN_SIZE"),
+    ("assets.dir", "ASSETS_DIR"),
+    ("assets.base_url", "ASSETS_BASE_URL"),
+    ("tls.cert_path", "TLS_CERT_PATH"),
+    ("tls.key_path", "TLS_KEY_PATH"),
+    ("tls.redirect_port", "TLS_REDIRECT_PORT"),
+    ("tls.hsts_max_age", "TLS_HSTS_MAX_AGE"),
+    ("tls.hsts_include_subdomains", "TLS_HSTS_INCLUDE_SUBDOMAINS"),
+    ("tls.reload_interval", "TLS_RELOAD_INTERVAL"),
+    ("observability.log_format", "LOG_FORMAT"),
+    ("observability.log_filter", "RUST_LOG"),
+    ("observability.otlp_endpoint", "OTEL_EXPORTER_OTLP_ENDPOINT"),
+    ("observability.service_name", "OTEL_SERVICE_NAME"),
+    (
+        "observability.metrics_interval_ms",
+        "OTEL_METRIC_EXPORT_INTERVAL",
+    ),
+];
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Default)]
 pub struct AppConfig {
     // pub aws_creds: AwsCredentials,
     // pub aws_region: Region,
+    pub server: ServerConfig,
+    pub jobs: JobsConfig,
+    pub scheduler: SchedulerConfig,
+    pub auth: AuthConfig,
+    pub i18n: I18nConfig,
+    pub assets: AssetConfig,
+    pub cors: CorsConfig,
+    pub security: SecurityConfig,
+    pub rate_limit: RateLimitConfig,
+    pub compression: CompressionConfig,
+    pub tls: TlsConfig,
+    pub observability: ObservabilityConfig,
     pub pg_pool: Option<sqlx::PgPool>,
     pub pg_config: Option<PgConfig>,
 }
 
-pub async fn config() -> Result<AppConfig, Error> {
-    let pg_url = env::var("DATABASE_URL").expect("DATABASE_URL is missing!");
-    let pg_connect_timeout =
-        env::var("POSTGRES_CONNECT_TIMEOUT").expect("POSTGRES_CONNECT_TIMEOUT is missing!");
-    let pg_idle_timeout =
-        env::var("POSTGRES_IDLE_TIMEOUT").expect("POSTGRES_IDLE_TIMEOUT is missing!");
-    let pg_max_lifetime =
-        env::var("POSTGRES_MAX_LIFETIME").expect("POSTGRES_MAX_LIFETIME is missing!");
-    let pg_min_connections =
-        env::var("POSTGRES_MIN_CONNECTIONS").expect("POSTGRES_MIN_CONNECTIONS is missing!");
-    let pg_max_connections =
-        env::var("POSTGRES_MAX_CONNECTIONS").expect("POSTGRES_MAX_CONNECTIONS is missing!");
-
-    let pg_pool: sqlx::PgPool = match crate::models::postgres::config::pg_connection(
-        &pg_url,
-        &pg_connect_timeout,
-        &pg_idle_timeout,
-        &pg_max_lifetime,
-        &pg_min_connections,
-        &pg_max_connections,
-    )
-    .await?
-    {
-        Ok(value) => value,
-        Err(err) => {
-            return Err(anyhow::anyhow!(err)
-                .context(format!(
-                    "Unable to establish a connection to Postgres at URL: {}",
-                    &pg_url
-                ))
-                .into())
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Bind address of the barebones server exposing `./public`.
+    pub public_host: String,
+    pub public_port: u16,
+    /// Origins of the `default` CORS profile, when the config file defines
+    /// none.
+    pub cors_origins: Vec<HeaderValue>,
+    /// Maximum request body size, in bytes.
+    pub body_limit: usize,
+    /// Show internal error details and chains to clients; never enable in
+    /// production.
+    pub debug_errors: bool,
+    /// Seconds to let in-flight requests and jobs finish after a shutdown
+    /// signal.
+    pub shutdown_grace: u64,
+    /// Public URL of the site, e.g. `https://example.com`, used for canonical
+    /// and Open Graph URLs; they are left out when unset.
+    pub site_url: Option<String>,
+    /// Serve `/public` from the main app instead of a second listener on
+    /// `public_host:public_port`.
+    pub single_port: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 3000,
+            public_host: "0.0.0.0".to_string(),
+            public_port: 9002,
+            cors_origins: vec![HeaderValue::from_static("http://localhost:9001")],
+            body_limit: 20971520,
+            debug_errors: false,
+            shutdown_grace: 30,
+            site_url: None,
+            single_port: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Where templates link assets when `assets.base_url` is unset: `/public`
+    /// on this server in single-port mode, or else the public server.
+    pub fn default_asset_base_url(&self) -> String {
+        if self.single_port {
+            return "/public".to_string();
+        }
+
+        let host = match self.public_host.as_str() {
+            "0.0.0.0" | "::" | "" => "localhost",
+            host => host,
+        };
+        format!("http://{}:{}/public", host, self.public_port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobBackend {
+    /// The in-process channel; queued jobs are lost on restart.
+    Memory,
+    /// The Postgres `jobs` table.
+    Postgres,
+}
+
+impl FromConfigValue for JobBackend {
+    fn from_text(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "memory" => Ok(JobBackend::Memory),
+            "postgres" => Ok(JobBackend::Postgres),
+            other => Err(format!(
+                "expected \"memory\" or \"postgres\", got {:?}",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobsConfig {
+    pub backend: JobBackend,
+    /// Jobs run at the same time by the consumer.
+    pub concurrency: usize,
+    /// Messages buffered by the job channel before senders wait.
+    pub queue_capacity: usize,
+    /// Seconds before a single attempt is abandoned.
+    pub timeout: u64,
+    /// Attempts per job, including the first.
+    pub max_attempts: u32,
+    pub backoff_min_ms: u64,
+    pub backoff_max_ms: u64,
+    /// How often idle Postgres workers look for due jobs.
+    pub poll_interval_ms: u64,
+    /// Seconds after which a running Postgres job is assumed abandoned.
+    pub lock_timeout: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            backend: JobBackend::Memory,
+            concurrency: 4,
+            queue_capacity: 32,
+            timeout: 60,
+            max_attempts: 3,
+            backoff_min_ms: 500,
+            backoff_max_ms: 30_000,
+            poll_interval_ms: 1_000,
+            lock_timeout: 600,
+        }
+    }
+}
+
+/// Where configuration is read from, besides the built-in defaults and the
+/// process environment.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources {
+    /// A TOML file; when `None`, [`DEFAULT_CONFIG_FILE`] is used if present.
+    pub file: Option<PathBuf>,
+    pub dotenv: Option<PathBuf>,
+    /// `key=value` overrides given on the command line.
+    pub overrides: Vec<(String, String)>,
+}
+
+impl ConfigSources {
+    pub fn layers(&self) -> Layers {
+        let layers = match &self.file {
+            Some(file) => Layers::new(KEYS).with_file(file, true),
+            None => Layers::new(KEYS).with_file(DEFAULT_CONFIG_FILE.as_ref(), false),
+        };
+        let dotenv = self
+            .dotenv
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DOTENV_FILE));
+
+        layers
+            .with_dotenv(&dotenv)
+            .with_env(std::env::vars())
+            .with_cli(&self.overrides)
+    }
+}
+
+impl AppConfig {
+    /// Resolve a config from `layers`, without connecting to Postgres.
+    pub fn from_layers(mut layers: Layers) -> Result<Self, Error> {
+        let defaults = ServerConfig::default();
+        let server = ServerConfig {
+            host: layers.get("server.host", defaults.host),
+            port: layers.get("server.port", defaults.port),
+            public_host: layers.get("server.public_host", defaults.public_host),
+            public_port: layers.get("server.public_port", defaults.public_port),
+            cors_origins: layers.get("server.cors_origins", defaults.cors_origins),
+            body_limit: layers.get("server.body_limit", defaults.body_limit),
+            debug_errors: layers.get("server.debug_errors", defaults.debug_errors),
+            shutdown_grace: layers.get("server.shutdown_grace", defaults.shutdown_grace),
+            site_url: layers.get_opt("server.site_url"),
+            single_port: layers.get("server.single_port", defaults.single_port),
+        };
+
+        let defaults = JobsConfig::default();
+        let jobs = JobsConfig {
+            backend: layers.get("jobs.backend", defaults.backend),
+            concurrency: layers.get("jobs.concurrency", defaults.concurrency),
+            queue_capacity: layers.get("jobs.queue_capacity", defaults.queue_capacity),
+            timeout: layers.get("jobs.timeout", defaults.timeout),
+            max_attempts: layers.get("jobs.max_attempts", defaults.max_attempts),
+            backoff_min_ms: layers.get("jobs.backoff_min_ms", defaults.backoff_min_ms),
+            backoff_max_ms: layers.get("jobs.backoff_max_ms", defaults.backoff_max_ms),
+            poll_interval_ms: layers.get("jobs.poll_interval_ms", defaults.poll_interval_ms),
+            lock_timeout: layers.get("jobs.lock_timeout", defaults.lock_timeout),
+        };
+
+        let defaults = SchedulerConfig::default();
+        let scheduler = SchedulerConfig {
+            enabled: layers.get("scheduler.enabled", defaults.enabled),
+            timezone: layers.get("scheduler.timezone", defaults.timezone),
+            tasks: layers.get("scheduler.tasks", defaults.tasks),
+        };
+
+        let defaults = AuthConfig::default();
+        let auth = AuthConfig {
+            session_secret: layers.get_opt("auth.session_secret"),
+            session_ttl: layers.get("auth.session_ttl", defaults.session_ttl),
+            cookie_name: layers.get("auth.cookie_name", defaults.cookie_name),
+            cookie_secure: layers.get("auth.cookie_secure", defaults.cookie_secure),
+        };
+
+        let defaults = I18nConfig::default();
+        let mut i18n = I18nConfig {
+            default_locale: layers.get("i18n.default_locale", defaults.default_locale),
+            locales: layers.get("i18n.locales", defaults.locales),
+            catalog_dir: layers.get("i18n.catalog_dir", defaults.catalog_dir),
+            watch_interval: layers.get("i18n.watch_interval", defaults.watch_interval),
+        };
+        if !i18n.locales.contains(&i18n.default_locale) {
+            i18n.locales.insert(0, i18n.default_locale.clone());
+        }
+
+        let defaults = AssetConfig::default();
+        let assets = AssetConfig {
+            dir: layers.get("assets.dir", defaults.dir),
+            base_url: layers
+                .get_opt("assets.base_url")
+                .unwrap_or_else(|| server.default_asset_base_url()),
+        };
+
+        let defaults = CorsConfig::default();
+        let mut cors = CorsConfig {
+            profile: layers.get("cors.profile", defaults.profile),
+            profiles: layers.get("cors.profiles", defaults.profiles),
+            routes: layers.get("cors.routes", defaults.routes),
+        };
+        cors.profiles
+            .0
+            .entry("default".to_string())
+            .or_insert_with(|| CorsProfile::with_origins(server.cors_origins.clone()));
+        if !cors.profiles.0.contains_key(&cors.profile) {
+            let message = format!("no profile named {:?} in cors.profiles", cors.profile);
+            layers.reject("cors.profile", message);
+        }
+        for route in &cors.routes.0 {
+            if !cors.profiles.0.contains_key(&route.profile) {
+                let message = format!(
+                    "{} uses profile {:?}, which is not in cors.profiles",
+                    route.path, route.profile
+                );
+                layers.reject("cors.routes", message);
+            }
+        }
+
+        let defaults = SecurityConfig::default();
+        let security = SecurityConfig {
+            enabled: layers.get("security.enabled", defaults.enabled),
+            csp: layers.get("security.csp", defaults.csp),
+            csp_report_only: layers.get("security.csp_report_only", defaults.csp_report_only),
+            csp_report: layers.get("security.csp_report", defaults.csp_report),
+            csp_report_retention_days: layers.get(
+                "security.csp_report_retention_days",
+                defaults.csp_report_retention_days,
+            ),
+            frame_options: layers.get("security.frame_options", defaults.frame_options),
+            referrer_policy: layers.get("security.referrer_policy", defaults.referrer_policy),
+            permissions_policy: layers
+                .get("security.permissions_policy", defaults.permissions_policy),
+            cross_origin_opener_policy: layers.get(
+                "security.cross_origin_opener_policy",
+                defaults.cross_origin_opener_policy,
+            ),
+            cross_origin_embedder_policy: layers.get(
+                "security.cross_origin_embedder_policy",
+                defaults.cross_origin_embedder_policy,
+            ),
+        };
+        if let Err(message) =
+            SecurityHeaders::new(&security, &assets.base_url).csp(&CspNonce(String::new()))
+        {
+            layers.reject("security.csp", message);
+        }
+
+        let defaults = RateLimitConfig::default();
+        let rate_limit = RateLimitConfig {
+            enabled: layers.get("rate_limit.enabled", defaults.enabled),
+            store: layers.get("rate_limit.store", defaults.store),
+            trusted_proxies: layers.get("rate_limit.trusted_proxies", defaults.trusted_proxies),
+            policies: layers.get("rate_limit.policies", defaults.policies),
+            max_in_flight: layers.get("rate_limit.max_in_flight", defaults.max_in_flight),
+        };
+
+        let defaults = CompressionConfig::default();
+        let compression = CompressionConfig {
+            enabled: layers.get("compression.enabled", defaults.enabled),
+            min_size: layers.get("compression.min_size", defaults.min_size),
+        };
+
+        let defaults = TlsConfig::default();
+        let tls = TlsConfig {
+            cert_path: layers.get_opt("tls.cert_path"),
+            key_path: layers.get_opt("tls.key_path"),
+            redirect_port: layers.get_opt("tls.redirect_port"),
+            hsts_max_age: layers.get("tls.hsts_max_age", defaults.hsts_max_age),
+            hsts_include_subdomains: layers.get(
+                "tls.hsts_include_subdomains",
+                defaults.hsts_include_subdomains,
+            ),
+            reload_interval: layers.get("tls.reload_interval", defaults.reload_interval),
+        };
+        match (&tls.cert_path, &tls.key_path) {
+            (Some(_), None) => layers.reject("tls.key_path", "missing; tls.cert_path is set"),
+            (None, Some(_)) => layers.reject("tls.cert_path", "missing; tls.key_path is set"),
+            _ => {}
+        }
+        if tls.redirect_port.is_some() && !tls.enabled() {
+            layers.reject("tls.redirect_port", "needs tls.cert_path and tls.key_path");
+        }
+
+        let defaults = ObservabilityConfig::default();
+        let observability = ObservabilityConfig {
+            log_format: layers.get("observability.log_format", defaults.log_format),
+            log_filter: layers.get("observability.log_filter", defaults.log_filter),
+            otlp_endpoint: layers.get_opt("observability.otlp_endpoint"),
+            service_name: layers.get("observability.service_name", defaults.service_name),
+            metrics_interval_ms: layers.get(
+                "observability.metrics_interval_ms",
+                defaults.metrics_interval_ms,
+            ),
+        };
+        if let Err(err) = tracing_subscriber::EnvFilter::try_new(&observability.log_filter) {
+            layers.reject("observability.log_filter", err.to_string());
         }
-    };
-
-    Ok(AppConfig {
-        pg_config: Some(PgConfig {
-            url: pg_url,
-            connect_timeout: pg_connect_timeout,
-            idle_timeout: pg_idle_timeout,
-            max_lifetime: pg_max_lifetime,
-            min_connections: pg_min_connections,
-            max_connections: pg_max_connections,
-        }),
-        pg_pool: Some(pg_pool),
-    })
+
+        let defaults = PgConfig::default();
+        let pg_config = PgConfig {
+            url: layers.require("database.url"),
+            connect_timeout: layers.get("database.connect_timeout", defaults.connect_timeout),
+            idle_timeout: layers.get("database.idle_timeout", defaults.idle_timeout),
+            max_lifetime: layers.get("database.max_lifetime", defaults.max_lifetime),
+            min_connections: layers.get("database.min_connections", defaults.min_connections),
+            max_connections: layers.get("database.max_connections", defaults.max_connections),
+        };
+
+        layers.finish().map_err(Error::new)?;
+
+        Ok(AppConfig {
+            server,
+            jobs,
+            scheduler,
+            auth,
+            i18n,
+            assets,
+            cors,
+            security,
+            rate_limit,
+            compression,
+            tls,
+            observability,
+            pg_pool: None,
+            pg_config: Some(pg_config),
+        })
+    }
+}
+
+pub async fn config(sources: &ConfigSources) -> Result<AppConfig, Error> {
+    connect(AppConfig::from_layers(sources.layers())?).await
+}
+
+/// Open the Postgres pool `config` describes.
+pub async fn connect(mut config: AppConfig) -> Result<AppConfig, Error> {
+    let pg_config = config
+        .pg_config
+        .as_ref()
+        .ok_or_else(|| Error::new("Postgres configuration is missing"))?;
+
+    let pg_pool: sqlx::PgPool =
+        match crate::models::postgres::config::pg_connection(pg_config).await? {
+            Ok(value) => value,
+            Err(err) => {
+                return Err(anyhow::anyhow!(err)
+                    .context(format!(
+                        "Unable to establish a connection to Postgres at URL: {}",
+                        sanitize_db_url(&pg_config.url)?
+                    ))
+                    .into())
+            }
+        };
+
+    config.pg_pool = Some(pg_pool);
+
+    Ok(config)
 }
 
 pub fn sanitize_db_url(url: &str) -> Result<String, Error> {
@@ -70,3 +510,179 @@ pub fn sanitize_db_url(url: &str) -> Result<String, Error> {
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::observability::LogFormat;
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let layers = Layers::new(KEYS)
+            .with_toml_str(
+                r#"
+                [server]
+                port = 8000
+                host = "127.0.0.1"
+
+                [database]
+                url = "postgres://file"
+                "#,
+            )
+            .with_env(env(&[
+                ("SERVER_BIND_PORT", "8001"),
+                ("DATABASE_URL", "postgres://env"),
+            ]))
+            .with_cli(&env(&[("server.port", "8002")]));
+
+        let config = AppConfig::from_layers(layers).unwrap();
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert_eq!(config.server.port, 8002);
+        assert_eq!(config.server.public_port, 9002);
+        assert_eq!(config.pg_config.unwrap().url, "postgres://env");
+    }
+
+    #[test]
+    fn lists_from_toml_and_env() {
+        let layers = Layers::new(KEYS)
+            .with_toml_str(
+                r#"
+                database.url = "postgres://file"
+                server.cors_origins = ["http://a.test", "http://b.test"]
+                "#,
+            )
+            .with_env(env(&[("POSTGRES_MAX_CONNECTIONS", "12")]));
+
+        let config = AppConfig::from_layers(layers).unwrap();
+        assert_eq!(config.server.cors_origins.len(), 2);
+        assert_eq!(config.pg_config.unwrap().max_connections, 12);
+
+        let layers = Layers::new(KEYS).with_env(env(&[
+            ("DATABASE_URL", "postgres://env"),
+            ("CORS_ORIGINS", "http://a.test, http://b.test,http://c.test"),
+        ]));
+        let config = AppConfig::from_layers(layers).unwrap();
+        assert_eq!(config.server.cors_origins.len(), 3);
+    }
+
+    #[test]
+    fn log_settings_come_from_the_usual_variables() {
+        let layers = Layers::new(KEYS).with_env(env(&[
+            ("DATABASE_URL", "postgres://env"),
+            ("LOG_FORMAT", "json"),
+            ("RUST_LOG", "info,sqlx=warn"),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4318"),
+        ]));
+        let config = AppConfig::from_layers(layers).unwrap().observability;
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.log_filter, "info,sqlx=warn");
+        assert_eq!(
+            config.otlp_endpoint.as_deref(),
+            Some("http://collector:4318")
+        );
+
+        let layers = Layers::new(KEYS).with_env(env(&[
+            ("DATABASE_URL", "postgres://env"),
+            ("RUST_LOG", "sqlx=loud"),
+        ]));
+        let err = AppConfig::from_layers(layers).unwrap_err().to_string();
+        assert!(err.contains("observability.log_filter"), "{}", err);
+    }
+
+    #[test]
+    fn cors_profiles_must_exist() {
+        let toml = r#"
+            database.url = "postgres://file"
+            server.cors_origins = ["http://a.test"]
+
+            [cors.profiles.production]
+            origins = ["https://*.example.com"]
+
+            [[cors.routes]]
+            path = "/api/public"
+            profile = "public"
+            "#;
+
+        let layers = Layers::new(KEYS)
+            .with_toml_str(toml)
+            .with_env(env(&[("CORS_PROFILE", "production")]));
+        let err = AppConfig::from_layers(layers).unwrap_err().to_string();
+        assert!(err.contains("cors.routes"), "{}", err);
+        assert!(!err.contains("cors.profile:"), "{}", err);
+
+        let layers = Layers::new(KEYS).with_toml_str(toml).with_env(env(&[
+            ("CORS_PROFILES", r#"public = { origins = ["*"] }"#),
+            ("CORS_PROFILE", "staging"),
+        ]));
+        let err = AppConfig::from_layers(layers).unwrap_err().to_string();
+        assert!(err.contains("no profile named \"staging\""), "{}", err);
+
+        let layers = Layers::new(KEYS)
+            .with_toml_str(toml)
+            .with_env(env(&[("CORS_ROUTES", "/health=default")]));
+        let config = AppConfig::from_layers(layers).unwrap();
+        let default = &config.cors.profiles.0["default"];
+        assert!(default.allows(&HeaderValue::from_static("http://a.test")));
+        assert_eq!(config.cors.routes.0[0].path, "/health");
+    }
+
+    #[test]
+    fn asset_urls_follow_the_port_mode() {
+        let config = |pairs: &[(&str, &str)]| {
+            let mut pairs = pairs.to_vec();
+            pairs.push(("DATABASE_URL", "postgres://env"));
+            AppConfig::from_layers(Layers::new(KEYS).with_env(env(&pairs))).unwrap()
+        };
+
+        assert_eq!(
+            config(&[("PUBLIC_BIND_PORT", "9102")]).assets.base_url,
+            "http://localhost:9102/public"
+        );
+        assert_eq!(
+            config(&[("SERVER_SINGLE_PORT", "true")]).assets.base_url,
+            "/public"
+        );
+        assert_eq!(
+            config(&[
+                ("SERVER_SINGLE_PORT", "true"),
+                ("ASSETS_BASE_URL", "https://cdn.test/public")
+            ])
+            .assets
+            .base_url,
+            "https://cdn.test/public"
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_key() {
+        let layers = Layers::new(KEYS)
+            .with_toml_str("server.colour = \"red\"")
+            .with_env(env(&[
+                ("SERVER_BIND_PORT", "eighty"),
+                ("POSTGRES_IDLE_TIMEOUT", "-1"),
+            ]))
+            .with_cli(&env(&[("server.nope", "1")]));
+
+        let err = AppConfig::from_layers(layers).unwrap_err();
+        let err = err.into_inner().downcast::<layers::ConfigError>().unwrap();
+        let keys: Vec<&str> = err.problems.iter().map(|p| p.key.as_str()).collect();
+
+        assert_eq!(err.problems.len(), 5, "{}", err);
+        for key in [
+            "server.colour",
+            "server.nope",
+            "server.port",
+            "database.url",
+            "database.idle_timeout",
+        ] {
+            assert!(keys.contains(&key), "{} not reported in {}", key, err);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Layered resolution of raw configuration values.
+//!
+//! Each layer maps a dotted key (e.g. `server.port`) to a raw value and records
+//! where it came from. Later layers override earlier ones. Typed values are
+//! pulled out with [`Layers::get`] and [`Layers::require`], which record
+//! problems instead of failing, so that [`Layers::finish`] can report every bad
+//! key at once.
+
+use axum::http::HeaderValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A known configuration key and the environment variable it can be read from.
+pub type Key = (&'static str, &'static str);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    File,
+    DotEnv,
+    Env,
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Source::File => "config file",
+            Source::DotEnv => ".env file",
+            Source::Env => "environment",
+            Source::Cli => "command line",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum RawValue {
+    Toml(toml::Value),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub key: String,
+    pub source: Option<Source>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source {
+            Some(source) => write!(f, "{} (from {}): {}", self.key, source, self.message),
+            None => write!(f, "{}: {}", self.key, self.message),
+        }
+    }
+}
+
+/// Every problem found while resolving configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid configuration ({} problem{}):",
+            self.problems.len(),
+            if self.problems.len() == 1 { "" } else { "s" }
+        )?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub struct Layers {
+    keys: &'static [Key],
+    values: HashMap<String, (RawValue, Source)>,
+    problems: Vec<Problem>,
+}
+
+impl Layers {
+    pub fn new(keys: &'static [Key]) -> Self {
+        Self {
+            keys,
+            values: HashMap::new(),
+            problems: Vec::new(),
+        }
+    }
+
+    /// Apply a TOML file. A missing file is only a problem when `required`.
+    pub fn with_file(self, path: &Path, required: bool) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(text) => self.with_toml_str(&text),
+            Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => self,
+            Err(err) => self.problem(&path.display().to_string(), Some(Source::File), err),
+        }
+    }
+
+    pub fn with_toml_str(mut self, text: &str) -> Self {
+        match text.parse::<toml::Table>() {
+            Ok(table) => {
+                self.flatten("", table);
+                self
+            }
+            Err(err) => self.problem("<config file>", Some(Source::File), err.message()),
+        }
+    }
+
+    /// Apply a dotenv file, without exporting its values to the process.
+    pub fn with_dotenv(self, path: &Path) -> Self {
+        // `dotenv` has no other way to read a file without exporting it.
+        #[allow(deprecated)]
+        let iter = match dotenv::from_filename_iter(path) {
+            Ok(iter) => iter,
+            Err(_) if !path.exists() => return self,
+            Err(err) => {
+                return self.problem(&path.display().to_string(), Some(Source::DotEnv), err)
+            }
+        };
+
+        let mut vars = Vec::new();
+        let mut layers = self;
+        for item in iter {
+            match item {
+                Ok(pair) => vars.push(pair),
+                Err(err) => {
+                    layers = layers.problem(&path.display().to_string(), Some(Source::DotEnv), err)
+                }
+            }
+        }
+
+        layers.apply_env(vars, Source::DotEnv)
+    }
+
+    pub fn with_env(self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.apply_env(vars, Source::Env)
+    }
+
+    pub fn with_cli(mut self, overrides: &[(String, String)]) -> Self {
+        for (key, value) in overrides {
+            if self.is_known(key) {
+                self.values.insert(
+                    key.to_string(),
+                    (RawValue::Text(value.to_string()), Source::Cli),
+                );
+            } else {
+                self = self.problem(key, Some(Source::Cli), "unknown configuration key");
+            }
+        }
+
+        self
+    }
+
+    /// Resolve `key`, falling back to `default` when no layer sets it.
+    pub fn get<T: FromConfigValue>(&mut self, key: &str, default: T) -> T {
+        self.lookup(key).unwrap_or(default)
+    }
+
+    /// Like [`Layers::get`], but `None` when no layer sets `key`.
+    pub fn get_opt<T: FromConfigValue>(&mut self, key: &str) -> Option<T> {
+        self.lookup(key)
+    }
+
+    /// Resolve a key that has no built-in default.
+    pub fn require<T: FromConfigValue + Default>(&mut self, key: &str) -> T {
+        if !self.values.contains_key(key) {
+            let hint = match self.env_name(key) {
+                Some(env) => format!("missing; set it in the config file or via {}", env),
+                None => "missing; set it in the config file".to_string(),
+            };
+            self.problems.push(Problem {
+                key: key.to_string(),
+                source: None,
+                message: hint,
+            });
+
+            return T::default();
+        }
+
+        self.lookup(key).unwrap_or_default()
+    }
+
+    /// Report a problem found while checking resolved values together, such as
+    /// a key that only makes sense alongside another.
+    pub fn reject(&mut self, key: &str, message: impl fmt::Display) {
+        let source = self.values.get(key).map(|(_, source)| *source);
+        self.problems.push(Problem {
+            key: key.to_string(),
+            source,
+            message: message.to_string(),
+        });
+    }
+
+    pub fn finish(self) -> Result<(), ConfigError> {
+        if self.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError {
+                problems: self.problems,
+            })
+        }
+    }
+
+    fn lookup<T: FromConfigValue>(&mut self, key: &str) -> Option<T> {
+        let (raw, source) = self.values.get(key)?;
+        let parsed = match raw {
+            RawValue::Toml(value) => T::from_toml(value),
+            RawValue::Text(text) => T::from_text(text),
+        };
+
+        match parsed {
+            Ok(value) => Some(value),
+            Err(message) => {
+                let source = *source;
+                self.problems.push(Problem {
+                    key: key.to_string(),
+                    source: Some(source),
+                    message,
+                });
+
+                None
+            }
+        }
+    }
+
+    fn apply_env(
+        mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+        source: Source,
+    ) -> Self {
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        for (key, env) in self.keys {
+            if let Some(value) = vars.get(*env) {
+                self.values
+                    .insert(key.to_string(), (RawValue::Text(value.clone()), source));
+            }
+        }
+
+        self
+    }
+
+    fn flatten(&mut self, prefix: &str, table: toml::Table) {
+        for (name, value) in table {
+            let key = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}.{}", prefix, name)
+            };
+
+            if self.is_known(&key) {
+                self.values
+                    .insert(key, (RawValue::Toml(value), Source::File));
+            } else if let toml::Value::Table(table) = value {
+                self.flatten(&key, table);
+            } else {
+                self.problems.push(Problem {
+                    key,
+                    source: Some(Source::File),
+                    message: "unknown configuration key".to_string(),
+                });
+            }
+        }
+    }
+
+    fn is_known(&self, key: &str) -> bool {
+        self.keys.iter().any(|(known, _)| *known == key)
+    }
+
+    fn env_name(&self, key: &str) -> Option<&'static str> {
+        self.keys
+            .iter()
+            .find(|(known, _)| *known == key)
+            .map(|(_, env)| *env)
+    }
+
+    fn problem(mut self, key: &str, source: Option<Source>, message: impl fmt::Display) -> Self {
+        self.problems.push(Problem {
+            key: key.to_string(),
+            source,
+            message: message.to_string(),
+        });
+
+        self
+    }
+}
+
+/// Parse a typed value out of either a TOML value or a plain string (env, CLI).
+pub trait FromConfigValue: Sized {
+    fn from_text(text: &str) -> Result<Self, String>;
+
+    fn from_toml(value: &toml::Value) -> Result<Self, String> {
+        match value {
+            toml::Value::String(text) => Self::from_text(text),
+            other => Self::from_text(&other.to_string()),
+        }
+    }
+}
+
+impl FromConfigValue for String {
+    fn from_text(text: &str) -> Result<Self, String> {
+        Ok(text.to_string())
+    }
+}
+
+impl FromConfigValue for PathBuf {
+    fn from_text(text: &str) -> Result<Self, String> {
+        match text.trim() {
+            "" => Err("expected a path".to_string()),
+            path => Ok(PathBuf::from(path)),
+        }
+    }
+}
+
+impl FromConfigValue for bool {
+    fn from_text(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Ok(true),
+            "false" | "0" | "no" | "off" => Ok(false),
+            other => Err(format!("expected a boolean, got {:?}", other)),
+        }
+    }
+}
+
+macro_rules! from_config_number {
+    ($($typ:ty),*) => {
+        $(
+            impl FromConfigValue for $typ {
+                fn from_text(text: &str) -> Result<Self, String> {
+                    text.trim()
+                        .parse::<$typ>()
+                        .map_err(|err| format!("expected {}, got {:?}: {}", stringify!($typ), text, err))
+                }
+            }
+        )*
+    };
+}
+
+from_config_number!(u16, u32, u64, usize, f64);
+
+impl FromConfigValue for HeaderValue {
+    fn from_text(text: &str) -> Result<Self, String> {
+        text.trim()
+            .parse::<HeaderValue>()
+            .map_err(|err| format!("invalid header value {:?}: {}", text, err))
+    }
+}
+
+/// Lists are TOML arrays in files and comma separated strings elsewhere.
+impl<T: FromConfigValue> FromConfigValue for Vec<T> {
+    fn from_text(text: &str) -> Result<Self, String> {
+        text.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(T::from_text)
+            .collect()
+    }
+
+    fn from_toml(value: &toml::Value) -> Result<Self, String> {
+        match value {
+            toml::Value::Array(items) => items.iter().map(T::from_toml).collect(),
+            toml::Value::String(text) => Self::from_text(text),
+            other => Err(format!("expected a list, got {}", other.type_str())),
+        }
+    }
+}
@@ -1 +1,34 @@
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+
+pub mod assets;
 pub mod templates;
+pub mod translations;
+
+/// Every file under `dir`, recursively and in order, leaving out the `.br` and
+/// `.gz` copies `compress-assets` writes next to a file.
+pub fn files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for path in sorted_entries(dir)? {
+        if path.is_dir() {
+            files.extend(self::files(&path)?);
+        } else if !path
+            .extension()
+            .is_some_and(|extension| extension == "br" || extension == "gz")
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// The entries of `dir`, sorted by path.
+pub(crate) fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+
+    Ok(paths)
+}
@@ -0,0 +1,201 @@
+//! Fingerprinted URLs for the files under `public/`.
+//!
+//! At startup every file is hashed into an [`AssetManifest`]. Templates link
+//! to `css/output.css` as `{{ self::asset("css/output.css") }}`, which gives
+//! `<assets.base_url>/css/output.<hash>.css`; a new build changes the hash, so
+//! browsers can cache each URL forever. The public server maps fingerprinted
+//! paths back to the file, see [`crate::server::public`].
+
+use super::files;
+use crate::error::Error;
+use arc_swap::ArcSwap;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::SystemTime;
+
+/// The manifest templates and the public server use; replaced with
+/// [`install`].
+pub static ASSETS: LazyLock<ArcSwap<AssetManifest>> =
+    LazyLock::new(|| ArcSwap::from_pointee(AssetManifest::default()));
+
+#[derive(Debug, Clone)]
+pub struct AssetConfig {
+    /// Directory served by the public server.
+    pub dir: PathBuf,
+    /// Prefix of every asset URL, e.g. `https://cdn.example.com/public`.
+    pub base_url: String,
+}
+
+impl Default for AssetConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("public"),
+            base_url: "http://localhost:9002/public".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+    /// Relative to the asset directory, with `/` separators.
+    pub path: String,
+    /// `path` with the hash before its extension.
+    pub fingerprinted: String,
+    /// A quoted strong `ETag` of the contents.
+    pub etag: String,
+    /// Size and modification time when hashed, to notice files changed since.
+    pub stamp: Option<(u64, SystemTime)>,
+}
+
+#[derive(Debug, Default)]
+pub struct AssetManifest {
+    base_url: String,
+    assets: HashMap<String, Asset>,
+    fingerprinted: HashMap<String, String>,
+}
+
+impl AssetManifest {
+    /// Hash every file under `config.dir`. A missing directory gives an empty
+    /// manifest.
+    pub fn build(config: &AssetConfig) -> Result<Self, Error> {
+        let mut manifest = AssetManifest {
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            ..AssetManifest::default()
+        };
+        if !config.dir.is_dir() {
+            return Ok(manifest);
+        }
+
+        for file in files(&config.dir)? {
+            let contents = std::fs::read(&file)?;
+            let path = file
+                .strip_prefix(&config.dir)
+                .map_err(|err| Error::new(err.to_string()))?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let digest = Sha256::digest(&contents);
+            let hash: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+            let asset = Asset {
+                fingerprinted: fingerprint(&path, &hash),
+                etag: format!("\"{}\"", hash),
+                stamp: stamp(&file),
+                path: path.clone(),
+            };
+
+            manifest
+                .fingerprinted
+                .insert(asset.fingerprinted.clone(), path.clone());
+            manifest.assets.insert(path, asset);
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assets.is_empty()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Asset> {
+        self.assets.get(path)
+    }
+
+    /// The asset a fingerprinted path such as `css/output.1a2b3c4d.css` names.
+    pub fn by_fingerprint(&self, path: &str) -> Option<&Asset> {
+        self.assets.get(self.fingerprinted.get(path)?)
+    }
+
+    /// The URL to link `path` with; unknown files are linked unversioned.
+    pub fn url(&self, path: &str) -> String {
+        let path = path.trim_start_matches('/');
+        match self.get(path) {
+            Some(asset) => format!("{}/{}", self.base_url, asset.fingerprinted),
+            None => format!("{}/{}", self.base_url, path),
+        }
+    }
+}
+
+impl Asset {
+    /// Whether the file on disk under `dir` is still the one that was hashed.
+    pub fn is_current(&self, dir: &Path) -> bool {
+        self.stamp.is_some() && stamp(&dir.join(&self.path)) == self.stamp
+    }
+}
+
+/// `css/output.css` becomes `css/output.<hash>.css`.
+fn fingerprint(path: &str, hash: &str) -> String {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), path),
+    };
+
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}{}.{}.{}", dir, stem, hash, extension)
+        }
+        _ => format!("{}{}.{}", dir, name, hash),
+    }
+}
+
+fn stamp(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Make `manifest` the one templates and the public server use.
+pub fn install(manifest: AssetManifest) {
+    tracing::info!("Fingerprinted {} public assets", manifest.len());
+    ASSETS.store(Arc::new(manifest));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints_every_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("css")).unwrap();
+        std::fs::write(dir.path().join("css/output.css"), "body {}").unwrap();
+        std::fs::write(dir.path().join("robots"), "").unwrap();
+
+        let config = AssetConfig {
+            dir: dir.path().to_path_buf(),
+            base_url: "https://cdn.example.com/public/".to_string(),
+        };
+        let manifest = AssetManifest::build(&config).unwrap();
+        let css = manifest.get("css/output.css").unwrap();
+
+        assert_eq!(css.fingerprinted, "css/output.62368a1a29259b30.css");
+        assert_eq!(css.etag, "\"62368a1a29259b30\"");
+        assert_eq!(
+            manifest.url("/css/output.css"),
+            "https://cdn.example.com/public/css/output.62368a1a29259b30.css"
+        );
+        assert_eq!(
+            manifest.url("missing.js"),
+            "https://cdn.example.com/public/missing.js"
+        );
+        assert!(manifest
+            .get("robots")
+            .unwrap()
+            .fingerprinted
+            .starts_with("robots."));
+        assert_eq!(
+            manifest.by_fingerprint(&css.fingerprinted).unwrap().path,
+            "css/output.css"
+        );
+        assert!(css.is_current(dir.path()));
+
+        std::fs::write(dir.path().join("css/output.css"), "body { margin: 0 }").unwrap();
+        assert!(!css.is_current(dir.path()));
+    }
+}
@@ -1,17 +1,24 @@
-use crate::utils;
-use crate::utils::logger;
+use crate::auth::csrf::{CsrfToken, CSRF_FIELD};
+use crate::content::assets::ASSETS;
+use crate::error::FieldError;
+use crate::models::users::User;
+use crate::server::locale::Locale;
+use crate::server::security::CspNonce;
+use arc_swap::ArcSwap;
 use askama::Template;
 use axum::response::{IntoResponse, Response};
 use hyper::StatusCode;
-use i18n::I18nBundle;
+use i18n::{I18nBundle, MessageArgs};
+use page::PageContext;
 use std::sync::LazyLock;
-use std::sync::Mutex;
 
 pub mod i18n;
+pub mod page;
 
-pub static I18N_STATIC_CONTENT: LazyLock<Mutex<Option<I18nBundle>>> =
-    LazyLock::new(|| Mutex::new(Some(I18nBundle::new())));
-pub static I18N_LANGUAGE: LazyLock<Mutex<Option<&str>>> = LazyLock::new(|| Mutex::new(Some("en")));
+/// The translations templates render with. Reads never block: a reload swaps
+/// in a whole new bundle.
+pub static I18N_STATIC_CONTENT: LazyLock<ArcSwap<I18nBundle>> =
+    LazyLock::new(|| ArcSwap::from_pointee(I18nBundle::new()));
 
 pub(crate) struct HtmlTemplate<T>(pub T);
 
@@ -35,40 +42,109 @@ where
     }
 }
 
-pub fn translate(key: &str) -> String {
-    let i18n_content = &mut *I18N_STATIC_CONTENT.lock().unwrap();
+/// The copy for `key` in `locale`, falling back to the default locale and then
+/// to `key` itself.
+pub fn translate(locale: &str, key: &str) -> String {
+    format(locale, key, None)
+}
+
+/// Like [`translate`], formatting Fluent messages with `args`. Formatting
+/// problems, such as a missing argument, are logged and the rest of the
+/// message is still shown.
+pub fn format(locale: &str, key: &str, args: Option<&MessageArgs>) -> String {
+    match I18N_STATIC_CONTENT.load().format(locale, key, args) {
+        Some(Ok(text)) => text,
+        Some(Err(err)) => {
+            tracing::error!("{}", err);
+            err.partial
+        }
+        None => key.to_string(),
+    }
+}
+
+/// The current request's locale, for templates. Outside a request, the
+/// `i18n.default_locale` the loaded translations were built with.
+pub fn locale() -> String {
+    Locale::current()
+        .map(|locale| locale.0)
+        .unwrap_or_else(|| I18N_STATIC_CONTENT.load().default_locale().to_string())
+}
+
+/// `path` under the current request's locale prefix, for links such as
+/// `href="{{ self::localized("/about") }}"`.
+pub fn localized(path: &str) -> String {
+    page::locale_path(&locale(), I18N_STATIC_CONTENT.load().default_locale(), path)
+}
 
-    if let Some(i18n) = i18n_content {
-        let i18n_lang = &mut *I18N_LANGUAGE.lock().unwrap();
+/// [`translate`] `key` into the current request's locale.
+pub fn t(key: &str) -> String {
+    translate(&locale(), key)
+}
+
+/// Arguments for [`t_with`], e.g. `self::args().arg("count", items.len())`.
+pub fn args() -> MessageArgs {
+    MessageArgs::new()
+}
 
-        if let Some(lang) = i18n_lang {
-            let bundle = i18n.fetch_bundle(lang);
+/// [`format`] `key` with `args` in the current request's locale. Text
+/// arguments are HTML-escaped, since templates output translations as they
+/// are.
+pub fn t_with(key: &str, args: MessageArgs) -> String {
+    let args = args.map_text(escape_html);
+
+    format(&locale(), key, Some(&args))
+}
 
-            bundle.get(key).unwrap_or(&String::default()).to_string()
-        } else {
-            translation_err(
-                "translation_language_missing",
-                "content::templates::translate",
-            )
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
         }
-    } else {
-        translation_err("translation_missing", "content::templates::translate")
     }
+
+    escaped
+}
+
+/// The fingerprinted URL of a file under the asset directory, e.g.
+/// `self::asset("css/output.css")`.
+pub fn asset(path: &str) -> String {
+    ASSETS.load().url(path)
+}
+
+/// The current request's CSRF token, for forms posted by script.
+pub fn csrf_token() -> String {
+    CsrfToken::current()
+        .map(|token| token.0)
+        .unwrap_or_default()
 }
 
-fn translation_err(err: &str, tag: &str) -> String {
-    logger::log(
-        logger::Level::Error,
-        logger::Color(utils::RED),
-        logger::Tag(tag),
-        logger::Text(format!("{}", err).as_str()),
-    );
+/// The current request's Content-Security-Policy nonce, for inline scripts
+/// and styles: `<script nonce="{{ self::csp_nonce() }}">`.
+pub fn csp_nonce() -> String {
+    CspNonce::current().map(|nonce| nonce.0).unwrap_or_default()
+}
 
-    err.to_string()
+/// A hidden input carrying the current request's CSRF token; include it in
+/// every form that does not use GET.
+pub fn csrf_input() -> String {
+    format!(
+        r#"<input type="hidden" name="{}" value="{}" />"#,
+        CSRF_FIELD,
+        csrf_token()
+    )
 }
 
-pub fn render_nav() -> String {
-    let nav = NavTemplate {};
+pub fn render_nav(user: &Option<User>, page: &PageContext) -> String {
+    let nav = NavTemplate {
+        user: user.clone(),
+        page: page.clone(),
+    };
 
     match nav.render() {
         Ok(html) => html,
@@ -79,36 +155,95 @@ pub fn render_nav() -> String {
 // Index: Homepage
 #[derive(Template, Clone)]
 #[template(path = "nav.html", escape = "none")]
-pub struct NavTemplate {}
+pub struct NavTemplate {
+    pub user: Option<User>,
+    pub page: PageContext,
+}
 
 // Index: Homepage
 #[derive(Template, Clone)]
 #[template(path = "index.html", escape = "none")]
-pub struct IndexTemplate {}
+pub struct IndexTemplate {
+    pub user: Option<User>,
+    pub page: PageContext,
+}
 
 // About
 #[derive(Template)]
 #[template(path = "about.html", escape = "none")]
-pub struct AboutTemplate {}
+pub struct AboutTemplate {
+    pub user: Option<User>,
+    pub page: PageContext,
+}
+
+// Login
+#[derive(Template)]
+#[template(path = "login.html", escape = "none")]
+pub struct LoginTemplate {
+    pub user: Option<User>,
+    pub page: PageContext,
+    pub email: String,
+    pub error: Option<String>,
+}
 
 // Panic Error Template
 #[derive(Template)]
 #[template(path = "panic.html", escape = "none")]
-pub(crate) struct PanicErrorTemplate {}
+pub(crate) struct PanicErrorTemplate {
+    user: Option<User>,
+    page: PageContext,
+    incident_id: String,
+}
 
-pub fn panic_error_template() -> String {
-    let template = PanicErrorTemplate {};
+pub fn panic_error_template(incident_id: &str) -> String {
+    let template = PanicErrorTemplate {
+        user: None,
+        page: PageContext::new("error")
+            .title("Something went wrong")
+            .noindex(),
+        incident_id: incident_id.to_string(),
+    };
 
     template.render().unwrap()
 }
 
+// 403 Error Template
+#[derive(Template)]
+#[template(path = "error_403.html", escape = "none")]
+pub(crate) struct Error403Template {
+    pub user: Option<User>,
+    pub page: PageContext,
+    pub correlation_id: String,
+}
+
+// CSRF Error Template
+#[derive(Template)]
+#[template(path = "error_csrf.html", escape = "none")]
+pub(crate) struct CsrfErrorTemplate {
+    pub user: Option<User>,
+    pub page: PageContext,
+}
+
 // 404 Error Template
 #[derive(Template)]
 #[template(path = "error_404.html", escape = "none")]
-pub(crate) struct Error404Template {}
-
-pub async fn error_404_template() -> impl IntoResponse {
-    let template = Error404Template {};
+pub(crate) struct Error404Template {
+    pub user: Option<User>,
+    pub page: PageContext,
+    pub correlation_id: String,
+}
 
-    HtmlTemplate(template)
+// Any other error
+#[derive(Template)]
+#[template(path = "error.html", escape = "none")]
+pub(crate) struct ErrorTemplate {
+    pub user: Option<User>,
+    pub page: PageContext,
+    pub status: u16,
+    pub title: String,
+    pub detail: String,
+    pub fields: Vec<FieldError>,
+    pub correlation_id: String,
+    /// Only filled in when `server.debug_errors` is on.
+    pub chain: Vec<String>,
 }
@@ -1,6 +1,22 @@
-use std::collections::HashMap;
-
-#[derive(Debug, Clone, PartialEq)]
+//! Translated copy, kept per locale as plain strings and Fluent messages.
+//!
+//! Plain strings are shown as they are. Fluent (`.ftl`) messages can take
+//! arguments, which select CLDR plural forms and other variants and are
+//! formatted for the locale; see [`format`].
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource, FluentValue};
+use fluent_syntax::ast::{Entry, Expression, InlineExpression, Pattern, PatternElement};
+use format::FluentDate;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use unic_langid::LanguageIdentifier;
+
+pub mod format;
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct TaggedContent {
     content: HashMap<String, String>,
 }
@@ -19,8 +35,17 @@ impl<'a> TaggedContent {
     pub fn get(&self, key: &'a str) -> Option<&String> {
         self.content.get(key)
     }
+
+    pub fn len(&self) -> usize {
+        self.content.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty()
+    }
 }
 
+#[derive(Default)]
 pub struct TaggedContentBuilder {
     content: TaggedContent,
 }
@@ -51,35 +76,351 @@ impl<'a> TaggedContentBuilder {
     }
 }
 
-#[derive(Debug)]
-pub struct I18nBundle<'a> {
-    content: HashMap<&'a str, TaggedContent>,
+/// A key that was looked up but not translated, for editors to fill in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MissingKey {
+    pub locale: String,
+    pub key: String,
+    /// Lookups since the bundle was loaded.
+    pub hits: u64,
+}
+
+/// Arguments for a Fluent message, e.g. `MessageArgs::new().arg("count", 3)`.
+#[derive(Debug, Default)]
+pub struct MessageArgs(FluentArgs<'static>);
+
+impl MessageArgs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn arg(mut self, name: &str, value: impl Into<MessageArg>) -> Self {
+        self.0.set(name.to_string(), value.into().0);
+        self
+    }
+
+    /// Apply `f` to every text argument, e.g. to escape it.
+    pub fn map_text(self, f: impl Fn(&str) -> String) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .map(|(name, value)| match value {
+                    FluentValue::String(text) => (name, FluentValue::from(f(&text))),
+                    other => (name, other),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A value for [`MessageArgs`]: a number, text or a date.
+pub struct MessageArg(FluentValue<'static>);
+
+macro_rules! message_arg_from_number {
+    ($($typ:ty),*) => {
+        $(
+            impl From<$typ> for MessageArg {
+                fn from(value: $typ) -> Self {
+                    MessageArg(FluentValue::from(value))
+                }
+            }
+        )*
+    };
+}
+
+message_arg_from_number!(i32, i64, u32, u64, usize, f64);
+
+impl From<&str> for MessageArg {
+    fn from(value: &str) -> Self {
+        MessageArg(FluentValue::from(value.to_string()))
+    }
+}
+
+impl From<String> for MessageArg {
+    fn from(value: String) -> Self {
+        MessageArg(FluentValue::from(value))
+    }
+}
+
+impl From<chrono::DateTime<chrono::Utc>> for MessageArg {
+    fn from(value: chrono::DateTime<chrono::Utc>) -> Self {
+        MessageArg(FluentValue::Custom(Box::new(FluentDate(value))))
+    }
+}
+
+/// A Fluent message that could not be fully formatted, such as one missing an
+/// argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatError {
+    pub locale: String,
+    pub key: String,
+    pub errors: Vec<String>,
+    /// The text produced anyway, with unresolved parts shown as `{$name}`.
+    pub partial: String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unable to format {} in {}: {}",
+            self.key,
+            self.locale,
+            self.errors.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+pub struct I18nBundle {
+    content: HashMap<String, TaggedContent>,
+    fluent: HashMap<String, FluentBundle<FluentResource>>,
+    /// Message IDs in each locale's Fluent resources.
+    fluent_keys: HashMap<String, BTreeSet<String>>,
+    /// Consulted when a key is missing from the requested locale.
+    default_locale: String,
+    /// Only locked when a lookup misses.
+    missing: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+impl fmt::Debug for I18nBundle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("I18nBundle")
+            .field("locales", &self.locales())
+            .field("default_locale", &self.default_locale)
+            .finish()
+    }
+}
+
+impl Default for I18nBundle {
+    fn default() -> Self {
+        Self::with_default_locale("en")
+    }
 }
 
-impl<'a> I18nBundle<'a> {
+impl I18nBundle {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_default_locale(locale: &str) -> Self {
         Self {
             content: HashMap::new(),
+            fluent: HashMap::new(),
+            fluent_keys: HashMap::new(),
+            default_locale: locale.to_string(),
+            missing: Mutex::new(BTreeMap::new()),
         }
     }
 
-    pub fn get(&self, key: &'a str) -> Option<&TaggedContent> {
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    pub fn get(&self, key: &str) -> Option<&TaggedContent> {
         self.content.get(key)
     }
 
-    pub fn fetch(&self, language: &'a str, tag: &'a str) -> Option<String> {
+    pub fn fetch(&self, language: &str, tag: &str) -> Option<String> {
         if let Some(content) = self.get(language) {
-            content.get(tag).map(|s| s.clone())
+            content.get(tag).cloned()
         } else {
             None
         }
     }
 
-    pub fn fetch_bundle(&self, language: &'a str) -> TaggedContent {
-        self.get(language).unwrap().clone()
+    /// The plain strings of the first loaded locale in `language`'s
+    /// [fallback chain](I18nBundle::fallback_chain).
+    pub fn fetch_bundle(&self, language: &str) -> Option<&TaggedContent> {
+        self.fallback_chain(language)
+            .iter()
+            .find_map(|locale| self.get(locale))
     }
 
-    pub fn create_language(&mut self, key: &'a str) {
+    /// The locales consulted for `language`, most specific first: `pt-BR`
+    /// falls back to `pt` and then to the default locale.
+    pub fn fallback_chain(&self, language: &str) -> Vec<String> {
+        let mut chain = vec![language.to_string()];
+        let mut tag = language;
+        while let Some((parent, _)) = tag.rsplit_once('-') {
+            chain.push(parent.to_string());
+            tag = parent;
+        }
+        if !chain.contains(&self.default_locale) {
+            chain.push(self.default_locale.clone());
+        }
+
+        chain
+    }
+
+    /// Look `tag` up along `language`'s fallback chain. The requested locale
+    /// is recorded as missing when it lacks `tag`, and so is the default
+    /// locale when nothing had it.
+    pub fn translate(&self, language: &str, tag: &str) -> Option<String> {
+        self.format(language, tag, None)
+            .map(|formatted| formatted.unwrap_or_else(|err| err.partial))
+    }
+
+    /// Like [`I18nBundle::translate`], formatting Fluent messages with `args`.
+    /// A message that cannot be fully formatted is an error carrying what
+    /// could be.
+    pub fn format(
+        &self,
+        language: &str,
+        tag: &str,
+        args: Option<&MessageArgs>,
+    ) -> Option<Result<String, FormatError>> {
+        let mut chain = self.fallback_chain(language).into_iter();
+        chain.next();
+
+        if let Some(formatted) = self.format_in(language, tag, args) {
+            return Some(formatted);
+        }
+        self.record_missing(language, tag);
+
+        let formatted = chain.find_map(|locale| self.format_in(&locale, tag, args));
+        if formatted.is_none() && language != self.default_locale {
+            self.record_missing(&self.default_locale, tag);
+        }
+
+        formatted
+    }
+
+    /// Plain strings win over Fluent messages, so that editors can override
+    /// copy shipped in `.ftl` files.
+    fn format_in(
+        &self,
+        language: &str,
+        tag: &str,
+        args: Option<&MessageArgs>,
+    ) -> Option<Result<String, FormatError>> {
+        if let Some(value) = self.fetch(language, tag) {
+            return Some(Ok(value));
+        }
+
+        let bundle = self.fluent.get(language)?;
+        let pattern = bundle.get_message(tag)?.value()?;
+        let mut errors = Vec::new();
+        let text = bundle
+            .format_pattern(pattern, args.map(|args| &args.0), &mut errors)
+            .into_owned();
+
+        Some(if errors.is_empty() {
+            Ok(text)
+        } else {
+            Err(FormatError {
+                locale: language.to_string(),
+                key: tag.to_string(),
+                errors: errors.iter().map(ToString::to_string).collect(),
+                partial: text,
+            })
+        })
+    }
+
+    /// Parse a Fluent resource into `language`, replacing messages it already
+    /// has. Errors name the line of each syntax error.
+    pub fn add_fluent(&mut self, language: &str, source: &str) -> Result<(), Vec<String>> {
+        let langid: LanguageIdentifier = language
+            .parse()
+            .map_err(|err| vec![format!("invalid locale {:?}: {}", language, err)])?;
+        let resource = FluentResource::try_new(source.to_string()).map_err(|(_, errors)| {
+            errors
+                .iter()
+                .map(|err| {
+                    let line = source[..err.pos.start.min(source.len())]
+                        .lines()
+                        .count()
+                        .max(1);
+                    format!("line {}: {}", line, err)
+                })
+                .collect::<Vec<_>>()
+        })?;
+
+        let keys = self.fluent_keys.entry(language.to_string()).or_default();
+        for entry in resource.entries() {
+            if let Entry::Message(message) = entry {
+                keys.insert(message.id.name.to_string());
+            }
+        }
+
+        self.fluent
+            .entry(language.to_string())
+            .or_insert_with(|| {
+                let mut bundle = FluentBundle::new_concurrent(vec![langid]);
+                // Output goes into HTML, where bidi isolation marks only get in the way.
+                bundle.set_use_isolating(false);
+                bundle.set_formatter(Some(format::format_value));
+                bundle
+                    .add_builtins()
+                    .expect("Builtins are added to an empty bundle");
+                bundle
+            })
+            .add_resource_overriding(resource);
+
+        Ok(())
+    }
+
+    fn record_missing(&self, language: &str, tag: &str) {
+        let mut missing = self.missing.lock().unwrap_or_else(|err| err.into_inner());
+        *missing
+            .entry((language.to_string(), tag.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Keys looked up without a translation, by locale then key.
+    pub fn missing(&self) -> Vec<MissingKey> {
+        self.missing
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .map(|((locale, key), hits)| MissingKey {
+                locale: locale.clone(),
+                key: key.clone(),
+                hits: *hits,
+            })
+            .collect()
+    }
+
+    /// Every key translated in `language`, as plain strings or Fluent messages.
+    pub fn keys(&self, language: &str) -> BTreeSet<String> {
+        let mut keys = self.fluent_keys.get(language).cloned().unwrap_or_default();
+        if let Some(content) = self.get(language) {
+            keys.extend(content.content.keys().cloned());
+        }
+
+        keys
+    }
+
+    /// The variables `tag` refers to in `language`, or `None` when it is not
+    /// translated there. Plain strings take none.
+    pub fn placeholders(&self, language: &str, tag: &str) -> Option<BTreeSet<String>> {
+        if self.get(language).and_then(|c| c.get(tag)).is_some() {
+            return Some(BTreeSet::new());
+        }
+
+        let message = self.fluent.get(language)?.get_message(tag)?;
+        let mut names = BTreeSet::new();
+        if let Some(pattern) = message.value() {
+            pattern_variables(pattern, &mut names);
+        }
+        for attribute in message.attributes() {
+            pattern_variables(attribute.value(), &mut names);
+        }
+
+        Some(names)
+    }
+
+    /// The number of keys translated in each locale.
+    pub fn locales(&self) -> BTreeMap<String, usize> {
+        self.content
+            .keys()
+            .chain(self.fluent_keys.keys())
+            .map(|locale| (locale.clone(), self.keys(locale).len()))
+            .collect()
+    }
+
+    pub fn create_language(&mut self, key: &str) {
         let lang = self.get(key);
 
         // Do not replace content for language if it already exists
@@ -88,25 +429,57 @@ impl<'a> I18nBundle<'a> {
         }
 
         let content = TaggedContent::new();
-        self.content.insert(key, content);
+        self.content.insert(key.to_string(), content);
     }
 
-    fn replace_content(&mut self, key: &'a str, value: TaggedContent) {
-        self.content.insert(key, value);
+    /// Merge `value` into `key`'s plain strings, creating the locale if needed.
+    pub fn add_to_content(&mut self, key: &str, value: TaggedContent) {
+        self.content
+            .entry(key.to_string())
+            .or_default()
+            .content
+            .extend(value.content);
     }
+}
 
-    pub fn add_to_content(&mut self, key: &'a str, value: TaggedContent) {
-        if let Some(collection) = self.get(key) {
-            // FIXME: This is a temporary workaround borrowing issues; potential performance
-            // penalty in terms of heap allocation
-            let mut new_collection = collection.clone();
-            new_collection.content.extend(value.content.clone());
+fn pattern_variables(pattern: &Pattern<&str>, names: &mut BTreeSet<String>) {
+    for element in &pattern.elements {
+        if let PatternElement::Placeable { expression } = element {
+            expression_variables(expression, names);
+        }
+    }
+}
 
-            self.replace_content(key, new_collection);
+fn expression_variables(expression: &Expression<&str>, names: &mut BTreeSet<String>) {
+    match expression {
+        Expression::Inline(inline) => inline_variables(inline, names),
+        Expression::Select { selector, variants } => {
+            inline_variables(selector, names);
+            for variant in variants {
+                pattern_variables(&variant.value, names);
+            }
         }
     }
 }
 
+fn inline_variables(inline: &InlineExpression<&str>, names: &mut BTreeSet<String>) {
+    match inline {
+        InlineExpression::VariableReference { id } => {
+            names.insert(id.name.to_string());
+        }
+        InlineExpression::FunctionReference { arguments, .. } => {
+            for argument in &arguments.positional {
+                inline_variables(argument, names);
+            }
+            for argument in &arguments.named {
+                inline_variables(&argument.value, names);
+            }
+        }
+        InlineExpression::Placeable { expression } => expression_variables(expression, names),
+        _ => {}
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -164,7 +537,7 @@ mod test {
         content.add("brown_fox", "The brown fox is just lazy".to_string());
 
         i18n.create_language("en");
-        i18n.replace_content("en", content);
+        i18n.add_to_content("en", content);
 
         i18n.create_language("en");
 
@@ -173,4 +546,112 @@ mod test {
             Some(&"The brown fox is just lazy".to_string())
         );
     }
+
+    #[test]
+    fn falls_back_to_the_default_locale() {
+        let mut i18n = I18nBundle::with_default_locale("en");
+        i18n.create_language("en");
+        i18n.create_language("fr");
+        i18n.add_to_content(
+            "en",
+            TaggedContentBuilder::from(vec![
+                ("greeting", "Hello".to_string()),
+                ("farewell", "Goodbye".to_string()),
+            ])
+            .build(),
+        );
+        i18n.add_to_content(
+            "fr",
+            TaggedContentBuilder::from(vec![("greeting", "Bonjour".to_string())]).build(),
+        );
+
+        assert_eq!(i18n.translate("fr", "greeting").as_deref(), Some("Bonjour"));
+        assert_eq!(i18n.translate("fr", "farewell").as_deref(), Some("Goodbye"));
+        assert_eq!(i18n.translate("fr", "farewell").as_deref(), Some("Goodbye"));
+        assert_eq!(i18n.translate("de", "nowhere"), None);
+
+        let missing = i18n.missing();
+        let missing: Vec<(&str, &str, u64)> = missing
+            .iter()
+            .map(|missing| (missing.locale.as_str(), missing.key.as_str(), missing.hits))
+            .collect();
+        assert_eq!(
+            missing,
+            vec![
+                ("de", "nowhere", 1),
+                ("en", "nowhere", 1),
+                ("fr", "farewell", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_the_fallback_chain() {
+        let mut i18n = I18nBundle::with_default_locale("en");
+        i18n.add_to_content(
+            "en",
+            TaggedContentBuilder::from(vec![("bus", "Bus".to_string())]).build(),
+        );
+        i18n.add_to_content(
+            "pt",
+            TaggedContentBuilder::from(vec![("bus", "Autocarro".to_string())]).build(),
+        );
+        i18n.add_to_content(
+            "pt-BR",
+            TaggedContentBuilder::from(vec![("hello", "Oi".to_string())]).build(),
+        );
+
+        assert_eq!(i18n.fallback_chain("pt-BR"), vec!["pt-BR", "pt", "en"]);
+        assert_eq!(i18n.translate("pt-BR", "hello").as_deref(), Some("Oi"));
+        assert_eq!(i18n.translate("pt-BR", "bus").as_deref(), Some("Autocarro"));
+        assert_eq!(i18n.translate("de-AT", "bus").as_deref(), Some("Bus"));
+        assert!(i18n.fetch_bundle("de").unwrap().get("bus").is_some());
+        assert!(I18nBundle::new().fetch_bundle("de").is_none());
+    }
+
+    #[test]
+    fn formats_fluent_messages() {
+        let mut i18n = I18nBundle::with_default_locale("en");
+        i18n.add_fluent(
+            "en",
+            "cart_items = { $count ->\n    [one] One item\n   *[other] { $count } items\n}\nsince = Since { $date }\n",
+        )
+        .unwrap();
+        i18n.add_fluent(
+            "fr",
+            "cart_items = { $count ->\n    [one] { $count } article\n   *[other] { $count } articles\n}\n",
+        )
+        .unwrap();
+        let count = |n: f64| MessageArgs::new().arg("count", n);
+
+        let en = |i18n: &mut I18nBundle, args| i18n.format("en", "cart_items", Some(&args));
+        assert_eq!(en(&mut i18n, count(1.0)), Some(Ok("One item".to_string())));
+        assert_eq!(
+            en(&mut i18n, count(1234.0)),
+            Some(Ok("1,234 items".to_string()))
+        );
+
+        // CLDR puts 0 and 1.5 in French's "one" category.
+        let fr = |i18n: &mut I18nBundle, args| i18n.format("fr", "cart_items", Some(&args));
+        assert_eq!(fr(&mut i18n, count(0.0)), Some(Ok("0 article".to_string())));
+        assert_eq!(
+            fr(&mut i18n, count(1.5)),
+            Some(Ok("1,5 article".to_string()))
+        );
+
+        let date = chrono::DateTime::parse_from_rfc3339("2024-12-25T10:00:00Z")
+            .unwrap()
+            .to_utc();
+        let since = i18n.format("en", "since", Some(&MessageArgs::new().arg("date", date)));
+        assert_eq!(since, Some(Ok("Since 12/25/2024".to_string())));
+
+        let err = i18n.format("en", "cart_items", None).unwrap().unwrap_err();
+        assert!(err.to_string().contains("$count"), "{}", err);
+        assert_eq!(err.partial, "{$count} items");
+
+        let errors = i18n
+            .add_fluent("en", "ok = fine\nbroken = {\n")
+            .unwrap_err();
+        assert!(errors[0].starts_with("line 2:"), "{:?}", errors);
+    }
 }
@@ -0,0 +1,124 @@
+//! Locale-aware formatting of Fluent arguments.
+//!
+//! Numbers use the locale's decimal and grouping separators, and dates its
+//! usual date representation, both taken from the glibc locale data shipped in
+//! `pure-rust-locales`. Languages without data format like `en_US`.
+
+use chrono::{DateTime, Utc};
+use fluent_bundle::types::{FluentNumber, FluentType};
+use fluent_bundle::FluentValue;
+use intl_memoizer::Memoizable;
+use pure_rust_locales::{locale_match, Locale};
+use std::borrow::Cow;
+use std::convert::Infallible;
+use unic_langid::LanguageIdentifier;
+
+/// Separators and date format of one language, built once per bundle by its
+/// memoizer.
+pub struct LocaleFormat {
+    locale: Locale,
+    decimal: &'static str,
+    group: &'static str,
+}
+
+impl Memoizable for LocaleFormat {
+    type Args = ();
+    type Error = Infallible;
+
+    fn construct(lang: LanguageIdentifier, _args: ()) -> Result<Self, Infallible> {
+        Ok(Self::new(&lang))
+    }
+}
+
+impl LocaleFormat {
+    pub fn new(lang: &LanguageIdentifier) -> Self {
+        let language = lang.language.as_str();
+        // glibc names locales `language_REGION`; without a region, try the
+        // language's namesake country, as in `fr_FR` or `de_DE`.
+        let candidates = [
+            lang.region
+                .map(|region| format!("{}_{}", language, region.as_str())),
+            Some(format!("{}_{}", language, language.to_ascii_uppercase())),
+        ];
+        let locale = candidates
+            .into_iter()
+            .flatten()
+            .find_map(|name| Locale::try_from(name.as_str()).ok())
+            .unwrap_or(Locale::en_US);
+
+        Self {
+            locale,
+            decimal: locale_match!(locale => LC_NUMERIC::DECIMAL_POINT),
+            group: locale_match!(locale => LC_NUMERIC::THOUSANDS_SEP),
+        }
+    }
+
+    pub fn number(&self, number: &FluentNumber) -> String {
+        let text = number.as_string();
+        let (integer, fraction) = match text.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (text.as_ref(), None),
+        };
+        let (sign, digits) = match integer.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", integer),
+        };
+
+        let mut formatted = sign.to_string();
+        let grouped = number.options.use_grouping && !self.group.is_empty();
+        for (i, digit) in digits.chars().enumerate() {
+            if grouped && i > 0 && (digits.len() - i) % 3 == 0 {
+                formatted.push_str(self.group);
+            }
+            formatted.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            formatted.push_str(self.decimal);
+            formatted.push_str(fraction);
+        }
+
+        formatted
+    }
+
+    pub fn date(&self, date: &DateTime<Utc>) -> String {
+        date.format_localized("%x", self.locale).to_string()
+    }
+}
+
+/// Number formatting for a bundle; install with `set_formatter`.
+pub fn format_value(
+    value: &FluentValue,
+    intls: &intl_memoizer::concurrent::IntlLangMemoizer,
+) -> Option<String> {
+    match value {
+        FluentValue::Number(number) => intls
+            .with_try_get::<LocaleFormat, _, _>((), |format| format.number(number))
+            .ok(),
+        _ => None,
+    }
+}
+
+/// A date argument, shown in the locale's date format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FluentDate(pub DateTime<Utc>);
+
+impl FluentType for FluentDate {
+    fn duplicate(&self) -> Box<dyn FluentType + Send> {
+        Box::new(self.clone())
+    }
+
+    fn as_string(&self, intls: &intl_memoizer::IntlLangMemoizer) -> Cow<'static, str> {
+        intls
+            .with_try_get::<LocaleFormat, _, _>((), |format| format.date(&self.0))
+            .map_or_else(|never| match never {}, Cow::Owned)
+    }
+
+    fn as_string_threadsafe(
+        &self,
+        intls: &intl_memoizer::concurrent::IntlLangMemoizer,
+    ) -> Cow<'static, str> {
+        intls
+            .with_try_get::<LocaleFormat, _, _>((), |format| format.date(&self.0))
+            .map_or_else(|never| match never {}, Cow::Owned)
+    }
+}
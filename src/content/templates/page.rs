@@ -0,0 +1,194 @@
+//! What the layout needs to know about the page it wraps: which nav item is
+//! active, the `<title>`, meta description, canonical URL, Open Graph and
+//! Twitter card fields, and breadcrumbs.
+//!
+//! Handlers extract a [`PageContext`], which fills in the canonical URL from
+//! the request, and name the route and title before rendering.
+
+use super::t;
+use crate::config::AppConfig;
+use crate::server::locale::Locale;
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use std::convert::Infallible;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breadcrumb {
+    pub label: String,
+    pub href: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PageContext {
+    /// Matched by `nav.html` to highlight the active link, e.g. `"about"`.
+    pub route: &'static str,
+    /// Without the site name, which [`PageContext::full_title`] adds.
+    pub title: String,
+    pub description: String,
+    pub canonical_url: Option<String>,
+    /// The Open Graph `og:type`.
+    pub og_type: &'static str,
+    /// Absolute URL of the image shown when the page is shared.
+    pub image: Option<String>,
+    pub breadcrumbs: Vec<Breadcrumb>,
+    /// Asks search engines not to index the page, e.g. for errors.
+    pub noindex: bool,
+}
+
+impl PageContext {
+    /// A page described by the site's `site_description`.
+    pub fn new(route: &'static str) -> Self {
+        Self {
+            route,
+            description: t("site_description"),
+            og_type: "website",
+            ..Self::default()
+        }
+    }
+
+    pub fn route(mut self, route: &'static str) -> Self {
+        self.route = route;
+        self
+    }
+
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    pub fn image(mut self, url: impl Into<String>) -> Self {
+        self.image = Some(url.into());
+        self
+    }
+
+    pub fn breadcrumb(mut self, label: impl Into<String>, href: impl Into<String>) -> Self {
+        self.breadcrumbs.push(Breadcrumb {
+            label: label.into(),
+            href: href.into(),
+        });
+        self
+    }
+
+    pub fn noindex(mut self) -> Self {
+        self.noindex = true;
+        self.canonical_url = None;
+        self
+    }
+
+    /// The `<title>`: the page title followed by the site name.
+    pub fn full_title(&self) -> String {
+        let site = t("site_name_short");
+        if self.title.is_empty() {
+            site
+        } else {
+            format!("{} · {}", self.title, site)
+        }
+    }
+
+    /// The `twitter:card` type, large when there is an image to show.
+    pub fn twitter_card(&self) -> &'static str {
+        match self.image {
+            Some(_) => "summary_large_image",
+            None => "summary",
+        }
+    }
+}
+
+/// `path` as linked from a page in `locale`: prefixed with the locale, unless
+/// it is the default one.
+pub fn locale_path(locale: &str, default_locale: &str, path: &str) -> String {
+    if locale == default_locale {
+        path.to_string()
+    } else if path == "/" {
+        format!("/{}/", locale)
+    } else {
+        format!("/{}{}", locale, path)
+    }
+}
+
+/// The canonical URL of `path` under `site_url`. Pages in the default locale
+/// are canonical without a locale prefix; others keep theirs.
+fn canonical_url(site_url: &str, locale: &str, default_locale: &str, path: &str) -> String {
+    format!(
+        "{}{}",
+        site_url.trim_end_matches('/'),
+        locale_path(locale, default_locale, path)
+    )
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for PageContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let mut page = PageContext::new("");
+
+        let config = parts.extensions.get::<AppConfig>();
+        if let Some((config, site_url)) =
+            config.and_then(|config| Some((config, config.server.site_url.as_deref()?)))
+        {
+            let locale = parts
+                .extensions
+                .get::<Locale>()
+                .map_or(config.i18n.default_locale.as_str(), |locale| &locale.0);
+            page.canonical_url = Some(canonical_url(
+                site_url,
+                locale,
+                &config.i18n.default_locale,
+                parts.uri.path(),
+            ));
+        }
+
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::content::templates::NavTemplate;
+    use askama::Template;
+
+    #[test]
+    fn canonical_urls_keep_non_default_locales() {
+        let url = |locale, path| canonical_url("https://example.com/", locale, "en", path);
+
+        assert_eq!(url("en", "/about"), "https://example.com/about");
+        assert_eq!(url("fr", "/about"), "https://example.com/fr/about");
+        assert_eq!(url("fr", "/"), "https://example.com/fr/");
+    }
+
+    #[test]
+    fn links_keep_the_locale_prefix() {
+        assert_eq!(locale_path("en", "en", "/about"), "/about");
+        assert_eq!(locale_path("fr", "en", "/about"), "/fr/about");
+        assert_eq!(locale_path("fr", "en", "/"), "/fr/");
+    }
+
+    #[test]
+    fn nav_highlights_the_current_route() {
+        let nav = |route| {
+            NavTemplate {
+                user: None,
+                page: PageContext::new(route),
+            }
+            .render()
+            .unwrap()
+        };
+        let active = |html: &str, href: &str| {
+            let link = html.split(&format!(r#"href="{}""#, href)).nth(1).unwrap();
+            link[..link.find('>').unwrap()].contains("text-black font-extrabold")
+        };
+
+        let about = nav("about");
+        assert!(active(&about, "/about"));
+        assert!(!active(&about, "/"));
+        assert!(active(&nav("index"), "/"));
+    }
+}
@@ -0,0 +1,208 @@
+//! Loads translations into the shared [`I18nBundle`].
+//!
+//! Catalogs ship as `<catalog_dir>/<locale>/*.ftl` Fluent files and `*.json`
+//! files of plain strings, while copy that editors change lives in the
+//! `translations` table and overrides them. All of it is read once at startup
+//! and again whenever [`reload`] runs: from the admin action, the
+//! `reload_translations` job, or [`watch`] when a catalog file changes.
+//! [`lint`] checks the catalogs against the templates.
+
+use super::sorted_entries;
+use super::templates::i18n::{I18nBundle, TaggedContent};
+use super::templates::I18N_STATIC_CONTENT;
+use crate::config::AppConfig;
+use crate::error::Error;
+use crate::lifecycle::Shutdown;
+use crate::models::translations;
+use crate::mpsc::jobs::{Job, JobContext};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub mod lint;
+
+#[derive(Debug, Clone)]
+pub struct I18nConfig {
+    /// Used for keys missing from the requested locale, and for visitors who
+    /// ask for none of `locales`.
+    pub default_locale: String,
+    /// Locales visitors can choose, by URL prefix, cookie or `Accept-Language`.
+    pub locales: Vec<String>,
+    /// Holds a directory of `.ftl` and `.json` files per locale.
+    pub catalog_dir: PathBuf,
+    /// Seconds between checks of the catalogs for changes; 0 turns reloading
+    /// off. Meant for development.
+    pub watch_interval: u64,
+}
+
+impl Default for I18nConfig {
+    fn default() -> Self {
+        Self {
+            default_locale: "en".to_string(),
+            locales: vec!["en".to_string()],
+            catalog_dir: PathBuf::from("locales"),
+            watch_interval: 0,
+        }
+    }
+}
+
+/// Build a bundle from the catalog files and every row in the `translations`
+/// table.
+pub async fn load(pool: &PgPool, config: &I18nConfig) -> Result<I18nBundle, Error> {
+    let mut bundle = I18nBundle::with_default_locale(&config.default_locale);
+    load_catalogs(&mut bundle, &config.catalog_dir)?;
+
+    let mut locales: HashMap<String, TaggedContent> = HashMap::new();
+    for row in translations::all(pool).await? {
+        locales
+            .entry(row.locale)
+            .or_default()
+            .add(&row.key, row.value);
+    }
+
+    bundle.create_language(&config.default_locale);
+    for (locale, content) in locales {
+        bundle.create_language(&locale);
+        bundle.add_to_content(&locale, content);
+    }
+
+    Ok(bundle)
+}
+
+/// Add every `<dir>/<locale>/*.ftl` and `*.json` file to `bundle`. A missing
+/// `dir` is not an error.
+pub fn load_catalogs(bundle: &mut I18nBundle, dir: &Path) -> Result<(), Error> {
+    for path in catalog_files(dir)? {
+        let Some(locale) = path
+            .parent()
+            .and_then(Path::file_name)
+            .and_then(|name| name.to_str())
+        else {
+            continue;
+        };
+        let source = std::fs::read_to_string(&path)?;
+        let failed = |message: String| Error::new(format!("{}: {}", path.display(), message));
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("ftl") => bundle
+                .add_fluent(locale, &source)
+                .map_err(|errors| failed(errors.join("; ")))?,
+            Some("json") => {
+                let strings: HashMap<String, String> = serde_json::from_str(&source)
+                    .map_err(|err| failed(format!("expected an object of strings, {}", err)))?;
+                let mut content = TaggedContent::new();
+                for (key, value) in strings {
+                    content.add(&key, value);
+                }
+
+                bundle.create_language(locale);
+                bundle.add_to_content(locale, content);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The `.ftl` and `.json` files under `dir`, one directory per locale.
+fn catalog_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for locale_dir in sorted_entries(dir)? {
+        if !locale_dir.is_dir() {
+            continue;
+        }
+
+        files.extend(sorted_entries(&locale_dir)?.into_iter().filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "ftl" || extension == "json")
+        }));
+    }
+
+    Ok(files)
+}
+
+/// Replace the shared bundle with the table's current contents, returning the
+/// number of keys loaded. Missing-key counts start over.
+pub async fn reload(pool: &PgPool, config: &I18nConfig) -> Result<usize, Error> {
+    let bundle = load(pool, config).await?;
+    let loaded = bundle.locales().values().sum();
+
+    I18N_STATIC_CONTENT.store(Arc::new(bundle));
+    tracing::info!("Loaded {} translations", loaded);
+
+    Ok(loaded)
+}
+
+/// Reload translations whenever a catalog file is added, changed or removed,
+/// checking every `every` until `shutdown`. A failed reload keeps serving the
+/// previous translations.
+pub async fn watch(pool: PgPool, config: I18nConfig, every: Duration, mut shutdown: Shutdown) {
+    let mut seen = fingerprint(&config.catalog_dir);
+    let mut ticker = tokio::time::interval(every);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.wait() => return,
+        }
+
+        let current = fingerprint(&config.catalog_dir);
+        if current == seen {
+            continue;
+        }
+        seen = current;
+
+        if let Err(err) = reload(&pool, &config).await {
+            tracing::error!(
+                "Unable to reload translations, keeping the previous ones: {}",
+                err
+            );
+        }
+    }
+}
+
+fn fingerprint(dir: &Path) -> Vec<(PathBuf, Option<(SystemTime, u64)>)> {
+    catalog_files(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|path| {
+            let stamp = std::fs::metadata(&path)
+                .ok()
+                .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())));
+            (path, stamp)
+        })
+        .collect()
+}
+
+/// Reload translations; schedule it to pick up edits made directly in the
+/// database.
+pub struct ReloadTranslations;
+
+#[async_trait]
+impl Job for ReloadTranslations {
+    const NAME: &'static str = "reload_translations";
+    type Payload = ();
+
+    async fn run(&self, ctx: &JobContext, _payload: ()) -> Result<Value, Error> {
+        let loaded = reload(pool(&ctx.config)?, &ctx.config.i18n).await?;
+
+        Ok(Value::from(loaded))
+    }
+}
+
+fn pool(config: &AppConfig) -> Result<&PgPool, Error> {
+    config
+        .pg_pool
+        .as_ref()
+        .ok_or_else(|| Error::new("Postgres is not configured"))
+}
@@ -0,0 +1,233 @@
+//! Checks the translations against the templates that use them.
+//!
+//! Keys are found by scanning templates for `t("...")`, `t_with("...", ...)`
+//! and `translate(..., "...")` calls, so keys built at runtime go unseen. A
+//! Fluent message whose comment has a `lint: used` line counts as used, for
+//! examples and keys only code uses.
+
+use super::I18nConfig;
+use crate::content::files;
+use crate::content::templates::i18n::I18nBundle;
+use crate::error::Error;
+use fluent_syntax::ast::Entry;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A key used by a template or translated elsewhere has no copy in `locale`.
+    Missing { locale: String, key: String },
+    /// A key no template uses.
+    Unused { key: String },
+    /// `locale` takes different variables for `key` than the default locale.
+    Placeholders {
+        locale: String,
+        key: String,
+        expected: BTreeSet<String>,
+        found: BTreeSet<String>,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |names: &BTreeSet<String>| {
+            names
+                .iter()
+                .map(|name| format!("${}", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        match self {
+            Problem::Missing { locale, key } => write!(f, "missing: {} has no {}", locale, key),
+            Problem::Unused { key } => write!(f, "unused: {} is not used by any template", key),
+            Problem::Placeholders {
+                locale,
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "placeholders: {} in {} takes [{}], expected [{}]",
+                key,
+                locale,
+                names(found),
+                names(expected)
+            ),
+        }
+    }
+}
+
+/// Every translation key used under `dir`, with the `file:line` places using
+/// it.
+pub fn template_keys(dir: &Path) -> Result<BTreeMap<String, Vec<String>>, Error> {
+    let calls = Regex::new(r#"\b(?:t|t_with)\(\s*"([^"]+)"|\btranslate\([^"]*?"([^"]+)""#)
+        .expect("The pattern is valid");
+
+    let mut keys: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for path in files(dir)? {
+        let source = std::fs::read_to_string(&path)?;
+        for (number, line) in source.lines().enumerate() {
+            for captures in calls.captures_iter(line) {
+                if let Some(key) = captures.get(1).or_else(|| captures.get(2)) {
+                    keys.entry(key.as_str().to_string())
+                        .or_default()
+                        .push(format!("{}:{}", path.display(), number + 1));
+                }
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+/// The Fluent messages under `catalog_dir` marked with a `lint: used` comment
+/// line, with the files marking them.
+pub fn marked_used(catalog_dir: &Path) -> Result<BTreeMap<String, Vec<String>>, Error> {
+    let mut keys: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    if !catalog_dir.is_dir() {
+        return Ok(keys);
+    }
+
+    for path in files(catalog_dir)? {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("ftl") {
+            continue;
+        }
+        let source = std::fs::read_to_string(&path)?;
+        // Syntax errors are reported when the catalogs load.
+        let resource =
+            fluent_syntax::parser::parse(source.as_str()).unwrap_or_else(|(resource, _)| resource);
+        for entry in resource.body {
+            let Entry::Message(message) = entry else {
+                continue;
+            };
+            let marked = message.comment.is_some_and(|comment| {
+                comment
+                    .content
+                    .iter()
+                    .any(|line| line.trim() == "lint: used")
+            });
+            if marked {
+                keys.entry(message.id.name.to_string())
+                    .or_default()
+                    .push(path.display().to_string());
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Compare the configured locales of `bundle` with each other and with the
+/// keys `used` by templates.
+pub fn check(
+    bundle: &I18nBundle,
+    config: &I18nConfig,
+    used: &BTreeMap<String, Vec<String>>,
+) -> Vec<Problem> {
+    let translated: BTreeMap<&str, BTreeSet<String>> = config
+        .locales
+        .iter()
+        .map(|locale| (locale.as_str(), bundle.keys(locale)))
+        .collect();
+    let defined: BTreeSet<&String> = translated.values().flatten().collect();
+    let every_key: BTreeSet<&String> = defined.iter().copied().chain(used.keys()).collect();
+
+    let mut problems = Vec::new();
+    for key in every_key {
+        if !used.contains_key(key) {
+            problems.push(Problem::Unused { key: key.clone() });
+        }
+
+        let expected = bundle.placeholders(&config.default_locale, key);
+        for (locale, keys) in &translated {
+            if !keys.contains(key) {
+                problems.push(Problem::Missing {
+                    locale: locale.to_string(),
+                    key: key.clone(),
+                });
+                continue;
+            }
+
+            if let (Some(expected), Some(found)) = (&expected, bundle.placeholders(locale, key)) {
+                if *expected != found {
+                    problems.push(Problem::Placeholders {
+                        locale: locale.to_string(),
+                        key: key.clone(),
+                        expected: expected.clone(),
+                        found,
+                    });
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reports_missing_unused_and_mismatched_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("layouts")).unwrap();
+        std::fs::write(
+            dir.path().join("layouts/base.html"),
+            "<title>{{ self::t(\"title\") }}</title>\n\
+             {{ self::t_with(\"cart_items\", self::args().arg(\"count\", 2)) }}\n\
+             {{ self::translate(self::locale(), \"tagline\") }}",
+        )
+        .unwrap();
+        let used = template_keys(dir.path()).unwrap();
+        assert_eq!(
+            used.keys().collect::<Vec<_>>(),
+            vec!["cart_items", "tagline", "title"]
+        );
+        assert!(used["tagline"][0].ends_with("base.html:3"));
+
+        std::fs::create_dir(dir.path().join("en")).unwrap();
+        std::fs::write(
+            dir.path().join("en/main.ftl"),
+            "# An example.\n# lint: used\nexample = Hi\n\n# Not lint: used\nother = Bye\n",
+        )
+        .unwrap();
+        let marked = marked_used(dir.path()).unwrap();
+        assert_eq!(marked.keys().collect::<Vec<_>>(), vec!["example"]);
+
+        let mut bundle = I18nBundle::new();
+        bundle
+            .add_fluent(
+                "en",
+                "title = Shop\ntagline = Hi\nold = Gone\n\
+                 cart_items = { $count ->\n [one] One item\n*[other] { $count } items\n}\n",
+            )
+            .unwrap();
+        bundle
+            .add_fluent(
+                "fr",
+                "title = Boutique\ncart_items = { $total } articles\nold = Parti\n",
+            )
+            .unwrap();
+        let config = I18nConfig {
+            locales: vec!["en".to_string(), "fr".to_string()],
+            ..I18nConfig::default()
+        };
+
+        let problems: Vec<String> = check(&bundle, &config, &used)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "placeholders: cart_items in fr takes [$total], expected [$count]",
+                "unused: old is not used by any template",
+                "missing: fr has no tagline",
+            ]
+        );
+    }
+}
@@ -2,14 +2,18 @@
 
 use crate::error_from;
 use axum::{
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use hyper::header::ToStrError;
-use serde_json::json;
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::error::Error as StdError;
 use std::fmt;
 use std::num::ParseIntError;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env::VarError, str::Utf8Error};
 use tokio::task::JoinError;
 
@@ -18,22 +22,147 @@ pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 #[derive(Debug)]
 pub struct Error {
     inner: BoxError,
+    kind: ErrorKind,
+}
+
+/// What went wrong, from the client's point of view; decides the status code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    /// The request was understood but some fields are invalid.
+    Validation(Vec<FieldError>),
+    /// Authentication is required.
+    Unauthorized,
+    /// Authenticated, but not allowed.
+    Forbidden,
+    /// Clashes with the current state, e.g. a duplicate email.
+    Conflict,
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// Too many requests are in flight to take another.
+    Overloaded,
+    /// A service we depend on failed.
+    Upstream,
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether the error message is meant for the client. Messages of other
+    /// kinds may leak internals and are only shown in debug mode.
+    pub fn is_public(&self) -> bool {
+        !matches!(self, ErrorKind::Upstream | ErrorKind::Internal)
+    }
 }
 
 impl Error {
-    #[allow(dead_code)]
-    /// Create a new `Error` from a boxable error.
+    /// Create a new internal `Error` from a boxable error.
     pub fn new(error: impl Into<BoxError>) -> Self {
+        Self::with_kind(ErrorKind::Internal, error)
+    }
+
+    pub fn with_kind(kind: ErrorKind, error: impl Into<BoxError>) -> Self {
         Self {
             inner: error.into(),
+            kind,
         }
     }
 
+    pub fn not_found(message: impl Into<BoxError>) -> Self {
+        Self::with_kind(ErrorKind::NotFound, message)
+    }
+
+    pub fn validation(fields: Vec<FieldError>) -> Self {
+        let message = fields
+            .iter()
+            .map(|field| format!("{}: {}", field.field, field.message))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Self::with_kind(ErrorKind::Validation(fields), message)
+    }
+
+    pub fn unauthorized(message: impl Into<BoxError>) -> Self {
+        Self::with_kind(ErrorKind::Unauthorized, message)
+    }
+
+    pub fn forbidden(message: impl Into<BoxError>) -> Self {
+        Self::with_kind(ErrorKind::Forbidden, message)
+    }
+
+    pub fn conflict(message: impl Into<BoxError>) -> Self {
+        Self::with_kind(ErrorKind::Conflict, message)
+    }
+
+    pub fn rate_limited(retry_after: Option<Duration>) -> Self {
+        Self::with_kind(ErrorKind::RateLimited { retry_after }, "Too many requests")
+    }
+
+    pub fn overloaded() -> Self {
+        Self::with_kind(
+            ErrorKind::Overloaded,
+            "The server is too busy, try again shortly",
+        )
+    }
+
+    pub fn upstream(error: impl Into<BoxError>) -> Self {
+        Self::with_kind(ErrorKind::Upstream, error)
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.kind.status()
+    }
+
     #[allow(dead_code)]
     /// Convert an `Error` back into the underlying boxed trait object.
     pub fn into_inner(self) -> BoxError {
         self.inner
     }
+
+    /// This error's message followed by those of its sources.
+    pub fn chain(&self) -> Vec<String> {
+        let mut chain = vec![self.inner.to_string()];
+        let mut source = self.inner.source();
+        while let Some(err) = source {
+            chain.push(err.to_string());
+            source = err.source();
+        }
+
+        chain
+    }
 }
 
 impl fmt::Display for Error {
@@ -44,13 +173,27 @@ impl fmt::Display for Error {
 
 impl StdError for Error {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
-        Some(&*self.inner)
+        // `Display` already shows `inner`, so continue the chain after it.
+        self.inner.source()
     }
 }
 
 impl From<Box<dyn std::error::Error + Send + Sync>> for Error {
     fn from(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
-        Self { inner: error }
+        Self::new(error)
+    }
+}
+
+/// Missing rows are `NotFound` and unique violations are `Conflict`.
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        let kind = match &error {
+            sqlx::Error::RowNotFound => ErrorKind::NotFound,
+            sqlx::Error::Database(db) if db.is_unique_violation() => ErrorKind::Conflict,
+            _ => ErrorKind::Internal,
+        };
+
+        Self::with_kind(kind, error)
     }
 }
 
@@ -71,33 +214,105 @@ error_from!(Utf8Error);
 error_from!(hyper::header::InvalidHeaderValue);
 error_from!(axum::http::Error);
 
+/// What the error rendering middleware needs to know about a failed response.
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    /// The message for the client; generic for non-public kinds.
+    pub detail: String,
+    pub chain: Vec<String>,
+}
+
+impl ErrorReport {
+    pub fn status(&self) -> StatusCode {
+        self.kind.status()
+    }
+
+    pub fn title(&self) -> &'static str {
+        self.status().canonical_reason().unwrap_or("Error")
+    }
+
+    /// An RFC 7807 problem document; the chain is only included if `debug`.
+    pub fn problem(
+        &self,
+        instance: Option<&str>,
+        correlation_id: Option<&str>,
+        debug: bool,
+    ) -> Value {
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": self.title(),
+            "status": self.status().as_u16(),
+            "detail": self.detail,
+        });
+
+        if let ErrorKind::Validation(fields) = &self.kind {
+            problem["errors"] = json!(fields);
+        }
+        if let Some(instance) = instance {
+            problem["instance"] = json!(instance);
+        }
+        if let Some(correlation_id) = correlation_id {
+            problem["correlation_id"] = json!(correlation_id);
+        }
+        if debug {
+            problem["chain"] = json!(self.chain);
+        }
+
+        problem
+    }
+}
+
+/// Responds with a bare problem document and attaches an [`ErrorReport`], so
+/// that middleware can render it for the client with request details.
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let _leak_error = false;
-        let source = if let Some(ref source) = self.inner.source() {
-            format!("{}", source)
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("Error: {}", self.chain().join(": "));
         } else {
-            String::default()
-        };
+            tracing::debug!("{}: {}", status, self);
+        }
 
-        tracing::error!("Error: {}", source);
+        let detail = if self.kind.is_public() {
+            self.inner.to_string()
+        } else {
+            "The server was unable to complete your request.".to_string()
+        };
+        let report = ErrorReport {
+            chain: self.chain(),
+            kind: self.kind,
+            detail,
+        };
 
-        let error_reason = "Internal Server Error".to_string();
-        let body = Json(json!({
-            "error": error_reason,
-        }));
+        report.response(report.problem(None, None, false))
+    }
+}
 
-        let mut res = body.into_response();
-        res.headers_mut().insert(
-            hyper::header::CONTENT_TYPE,
-            hyper::header::HeaderValue::from_static("application/json"),
-        );
-        *res.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+impl ErrorReport {
+    /// A problem+json response carrying this report.
+    pub fn response(&self, problem: Value) -> Response {
+        let mut res = Json(problem).into_response();
+        *res.status_mut() = self.status();
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        if let ErrorKind::RateLimited {
+            retry_after: Some(retry_after),
+        } = self.kind
+        {
+            // Whole seconds, rounded up so clients do not come back early.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+        res.extensions_mut().insert(Arc::new(self.clone()));
 
         res
     }
 }
 
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
 #[macro_use]
 pub mod macros {
     #[macro_export]
@@ -105,9 +320,7 @@ pub mod macros {
         ($typ:ty) => {
             impl From<$typ> for Error {
                 fn from(error: $typ) -> Self {
-                    Self {
-                        inner: error.into(),
-                    }
+                    Self::new(error)
                 }
             }
         };
@@ -1,4 +1,3 @@
-mod error;
 pub mod utils;
 
 pub mod prelude {
@@ -0,0 +1,95 @@
+//! Process lifecycle: waiting for a shutdown signal and telling long-running
+//! tasks to stop.
+//!
+//! `main` stops things in dependency order: the servers drain their in-flight
+//! connections, the scheduler and Postgres worker stop taking new work, the job
+//! channel closes so that the consumer can finish what it has, and finally the
+//! `PgPool` is closed.
+
+use crate::error::Error;
+use tokio::sync::watch;
+
+/// Tells every [`Shutdown`] made from it to stop.
+#[derive(Debug)]
+pub struct ShutdownTrigger {
+    sender: watch::Sender<bool>,
+}
+
+/// Resolves once shutdown has been triggered; cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+pub fn shutdown_channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+
+    (ShutdownTrigger { sender }, Shutdown { receiver })
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn subscribe(&self) -> Shutdown {
+        Shutdown {
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Wait for shutdown; also resolves if the trigger is dropped.
+    pub async fn wait(&mut self) {
+        let _ = self.receiver.wait_for(|stopping| *stopping).await;
+    }
+}
+
+/// Wait for SIGINT (Ctrl-C) or, on Unix, SIGTERM, returning which arrived.
+pub async fn signal() -> Result<&'static str, Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT").map_err(Error::new),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok("Ctrl-C")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn every_subscriber_sees_the_trigger() {
+        let (trigger, shutdown) = shutdown_channel();
+        let mut late = trigger.subscribe();
+        let mut waiting = shutdown.clone();
+        let waiter = tokio::spawn(async move { waiting.wait().await });
+
+        assert!(!shutdown.is_triggered());
+        trigger.trigger();
+
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        late.wait().await;
+        assert!(shutdown.is_triggered());
+    }
+}
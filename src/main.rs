@@ -1,19 +1,35 @@
-use crate::{config::AppConfig, mpsc::ChannelReceiver, utils::logger};
-use content::templates::{i18n::TaggedContentBuilder, I18N_STATIC_CONTENT};
+use crate::{
+    auth::{PruneSessions, Sessions},
+    config::{AppConfig, JobBackend},
+    mpsc::jobs::{JobRegistry, JobRunner},
+    mpsc::postgres::{PgJobQueue, PgJobWorker},
+    mpsc::queue::{MemoryQueue, SharedJobQueue},
+    mpsc::ChannelReceiver,
+    scheduler::Scheduler,
+    utils::logger,
+};
+use axum_server::Handle;
+use clap::Parser;
+use content::translations::ReloadTranslations;
 use error::Error;
 use mpsc::TxMessage;
+use server::rate_limit::PruneRateLimits;
+use server::security::PruneCspReports;
+use std::sync::Arc;
 use std::sync::LazyLock;
-use std::{
-    env,
-    sync::Arc,
-};
+use std::time::Duration;
 use tokio::sync::Mutex;
 
+pub mod auth;
+pub mod cli;
 pub mod config;
 pub mod content;
 pub mod error;
+pub mod lifecycle;
 pub mod models;
 pub mod mpsc;
+pub mod observability;
+pub mod scheduler;
 pub mod server;
 pub mod utils;
 
@@ -24,22 +40,24 @@ static APP_CONFIG: LazyLock<Mutex<Option<Box<AppConfig>>>> =
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
-    dotenv::from_filename(".env.development").ok();
+    server::panics::install_hook();
+    let cli = cli::Cli::parse();
 
-    // Set the RUST_LOG, if it hasn't been explicitly defined
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var(
-            "RUST_LOG",
-            "gadget-hub=trace,tower_http=trace,tokio=trace,runtime=trace",
-        )
-    }
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_file(true)
-        .with_line_number(true)
-        .init();
+    let sources = cli.sources();
+    let config = AppConfig::from_layers(sources.layers())?;
+    let telemetry = observability::init(&config.observability)?;
 
-    let new_config = config::config().await.expect("Loads config");
+    if let Some(command) = cli.command {
+        let config = if command.needs_database() {
+            config::connect(config).await?
+        } else {
+            config
+        };
+        command.run(&config).await?;
+        telemetry.shutdown();
+        return Ok(());
+    }
+    let new_config = config::connect(config).await?;
     tracing::info!("Config: {:#?}", new_config);
     let arc_config = Arc::new(new_config.clone());
 
@@ -47,63 +65,206 @@ async fn main() -> color_eyre::Result<()> {
     {
         let config_lock = &mut *APP_CONFIG.lock().await;
         if let Some(config) = config_lock {
-            *config = Box::new(new_config);
-        }
-
-        let i18n_content = &mut *I18N_STATIC_CONTENT.lock().unwrap();
-        if let Some(i18n) = i18n_content {
-            let mut builder = TaggedContentBuilder::new();
-            let content = builder
-                .add("site_name_short", "Nosferatu".to_string())
-                .add(
-                    "site_description",
-                    "Static site with Axum and Askama".to_string(),
-                )
-                .build();
-
-            i18n.create_language("en");
-            i18n.add_to_content("en", content);
+            **config = new_config;
         }
     } // This block ensures we drop the lock here.
 
+    let pool = arc_config
+        .pg_pool
+        .clone()
+        .ok_or_else(|| Error::new("Postgres is required for sessions and translations"))?;
+    content::translations::reload(&pool, &arc_config.i18n).await?;
+    content::assets::install(content::assets::AssetManifest::build(&arc_config.assets)?);
+
     // Spin up our API
-    let host: &str = &env::var("SERVER_BIND_HOST").unwrap_or("0.0.0.0".to_string());
-    let port: u16 = env::var("SERVER_BIND_PORT")
-        .unwrap_or("3000".to_string())
-        .parse()
-        .expect("Unable to parse port!");
-    let addr = server::common::NetworkAddr::new(host, port);
+    let server_config = arc_config.server.clone();
+    let addr = server::common::NetworkAddr::new(&server_config.host, server_config.port);
     logger::log(
         logger::Level::Info,
         logger::Color(utils::YELLOW),
         logger::Tag("[ OK ]"),
-        logger::Text(format!("Listening on {}", &addr.to_string()).as_str()),
+        logger::Text(format!("Listening on {}", addr).as_str()),
     );
 
     // Setup mpsc
-    let (tx, receiver) = tokio::sync::mpsc::channel::<TxMessage>(32);
-    let mut rx = ChannelReceiver::new(receiver);
+    let (tx, receiver) =
+        tokio::sync::mpsc::channel::<TxMessage>(arc_config.jobs.queue_capacity.max(1));
+    let mut registry = JobRegistry::new();
+    registry
+        .register(PruneSessions)
+        .register(ReloadTranslations)
+        .register(PruneRateLimits)
+        .register(PruneCspReports);
+    let runner = JobRunner::new(registry.clone(), arc_config.clone());
+    let scheduler = Scheduler::new(
+        &arc_config.scheduler,
+        tx.clone(),
+        runner.subscribe(),
+        arc_config.pg_pool.clone(),
+    );
+    let schedules = scheduler.handle();
+    let mut rx = ChannelReceiver::new(receiver, runner);
+    let (shutdown, stopping) = lifecycle::shutdown_channel();
+    let mut background = tokio::task::JoinSet::new();
 
-    // let config = config::config().await.expect("Loads config");
-    let backend = async move { server::serve(&arc_config, addr, tx).await };
+    if arc_config.scheduler.enabled && !arc_config.scheduler.tasks.0.is_empty() {
+        for task in &arc_config.scheduler.tasks.0 {
+            if !registry.contains(&task.job) {
+                tracing::warn!("Scheduled task {} runs unknown job {}", task.name, task.job);
+            }
+        }
 
-    // single consumer
-    tokio::spawn(async move {
-        if let Err(err) = rx.run().await {
-            let err_message = format!("Error when spawning single consumer! {}", err);
-            tracing::error!("{}", err_message);
+        let stopping = stopping.clone();
+        background.spawn(async move {
+            if let Err(err) = scheduler.run(stopping).await {
+                tracing::error!("Scheduler stopped! {}", err);
+            }
+        });
+    } else {
+        // Drop its job sender now so that the consumer can finish at shutdown.
+        drop(scheduler);
+    }
+
+    if arc_config.i18n.watch_interval > 0 {
+        background.spawn(content::translations::watch(
+            pool.clone(),
+            arc_config.i18n.clone(),
+            Duration::from_secs(arc_config.i18n.watch_interval),
+            stopping.clone(),
+        ));
+    }
+
+    let queue: SharedJobQueue = match (arc_config.jobs.backend, &arc_config.pg_pool) {
+        (JobBackend::Postgres, Some(pool)) => {
+            let mut worker = PgJobWorker::new(
+                pool.clone(),
+                JobRunner::new(registry, arc_config.clone()),
+                &arc_config.jobs,
+            );
+            let stopping = stopping.clone();
+            background.spawn(async move {
+                if let Err(err) = worker.run(stopping).await {
+                    tracing::error!("Postgres job worker stopped! {}", err);
+                }
+            });
 
-            return Err(Error::new(err_message));
+            Arc::new(PgJobQueue::new(pool.clone(), &arc_config.jobs))
         }
+        _ => Arc::new(MemoryQueue::new(tx.clone())),
+    };
+    drop(tx);
 
-        Ok(())
+    let meter = telemetry.meter();
+    observability::metrics::observe_pool(&meter, pool.clone());
+    if telemetry.is_exporting() {
+        background.spawn(observability::metrics::watch_queue(
+            meter.clone(),
+            queue.clone(),
+            Duration::from_millis(arc_config.observability.metrics_interval_ms.max(1)),
+            stopping.clone(),
+        ));
+    }
+
+    let sessions = Sessions::new(pool.clone(), &arc_config.auth);
+    let services = server::Services {
+        queue,
+        schedules,
+        sessions,
+        metrics: Arc::new(observability::metrics::HttpMetrics::new(&meter)),
+    };
+
+    // single consumer; it returns once every job sender is dropped
+    let consumer = tokio::spawn(async move {
+        if let Err(err) = rx.run().await {
+            tracing::error!("Error when spawning single consumer! {}", err);
+        }
     });
 
-    let public_addr = server::common::NetworkAddr::new("0.0.0.0", 9002);
-    tokio::join!(
-        server::public::serve_barebones(server::public::public_dir(), public_addr),
-        backend,
+    let grace = Duration::from_secs(server_config.shutdown_grace);
+    let (api_handle, public_handle, redirect_handle) =
+        (Handle::new(), Handle::new(), Handle::new());
+    {
+        let (api_handle, public_handle, redirect_handle) = (
+            api_handle.clone(),
+            public_handle.clone(),
+            redirect_handle.clone(),
+        );
+        tokio::spawn(async move {
+            match lifecycle::signal().await {
+                Ok(signal) => tracing::info!(
+                    "Received {}, draining connections for up to {:?}",
+                    signal,
+                    grace
+                ),
+                Err(err) => {
+                    tracing::error!("Unable to listen for shutdown signals! {}", err);
+                    return;
+                }
+            }
+            api_handle.graceful_shutdown(Some(grace));
+            public_handle.graceful_shutdown(Some(grace));
+            redirect_handle.graceful_shutdown(Some(grace));
+        });
+    }
+
+    let public_addr =
+        server::common::NetworkAddr::new(&server_config.public_host, server_config.public_port);
+    let redirect = async {
+        match arc_config.tls.redirect_port {
+            Some(port) => {
+                let redirect_addr = server::common::NetworkAddr::new(&server_config.host, port);
+                server::tls::serve_redirect(
+                    redirect_addr,
+                    server_config.port,
+                    redirect_handle.clone(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    };
+    let public = async {
+        if server_config.single_port {
+            return Ok(());
+        }
+        server::public::serve_barebones(
+            server::public::public_dir(&arc_config.assets),
+            public_addr,
+            public_handle.clone(),
+        )
+        .await
+    };
+    let served = tokio::try_join!(
+        public,
+        server::serve(&arc_config, addr, services, api_handle.clone()),
+        redirect,
     );
+    if served.is_err() {
+        // One server failed to start; take the others down with it.
+        api_handle.shutdown();
+        public_handle.shutdown();
+        redirect_handle.shutdown();
+    }
+    tracing::info!("Servers stopped");
+
+    // The router, and with it the memory queue's sender, is gone; stop the
+    // scheduler and Postgres worker so that the channel closes.
+    shutdown.trigger();
+    let drained = tokio::time::timeout(grace, async move {
+        while background.join_next().await.is_some() {}
+        let _ = consumer.await;
+    })
+    .await;
+    match drained {
+        Ok(()) => tracing::info!("Background jobs finished"),
+        Err(_) => tracing::warn!("Background jobs still running after {:?}", grace),
+    }
+
+    pool.close().await;
+    tracing::info!("Database pool closed");
+    telemetry.shutdown();
+
+    served?;
 
     Ok(())
 }
@@ -1,22 +1,46 @@
 use crate::error::Error;
 use sqlx::postgres::PgPoolOptions;
 
+pub mod csp_reports;
+pub mod incidents;
+pub mod rate_limits;
+pub mod roles;
+pub mod translations;
+pub mod users;
+
 pub mod postgres {
     use super::*;
 
     pub mod config {
         use super::*;
         use std::fmt;
+        use std::time::Duration;
 
         #[derive(Clone)]
         #[allow(dead_code)]
         pub struct PgConfig {
             pub url: String,
-            pub connect_timeout: String,
-            pub idle_timeout: String,
-            pub max_lifetime: String,
-            pub min_connections: String,
-            pub max_connections: String,
+            /// Seconds to wait when acquiring a connection.
+            pub connect_timeout: u64,
+            /// Seconds before an idle connection is closed.
+            pub idle_timeout: u64,
+            /// Seconds before a connection is recycled.
+            pub max_lifetime: u64,
+            pub min_connections: u32,
+            pub max_connections: u32,
+        }
+
+        impl Default for PgConfig {
+            fn default() -> Self {
+                Self {
+                    url: String::default(),
+                    connect_timeout: 30,
+                    idle_timeout: 600,
+                    max_lifetime: 1800,
+                    min_connections: 2,
+                    max_connections: 5,
+                }
+            }
         }
 
         impl fmt::Debug for PgConfig {
@@ -33,31 +57,20 @@ pub mod postgres {
         }
 
         pub async fn pg_connection(
-            url: &str,
-            connect_timeout: &str,
-            idle_timeout: &str,
-            max_lifetime: &str,
-            min_connections: &str,
-            max_connections: &str,
+            config: &PgConfig,
         ) -> Result<Result<sqlx::PgPool, sqlx::Error>, Error> {
             let pool = PgPoolOptions::new()
-                .acquire_timeout(std::time::Duration::from_secs(
-                    connect_timeout.parse().expect("Unable to parse into u64!"),
-                ))
-                .idle_timeout(std::time::Duration::from_secs(
-                    idle_timeout.parse().expect("Unable to parse into u64!"),
-                ))
-                .max_lifetime(std::time::Duration::from_secs(
-                    max_lifetime.parse().expect("Unable to parse into u64!"),
-                ))
-                .min_connections(min_connections.parse().expect("Unable to parse into u32!"))
-                .max_connections(max_connections.parse().expect("Unable to parse into u32!"))
-                .connect(url)
+                .acquire_timeout(Duration::from_secs(config.connect_timeout))
+                .idle_timeout(Duration::from_secs(config.idle_timeout))
+                .max_lifetime(Duration::from_secs(config.max_lifetime))
+                .min_connections(config.min_connections)
+                .max_connections(config.max_connections)
+                .connect(&config.url)
                 .await;
 
             tracing::info!(
                 "[ OK ]: Opening PostgreSQL DB connection to URL: {}",
-                crate::config::sanitize_db_url(url)?
+                crate::config::sanitize_db_url(&config.url)?
             );
 
             Ok(pool)
@@ -0,0 +1,54 @@
+//! Content-Security-Policy violations reported by browsers.
+
+use crate::error::Error;
+use sqlx::PgPool;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CspReport {
+    pub document_uri: String,
+    pub blocked_uri: String,
+    pub effective_directive: String,
+    pub disposition: String,
+    pub source_file: Option<String>,
+    pub line_number: Option<i32>,
+    pub column_number: Option<i32>,
+    pub sample: Option<String>,
+    pub user_agent: Option<String>,
+    /// The report as sent.
+    pub body: serde_json::Value,
+}
+
+pub async fn insert(pool: &PgPool, report: &CspReport) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+        insert into csp_reports (document_uri, blocked_uri, effective_directive, disposition,
+                                 source_file, line_number, column_number, sample, user_agent, body)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        "#,
+    )
+    .bind(&report.document_uri)
+    .bind(&report.blocked_uri)
+    .bind(&report.effective_directive)
+    .bind(&report.disposition)
+    .bind(&report.source_file)
+    .bind(report.line_number)
+    .bind(report.column_number)
+    .bind(&report.sample)
+    .bind(&report.user_agent)
+    .bind(&report.body)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Delete reports older than `days`, returning how many were deleted.
+pub async fn delete_older_than(pool: &PgPool, days: u32) -> Result<u64, Error> {
+    let result =
+        sqlx::query("delete from csp_reports where created_at < now() - make_interval(days => $1)")
+            .bind(i32::try_from(days).unwrap_or(i32::MAX))
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected())
+}
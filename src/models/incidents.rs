@@ -0,0 +1,73 @@
+//! Panics caught while handling requests.
+
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub incident_id: Uuid,
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: String,
+    pub method: String,
+    pub path: String,
+    pub correlation_id: String,
+}
+
+/// Incidents sharing a panic location.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct IncidentGroup {
+    pub location: String,
+    pub count: i64,
+    pub last_seen: DateTime<Utc>,
+    pub last_message: String,
+    pub last_path: String,
+    /// Newest first.
+    pub recent_ids: Vec<Uuid>,
+}
+
+pub async fn insert(pool: &PgPool, incident: &Incident) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+        insert into incidents (incident_id, message, location, backtrace, method, path, correlation_id)
+        values ($1, $2, coalesce($3, 'unknown'), $4, $5, $6, $7)
+        "#,
+    )
+    .bind(incident.incident_id)
+    .bind(&incident.message)
+    .bind(&incident.location)
+    .bind(&incident.backtrace)
+    .bind(&incident.method)
+    .bind(&incident.path)
+    .bind(&incident.correlation_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Incidents from the last `days`, grouped by location, most recent first.
+pub async fn recent_by_location(pool: &PgPool, days: i32) -> Result<Vec<IncidentGroup>, Error> {
+    let groups = sqlx::query_as(
+        r#"
+        select location,
+               count(*) as count,
+               max(created_at) as last_seen,
+               (array_agg(message order by created_at desc))[1] as last_message,
+               (array_agg(path order by created_at desc))[1] as last_path,
+               (array_agg(incident_id order by created_at desc))[1:5] as recent_ids
+        from incidents
+        where created_at > now() - make_interval(days => $1)
+        group by location
+        order by last_seen desc
+        "#,
+    )
+    .bind(days)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(groups)
+}
@@ -0,0 +1,58 @@
+//! Rate limiter state kept in Postgres, so that every node counts the same
+//! requests.
+
+use crate::error::Error;
+use sqlx::PgPool;
+
+/// Count a request against `key`, allowing one every `interval` seconds with
+/// up to `burst` at once. `None` when allowed, or else the seconds to wait.
+pub async fn take(
+    pool: &PgPool,
+    key: &str,
+    interval: f64,
+    burst: u32,
+) -> Result<Option<f64>, Error> {
+    // The row lock taken by the upsert serialises nodes counting the same key.
+    let allowed: Option<(i32,)> = sqlx::query_as(
+        r#"
+        insert into rate_limits as limits (key, tat)
+        values ($1, now() + make_interval(secs => $2))
+        on conflict (key) do update
+            set tat = greatest(limits.tat, now()) + make_interval(secs => $2)
+            where greatest(limits.tat, now()) <= now() + make_interval(secs => $2 * ($3 - 1))
+        returning 1
+        "#,
+    )
+    .bind(key)
+    .bind(interval)
+    .bind(f64::from(burst))
+    .fetch_optional(pool)
+    .await?;
+    if allowed.is_some() {
+        return Ok(None);
+    }
+
+    let wait: f64 = sqlx::query_scalar(
+        r#"
+        select extract(epoch from tat - now())::float8 - $2 * ($3 - 1)
+        from rate_limits
+        where key = $1
+        "#,
+    )
+    .bind(key)
+    .bind(interval)
+    .bind(f64::from(burst))
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Some(wait.max(0.0)))
+}
+
+/// Delete keys whose bucket has refilled; they behave as if absent.
+pub async fn delete_expired(pool: &PgPool) -> Result<u64, Error> {
+    let result = sqlx::query("delete from rate_limits where tat <= now()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,50 @@
+//! Roles, the permissions they grant and who holds them.
+
+use crate::error::Error;
+use sqlx::PgPool;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// Every permission granted to `user_id` through their roles.
+pub async fn permissions_for(pool: &PgPool, user_id: Uuid) -> Result<HashSet<String>, Error> {
+    let names: Vec<String> = sqlx::query_scalar(
+        r#"
+        select distinct role_permissions.permission
+        from user_roles
+        join role_permissions on role_permissions.role = user_roles.role
+        where user_roles.user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(names.into_iter().collect())
+}
+
+/// Give the user registered under `email` the role `role`. Returns `false` if
+/// no such user exists.
+pub async fn grant_role(pool: &PgPool, email: &str, role: &str) -> Result<bool, Error> {
+    let result = sqlx::query(
+        r#"
+        insert into user_roles (user_id, role)
+        select user_id, $2 from users where email = $1
+        on conflict do nothing
+        "#,
+    )
+    .bind(email.trim())
+    .bind(role)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() > 0 {
+        return Ok(true);
+    }
+
+    let exists: bool = sqlx::query_scalar("select exists (select 1 from users where email = $1)")
+        .bind(email.trim())
+        .fetch_one(pool)
+        .await?;
+
+    Ok(exists)
+}
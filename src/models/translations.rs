@@ -0,0 +1,45 @@
+//! Translated copy, keyed by locale and message key.
+
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Translation {
+    pub locale: String,
+    pub key: String,
+    pub value: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub async fn all(pool: &PgPool) -> Result<Vec<Translation>, Error> {
+    let translations = sqlx::query_as(
+        r#"
+        select locale, key, value, updated_at
+        from translations
+        order by locale, key
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(translations)
+}
+
+/// Insert or replace the value of `key` in `locale`.
+pub async fn upsert(pool: &PgPool, locale: &str, key: &str, value: &str) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+        insert into translations (locale, key, value)
+        values ($1, $2, $3)
+        on conflict (locale, key) do update set value = excluded.value
+        "#,
+    )
+    .bind(locale)
+    .bind(key)
+    .bind(value)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
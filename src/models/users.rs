@@ -0,0 +1,119 @@
+//! Accounts and their login sessions.
+
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub user_id: Uuid,
+    /// Unique regardless of case.
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An unexpired session and its owner.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Session {
+    #[sqlx(flatten)]
+    pub user: User,
+    /// The token unsafe requests made within the session must carry.
+    pub csrf_token: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Credentials {
+    #[sqlx(flatten)]
+    user: User,
+    password_hash: String,
+}
+
+pub async fn create_user(pool: &PgPool, email: &str, password_hash: &str) -> Result<User, Error> {
+    sqlx::query_as(
+        r#"
+        insert into users (email, password_hash)
+        values ($1, $2)
+        returning user_id, email, created_at
+        "#,
+    )
+    .bind(email.trim())
+    .bind(password_hash)
+    .fetch_one(pool)
+    .await
+    .map_err(Error::from)
+}
+
+/// The user registered under `email` and their password hash.
+pub async fn find_credentials(pool: &PgPool, email: &str) -> Result<Option<(User, String)>, Error> {
+    let found: Option<Credentials> = sqlx::query_as(
+        r#"
+        select user_id, email, created_at, password_hash
+        from users
+        where email = $1
+        "#,
+    )
+    .bind(email.trim())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(found.map(|found| (found.user, found.password_hash)))
+}
+
+/// Start a session for `user_id`, returning its ID.
+pub async fn create_session(
+    pool: &PgPool,
+    user_id: Uuid,
+    ttl: Duration,
+    csrf_token: &str,
+) -> Result<Uuid, Error> {
+    sqlx::query_scalar(
+        r#"
+        insert into sessions (user_id, expires_at, csrf_token)
+        values ($1, now() + make_interval(secs => $2), $3)
+        returning session_id
+        "#,
+    )
+    .bind(user_id)
+    .bind(ttl.as_secs_f64())
+    .bind(csrf_token)
+    .fetch_one(pool)
+    .await
+    .map_err(Error::from)
+}
+
+/// An unexpired session, with its owner.
+pub async fn find_session(pool: &PgPool, session_id: Uuid) -> Result<Option<Session>, Error> {
+    sqlx::query_as(
+        r#"
+        select users.user_id, users.email, users.created_at, sessions.csrf_token
+        from sessions
+        join users on users.user_id = sessions.user_id
+        where sessions.session_id = $1 and sessions.expires_at > now()
+        "#,
+    )
+    .bind(session_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(Error::from)
+}
+
+pub async fn delete_session(pool: &PgPool, session_id: Uuid) -> Result<(), Error> {
+    sqlx::query("delete from sessions where session_id = $1")
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Remove expired sessions, returning how many were deleted.
+pub async fn delete_expired_sessions(pool: &PgPool) -> Result<u64, Error> {
+    let result = sqlx::query("delete from sessions where expires_at <= now()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
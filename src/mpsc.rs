@@ -1,43 +1,191 @@
 use crate::error::Error;
+use jobs::{Job, JobRequest, JobRunner};
+use serde_json::Value;
 use std::fmt::{self, Debug};
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+pub mod jobs;
+pub mod postgres;
+pub mod queue;
 
-#[allow(dead_code)]
 pub struct ChannelReceiver {
     receiver: mpsc::Receiver<TxMessage>,
     next_id: u32,
+    runner: Arc<JobRunner>,
 }
 
 #[derive(Debug)]
 pub enum TxMessage {
-    RunTask { timestamp: String },
+    /// Run the registered job `name` without a payload.
+    RunTask {
+        name: String,
+        timestamp: String,
+        /// Identifies this run in its [`JobResult`](jobs::JobResult), as the
+        /// job's correlation ID.
+        run_id: String,
+    },
+    Job(JobRequest),
 }
 
 impl fmt::Display for TxMessage {
-    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        unimplemented!()
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxMessage::RunTask {
+                name,
+                timestamp,
+                run_id,
+            } => write!(f, "RunTask({} @ {} [{}])", name, timestamp, run_id),
+            TxMessage::Job(request) => write!(f, "Job({})", request),
+        }
     }
 }
 
+impl From<TxMessage> for JobRequest {
+    fn from(msg: TxMessage) -> Self {
+        match msg {
+            TxMessage::RunTask { name, run_id, .. } => JobRequest {
+                name,
+                payload: Value::Null,
+                correlation_id: Some(run_id),
+            },
+            TxMessage::Job(request) => request,
+        }
+    }
+}
+
+/// Enqueue `payload` for the job `J` on the channel consumed by
+/// [`ChannelReceiver::run`].
+pub async fn enqueue<J: Job>(
+    sender: &mpsc::Sender<TxMessage>,
+    payload: &J::Payload,
+) -> Result<(), Error> {
+    let request = JobRequest::new::<J>(payload)?;
+    sender
+        .send(TxMessage::Job(request))
+        .await
+        .map_err(|err| Error::new(format!("Job channel is closed; dropped {}", err.0)))
+}
+
 impl ChannelReceiver {
-    pub fn new(receiver: mpsc::Receiver<TxMessage>) -> Self {
+    pub fn new(receiver: mpsc::Receiver<TxMessage>, runner: JobRunner) -> Self {
         ChannelReceiver {
             receiver,
             next_id: 0,
+            runner: Arc::new(runner),
         }
     }
 
+    /// Consume messages until every sender is dropped, running up to the
+    /// runner's concurrency limit of jobs at once. Job failures are logged and
+    /// never stop the loop.
     pub async fn run(&mut self) -> Result<(), Error> {
         tracing::info!("\n\r --> run(): Blocking for next message.");
 
+        let permits = Arc::new(Semaphore::new(self.runner.concurrency()));
+        let mut tasks = JoinSet::new();
+
         while let Some(msg) = self.receiver.recv().await {
-            match msg {
-                TxMessage::RunTask { timestamp: _ } => {
-                    unimplemented!()
-                }
-            };
+            while let Some(joined) = tasks.try_join_next() {
+                log_join(joined);
+            }
+
+            let permit = permits.clone().acquire_owned().await.map_err(Error::new)?;
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+
+            tracing::debug!("Received message #{}: {}", id, msg);
+            let runner = self.runner.clone();
+            tasks.spawn(async move {
+                let _permit = permit;
+                runner.execute(id, msg.into()).await;
+            });
+        }
+
+        // Every sender is gone; let in-flight jobs finish.
+        while let Some(joined) = tasks.join_next().await {
+            log_join(joined);
         }
 
         Ok(())
     }
 }
+
+fn log_join(joined: Result<(), tokio::task::JoinError>) {
+    if let Err(err) = joined {
+        tracing::error!("Job task failed to complete: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AppConfig;
+    use async_trait::async_trait;
+    use jobs::{JobContext, JobRegistry};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Counted {
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Job for Counted {
+        const NAME: &'static str = "counted";
+        type Payload = u32;
+
+        async fn run(&self, _ctx: &JobContext, payload: u32) -> Result<Value, Error> {
+            let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+
+            if payload == 3 {
+                return Err(Error::new("three is unlucky"));
+            }
+            Ok(Value::from(payload))
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_jobs_with_bounded_concurrency() {
+        let job = Counted::default();
+        let peak = job.peak.clone();
+        let mut registry = JobRegistry::new();
+        registry.register(job);
+
+        let mut config = AppConfig::default();
+        config.jobs.concurrency = 2;
+        config.jobs.max_attempts = 1;
+        let runner = JobRunner::new(registry, Arc::new(config));
+        let mut results = runner.subscribe();
+
+        let (tx, receiver) = mpsc::channel(8);
+        let mut rx = ChannelReceiver::new(receiver, runner);
+        let consumer = tokio::spawn(async move { rx.run().await });
+
+        for n in 0..6u32 {
+            enqueue::<Counted>(&tx, &n).await.unwrap();
+        }
+        drop(tx);
+        consumer.await.unwrap().unwrap();
+
+        let mut ids = HashSet::new();
+        let mut failures = 0;
+        while let Ok(result) = results.try_recv() {
+            ids.insert(result.id);
+            if !result.is_success() {
+                failures += 1;
+            }
+        }
+
+        assert_eq!(ids.len(), 6);
+        assert_eq!(failures, 1);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,543 @@
+//! Background jobs executed by [`ChannelReceiver`](super::ChannelReceiver).
+//!
+//! A [`Job`] declares a typed payload; it is enqueued as a [`JobRequest`] with
+//! the payload serialised to JSON, and looked up by name in a [`JobRegistry`]
+//! when it is run. The [`JobRunner`] applies timeouts and retries with
+//! exponential backoff, and publishes a [`JobResult`] for every run.
+//!
+//! A job enqueued while a request is handled carries that request's
+//! correlation ID; its attempts run in a `job` span recording it, and its
+//! failures are logged with it.
+
+use crate::config::{AppConfig, JobsConfig};
+use crate::error::Error;
+use crate::server::errors::RequestContext;
+use crate::utils::{self, logger};
+use async_trait::async_trait;
+use exponential_backoff::Backoff;
+use futures::FutureExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::Instrument;
+
+#[async_trait]
+pub trait Job: Send + Sync + 'static {
+    /// Unique name the job is registered and enqueued under.
+    const NAME: &'static str;
+
+    type Payload: Serialize + DeserializeOwned + Send;
+
+    async fn run(&self, ctx: &JobContext, payload: Self::Payload) -> Result<Value, Error>;
+
+    /// Overrides the runner's default retry policy.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
+
+    /// Overrides the runner's default timeout, per attempt.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobContext {
+    pub job_id: u32,
+    /// Starts at 1.
+    pub attempt: u32,
+    /// ID of the request that enqueued the job, if any.
+    pub correlation_id: Option<String>,
+    pub config: Arc<AppConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first; `1` disables retries.
+    pub max_attempts: u32,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the retry following the `attempt`th try.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        // `Backoff::new` panics on zero retries.
+        Backoff::new(attempt.max(1), self.min_delay, self.max_delay)
+            .next(attempt.saturating_sub(1))
+            .unwrap_or(self.max_delay)
+    }
+}
+
+impl From<&JobsConfig> for RetryPolicy {
+    fn from(config: &JobsConfig) -> Self {
+        Self {
+            max_attempts: config.max_attempts.max(1),
+            min_delay: Duration::from_millis(config.backoff_min_ms),
+            max_delay: Duration::from_millis(config.backoff_max_ms),
+        }
+    }
+}
+
+/// A job as it travels through a queue: its name and JSON encoded payload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobRequest {
+    pub name: String,
+    pub payload: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl JobRequest {
+    /// Created while a request is handled, the job is tagged with its
+    /// correlation ID.
+    pub fn new<J: Job>(payload: &J::Payload) -> Result<Self, Error> {
+        Ok(Self {
+            name: J::NAME.to_string(),
+            payload: serde_json::to_value(payload)?,
+            correlation_id: RequestContext::current().map(|context| context.correlation_id.0),
+        })
+    }
+}
+
+impl fmt::Display for JobRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobOutcome {
+    Succeeded {
+        output: Value,
+    },
+    Failed {
+        error: String,
+    },
+    TimedOut {
+        after_ms: u64,
+    },
+    /// No job is registered under the requested name.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobResult {
+    pub id: u32,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub attempts: u32,
+    pub elapsed_ms: u64,
+    pub outcome: JobOutcome,
+}
+
+impl JobResult {
+    pub fn is_success(&self) -> bool {
+        matches!(self.outcome, JobOutcome::Succeeded { .. })
+    }
+}
+
+/// Object safe form of [`Job`], taking and returning JSON.
+#[async_trait]
+trait ErasedJob: Send + Sync {
+    async fn run(&self, ctx: &JobContext, payload: Value) -> Result<Value, Error>;
+    fn retry_policy(&self) -> Option<RetryPolicy>;
+    fn timeout(&self) -> Option<Duration>;
+}
+
+struct Erased<J>(J);
+
+#[async_trait]
+impl<J: Job> ErasedJob for Erased<J> {
+    async fn run(&self, ctx: &JobContext, payload: Value) -> Result<Value, Error> {
+        let payload: J::Payload = serde_json::from_value(payload)?;
+        self.0.run(ctx, payload).await
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.0.retry_policy()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.0.timeout()
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct JobRegistry {
+    jobs: HashMap<&'static str, Arc<dyn ErasedJob>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<J: Job>(&mut self, job: J) -> &mut Self {
+        self.jobs.insert(J::NAME, Arc::new(Erased(job)));
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.jobs.contains_key(name)
+    }
+}
+
+pub struct JobRunner {
+    registry: JobRegistry,
+    retry_policy: RetryPolicy,
+    timeout: Duration,
+    concurrency: usize,
+    config: Arc<AppConfig>,
+    results: broadcast::Sender<JobResult>,
+}
+
+impl JobRunner {
+    pub fn new(registry: JobRegistry, config: Arc<AppConfig>) -> Self {
+        let (results, _) = broadcast::channel(64);
+
+        Self {
+            registry,
+            retry_policy: RetryPolicy::from(&config.jobs),
+            timeout: Duration::from_secs(config.jobs.timeout),
+            concurrency: config.jobs.concurrency.max(1),
+            config,
+            results,
+        }
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Receive a [`JobResult`] for every job run from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<JobResult> {
+        self.results.subscribe()
+    }
+
+    /// Run `request` to completion, retrying failures per the job's policy.
+    /// Failures, timeouts and panics are reported in the result, never
+    /// propagated.
+    pub async fn execute(&self, id: u32, request: JobRequest) -> JobResult {
+        let started = Instant::now();
+        if !self.registry.contains(&request.name) {
+            return self.finish(started, id, request, 0, JobOutcome::Unknown);
+        }
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let outcome = self.attempt(id, &request, attempt).await;
+
+            match self.retry_delay(&request.name, &outcome, attempt) {
+                Some(delay) => {
+                    logger::log(
+                        logger::Level::Warn,
+                        logger::Color(utils::ORANGE),
+                        logger::Tag("[ JOB ]"),
+                        logger::Text(
+                            format!(
+                                "#{} {}{} attempt {}/{} failed, retrying in {:?}: {:?}",
+                                id,
+                                request.name,
+                                tag(&request.correlation_id),
+                                attempt,
+                                self.max_attempts(&request.name),
+                                delay,
+                                outcome
+                            )
+                            .as_str(),
+                        ),
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                None => return self.finish(started, id, request, attempt, outcome),
+            }
+        }
+    }
+
+    /// Run a single attempt of `request`, without retrying.
+    pub async fn attempt(&self, id: u32, request: &JobRequest, attempt: u32) -> JobOutcome {
+        let Some(job) = self.registry.jobs.get(request.name.as_str()) else {
+            return JobOutcome::Unknown;
+        };
+
+        let timeout = job.timeout().unwrap_or(self.timeout);
+        let ctx = JobContext {
+            job_id: id,
+            attempt,
+            correlation_id: request.correlation_id.clone(),
+            config: self.config.clone(),
+        };
+        let span = tracing::info_span!(
+            "job",
+            id,
+            name = %request.name,
+            attempt,
+            request_id = request.correlation_id.as_deref().unwrap_or_default(),
+        );
+        let run = AssertUnwindSafe(job.run(&ctx, request.payload.clone())).catch_unwind();
+
+        match tokio::time::timeout(timeout, run).instrument(span).await {
+            Ok(Ok(Ok(output))) => JobOutcome::Succeeded { output },
+            Ok(Ok(Err(err))) => JobOutcome::Failed {
+                error: err.to_string(),
+            },
+            Ok(Err(panic)) => JobOutcome::Failed {
+                error: format!("job panicked: {}", panic_message(panic.as_ref())),
+            },
+            Err(_) => JobOutcome::TimedOut {
+                after_ms: timeout.as_millis() as u64,
+            },
+        }
+    }
+
+    /// How long to wait before retrying a job that ended with `outcome` on its
+    /// `attempt`th try, or `None` when it should not be retried.
+    pub fn retry_delay(&self, name: &str, outcome: &JobOutcome, attempt: u32) -> Option<Duration> {
+        if !matches!(
+            outcome,
+            JobOutcome::Failed { .. } | JobOutcome::TimedOut { .. }
+        ) {
+            return None;
+        }
+
+        let policy = self.policy(name);
+        (attempt < policy.max_attempts).then(|| policy.delay(attempt))
+    }
+
+    pub fn max_attempts(&self, name: &str) -> u32 {
+        self.policy(name).max_attempts
+    }
+
+    pub fn policy(&self, name: &str) -> RetryPolicy {
+        self.registry
+            .jobs
+            .get(name)
+            .and_then(|job| job.retry_policy())
+            .unwrap_or(self.retry_policy)
+    }
+
+    /// Log and publish the final result of a job.
+    pub(crate) fn finish(
+        &self,
+        started: Instant,
+        id: u32,
+        request: JobRequest,
+        attempts: u32,
+        outcome: JobOutcome,
+    ) -> JobResult {
+        let result = JobResult {
+            id,
+            name: request.name,
+            correlation_id: request.correlation_id,
+            attempts,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            outcome,
+        };
+
+        let text = format!(
+            "#{} {}{} after {} attempt(s) in {}ms: {}",
+            result.id,
+            result.name,
+            tag(&result.correlation_id),
+            result.attempts,
+            result.elapsed_ms,
+            serde_json::to_string(&result.outcome).unwrap_or_default()
+        );
+        if result.is_success() {
+            logger::log(
+                logger::Level::Info,
+                logger::Color(utils::GREEN),
+                logger::Tag("[ JOB ]"),
+                logger::Text(text.as_str()),
+            );
+        } else {
+            logger::log(
+                logger::Level::Error,
+                logger::Color(utils::RED),
+                logger::Tag("[ JOB ]"),
+                logger::Text(text.as_str()),
+            );
+        }
+
+        // No subscribers is fine.
+        let _ = self.results.send(result.clone());
+
+        result
+    }
+}
+
+/// ` [<correlation id>]`, for log lines about a job a request enqueued.
+pub(crate) fn tag(correlation_id: &Option<String>) -> String {
+    match correlation_id {
+        Some(id) => format!(" [{}]", id),
+        None => String::new(),
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    struct Flaky {
+        calls: Arc<AtomicU32>,
+        fail_times: u32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Greeting {
+        name: String,
+    }
+
+    #[async_trait]
+    impl Job for Flaky {
+        const NAME: &'static str = "flaky";
+        type Payload = Greeting;
+
+        async fn run(&self, _ctx: &JobContext, payload: Greeting) -> Result<Value, Error> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            if call <= self.fail_times {
+                return Err(Error::new(format!("call {} failed", call)));
+            }
+
+            Ok(Value::String(format!("hello {}", payload.name)))
+        }
+    }
+
+    struct Sleepy;
+
+    #[async_trait]
+    impl Job for Sleepy {
+        const NAME: &'static str = "sleepy";
+        type Payload = ();
+
+        async fn run(&self, _ctx: &JobContext, _payload: ()) -> Result<Value, Error> {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(Value::Null)
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+    }
+
+    struct Panicky;
+
+    #[async_trait]
+    impl Job for Panicky {
+        const NAME: &'static str = "panicky";
+        type Payload = ();
+
+        async fn run(&self, _ctx: &JobContext, _payload: ()) -> Result<Value, Error> {
+            panic!("boom")
+        }
+    }
+
+    fn runner(registry: JobRegistry, max_attempts: u32) -> JobRunner {
+        let mut config = AppConfig::default();
+        config.jobs.max_attempts = max_attempts;
+        config.jobs.backoff_min_ms = 1;
+        config.jobs.backoff_max_ms = 5;
+
+        JobRunner::new(registry, Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut registry = JobRegistry::new();
+        registry.register(Flaky {
+            calls: calls.clone(),
+            fail_times: 2,
+        });
+
+        let request = JobRequest::new::<Flaky>(&Greeting {
+            name: "Nosferatu".to_string(),
+        })
+        .unwrap();
+        let result = runner(registry, 3).execute(1, request).await;
+
+        assert_eq!(result.attempts, 3);
+        assert_eq!(
+            result.outcome,
+            JobOutcome::Succeeded {
+                output: Value::String("hello Nosferatu".to_string())
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut registry = JobRegistry::new();
+        registry.register(Flaky {
+            calls: calls.clone(),
+            fail_times: 10,
+        });
+
+        let request = JobRequest::new::<Flaky>(&Greeting {
+            name: "Orlok".to_string(),
+        })
+        .unwrap();
+        let result = runner(registry, 2).execute(1, request).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            result.outcome,
+            JobOutcome::Failed {
+                error: "call 2 failed".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn timeouts_panics_and_unknown_jobs_are_reported() {
+        let mut registry = JobRegistry::new();
+        registry.register(Sleepy).register(Panicky);
+        let runner = runner(registry, 1);
+
+        let result = runner
+            .execute(1, JobRequest::new::<Sleepy>(&()).unwrap())
+            .await;
+        assert_eq!(result.outcome, JobOutcome::TimedOut { after_ms: 10 });
+
+        let result = runner
+            .execute(2, JobRequest::new::<Panicky>(&()).unwrap())
+            .await;
+        assert_eq!(
+            result.outcome,
+            JobOutcome::Failed {
+                error: "job panicked: boom".to_string()
+            }
+        );
+
+        let request = JobRequest {
+            name: "missing".to_string(),
+            payload: Value::Null,
+            correlation_id: None,
+        };
+        assert_eq!(
+            runner.execute(3, request).await.outcome,
+            JobOutcome::Unknown
+        );
+    }
+}
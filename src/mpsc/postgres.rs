@@ -0,0 +1,399 @@
+//! Durable job queue stored in the Postgres `jobs` table.
+//!
+//! Workers claim due jobs with `FOR UPDATE SKIP LOCKED`, so any number of them
+//! can poll the same table. Failed attempts are rescheduled with backoff until
+//! `max_attempts` is reached, after which the job is left in the `dead` state.
+//! A job still locked after `lock_timeout` has lost its worker; it counts as a
+//! failed attempt too.
+
+use super::jobs::{self, JobOutcome, JobRequest, JobRunner};
+use super::queue::{EnqueueOptions, Enqueued, JobQueue};
+use crate::config::JobsConfig;
+use crate::error::Error;
+use crate::lifecycle::Shutdown;
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct PgJobQueue {
+    pool: PgPool,
+    max_attempts: u32,
+}
+
+impl PgJobQueue {
+    pub fn new(pool: PgPool, config: &JobsConfig) -> Self {
+        Self {
+            pool,
+            max_attempts: config.max_attempts.max(1),
+        }
+    }
+}
+
+#[async_trait]
+impl JobQueue for PgJobQueue {
+    async fn push(&self, request: JobRequest, options: EnqueueOptions) -> Result<Enqueued, Error> {
+        let job_id: Option<Uuid> = sqlx::query_scalar(
+            r#"
+            insert into jobs (name, payload, unique_key, max_attempts, scheduled_at, correlation_id)
+            values ($1, $2, $3, $4, coalesce($5, now()), $6)
+            on conflict (unique_key)
+                where unique_key is not null and status in ('pending', 'running')
+                do nothing
+            returning job_id
+            "#,
+        )
+        .bind(&request.name)
+        .bind(&request.payload)
+        .bind(&options.unique_key)
+        .bind(options.max_attempts.unwrap_or(self.max_attempts) as i32)
+        .bind(options.run_at)
+        .bind(&request.correlation_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(match job_id {
+            Some(_) => Enqueued::Queued,
+            None => Enqueued::Duplicate,
+        })
+    }
+
+    /// Pending jobs, including those scheduled for later.
+    async fn depth(&self) -> Result<u64, Error> {
+        let pending: i64 = sqlx::query_scalar("select count(*) from jobs where status = 'pending'")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(Error::new)?;
+
+        Ok(pending as u64)
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ClaimedJob {
+    job_id: Uuid,
+    name: String,
+    payload: Value,
+    correlation_id: Option<String>,
+    attempts: i32,
+    max_attempts: i32,
+}
+
+pub struct PgJobWorker {
+    pool: PgPool,
+    runner: Arc<JobRunner>,
+    worker_id: String,
+    poll_interval: Duration,
+    lock_timeout: Duration,
+    next_id: u32,
+}
+
+impl PgJobWorker {
+    pub fn new(pool: PgPool, runner: JobRunner, config: &JobsConfig) -> Self {
+        Self {
+            pool,
+            runner: Arc::new(runner),
+            worker_id: format!("{}-{}", std::process::id(), Uuid::new_v4()),
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            lock_timeout: Duration::from_secs(config.lock_timeout),
+            next_id: 0,
+        }
+    }
+
+    /// Poll for due jobs until `shutdown`, running up to the runner's
+    /// concurrency limit at once, then wait for running jobs to finish.
+    /// Database errors are logged and retried after the poll interval.
+    pub async fn run(&mut self, mut shutdown: Shutdown) -> Result<(), Error> {
+        tracing::info!("Postgres job worker {} started", self.worker_id);
+        let concurrency = self.runner.concurrency();
+        let permits = Arc::new(Semaphore::new(concurrency));
+
+        while !shutdown.is_triggered() {
+            if let Err(err) = self.requeue_stale().await {
+                tracing::error!("Unable to requeue stale jobs: {}", err);
+            }
+
+            let free = permits.available_permits();
+            let claimed = if free == 0 {
+                Vec::new()
+            } else {
+                self.claim(free as i64).await.unwrap_or_else(|err| {
+                    tracing::error!("Unable to claim jobs: {}", err);
+                    Vec::new()
+                })
+            };
+
+            if claimed.is_empty() {
+                tokio::select! {
+                    _ = shutdown.wait() => {}
+                    _ = tokio::time::sleep(self.poll_interval) => {}
+                }
+                continue;
+            }
+
+            for job in claimed {
+                let permit = permits.clone().acquire_owned().await.map_err(Error::new)?;
+                let id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+
+                let pool = self.pool.clone();
+                let runner = self.runner.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    if let Err(err) = process(&pool, &runner, id, job).await {
+                        tracing::error!("Unable to record job result: {}", err);
+                    }
+                });
+            }
+        }
+
+        // Every permit is back once the running jobs are done.
+        let _ = permits.acquire_many(concurrency as u32).await;
+        tracing::info!("Postgres job worker {} stopped", self.worker_id);
+
+        Ok(())
+    }
+
+    async fn claim(&self, limit: i64) -> Result<Vec<ClaimedJob>, Error> {
+        sqlx::query_as(
+            r#"
+            update jobs
+            set status = 'running', attempts = attempts + 1, locked_at = now(), locked_by = $1
+            where job_id in (
+                select job_id from jobs
+                where status = 'pending' and scheduled_at <= now()
+                order by scheduled_at
+                limit $2
+                for update skip locked
+            )
+            returning job_id, name, payload, correlation_id, attempts, max_attempts
+            "#,
+        )
+        .bind(&self.worker_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::new)
+    }
+
+    /// Return jobs locked by workers that died mid-run to the queue, or mark
+    /// them `dead` once they have used up their attempts, since a job that
+    /// kills its worker would otherwise be retried forever.
+    async fn requeue_stale(&self) -> Result<u64, Error> {
+        let lock_timeout = self.lock_timeout.as_secs_f64();
+        let mut tx = self.pool.begin().await.map_err(Error::new)?;
+        let requeued = sqlx::query(
+            r#"
+            update jobs
+            set status = 'pending', locked_at = null, locked_by = null
+            where status = 'running' and locked_at < now() - make_interval(secs => $1)
+                and attempts < max_attempts
+            "#,
+        )
+        .bind(lock_timeout)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        let dead = sqlx::query(
+            r#"
+            update jobs
+            set status = 'dead', locked_at = null, locked_by = null, last_error = $2
+            where status = 'running' and locked_at < now() - make_interval(secs => $1)
+            "#,
+        )
+        .bind(lock_timeout)
+        .bind(format!(
+            "lock timed out after {}s on the last attempt",
+            self.lock_timeout.as_secs()
+        ))
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        tx.commit().await.map_err(Error::new)?;
+
+        if dead.rows_affected() > 0 {
+            tracing::warn!(
+                "Marked {} stale jobs dead after their last attempt",
+                dead.rows_affected()
+            );
+        }
+
+        Ok(requeued.rows_affected())
+    }
+}
+
+async fn process(pool: &PgPool, runner: &JobRunner, id: u32, job: ClaimedJob) -> Result<(), Error> {
+    let started = Instant::now();
+    let request = JobRequest {
+        name: job.name,
+        payload: job.payload,
+        correlation_id: job.correlation_id,
+    };
+    let attempt = job.attempts.max(1) as u32;
+    let outcome = runner.attempt(id, &request, attempt).await;
+    tracing::debug!("Job #{} is row {}", id, job.job_id);
+
+    match outcome {
+        JobOutcome::Succeeded { .. } => {
+            sqlx::query(
+                r#"
+                update jobs
+                set status = 'succeeded', locked_at = null, locked_by = null, last_error = null
+                where job_id = $1
+                "#,
+            )
+            .bind(job.job_id)
+            .execute(pool)
+            .await
+            .map_err(Error::new)?;
+        }
+        JobOutcome::Failed { .. } | JobOutcome::TimedOut { .. }
+            if attempt < job.max_attempts as u32 =>
+        {
+            let delay = runner.policy(&request.name).delay(attempt);
+            sqlx::query(
+                r#"
+                update jobs
+                set status = 'pending', locked_at = null, locked_by = null, last_error = $2,
+                    scheduled_at = now() + make_interval(secs => $3)
+                where job_id = $1
+                "#,
+            )
+            .bind(job.job_id)
+            .bind(describe(&outcome))
+            .bind(delay.as_secs_f64())
+            .execute(pool)
+            .await
+            .map_err(Error::new)?;
+
+            tracing::warn!(
+                "Job #{} {}{} attempt {}/{} failed, retrying in {:?}",
+                id,
+                request.name,
+                jobs::tag(&request.correlation_id),
+                attempt,
+                job.max_attempts,
+                delay
+            );
+            return Ok(());
+        }
+        _ => {
+            sqlx::query(
+                r#"
+                update jobs
+                set status = 'dead', locked_at = null, locked_by = null, last_error = $2
+                where job_id = $1
+                "#,
+            )
+            .bind(job.job_id)
+            .bind(describe(&outcome))
+            .execute(pool)
+            .await
+            .map_err(Error::new)?;
+        }
+    }
+
+    runner.finish(started, id, request, attempt, outcome);
+
+    Ok(())
+}
+
+fn describe(outcome: &JobOutcome) -> String {
+    match outcome {
+        JobOutcome::Succeeded { .. } => String::default(),
+        JobOutcome::Failed { error } => error.clone(),
+        JobOutcome::TimedOut { after_ms } => format!("timed out after {}ms", after_ms),
+        JobOutcome::Unknown => "no job is registered under this name".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::mpsc::jobs::JobRegistry;
+    use chrono::DateTime;
+
+    /// The database named by `DATABASE_URL`, migrated; `None` skips the test.
+    async fn database() -> Option<PgPool> {
+        let url = std::env::var("DATABASE_URL").ok()?;
+        Some(
+            PgPool::connect(&url)
+                .await
+                .expect("DATABASE_URL is reachable"),
+        )
+    }
+
+    #[tokio::test]
+    async fn requeues_stale_jobs_until_their_last_attempt() {
+        let Some(pool) = database().await else {
+            return;
+        };
+        let config = JobsConfig::default();
+        let queue = PgJobQueue::new(pool.clone(), &config);
+        let worker = PgJobWorker::new(
+            pool.clone(),
+            JobRunner::new(JobRegistry::new(), Arc::new(AppConfig::default())),
+            &config,
+        );
+
+        // A name of its own, and due before any other job, so only these are claimed.
+        let name = format!("crashes_its_worker_{}", Uuid::new_v4());
+        for max_attempts in [1, 2] {
+            let request = JobRequest {
+                name: name.clone(),
+                payload: Value::from(max_attempts),
+                correlation_id: None,
+            };
+            let options = EnqueueOptions {
+                run_at: Some(DateTime::UNIX_EPOCH),
+                max_attempts: Some(max_attempts),
+                ..Default::default()
+            };
+            assert_eq!(
+                queue.push(request, options).await.unwrap(),
+                Enqueued::Queued
+            );
+        }
+
+        let claimed = worker.claim(2).await.unwrap();
+        assert_eq!(claimed.len(), 2);
+        assert!(claimed
+            .iter()
+            .all(|job| job.name == name && job.attempts == 1));
+
+        // The worker dies: nothing records a result before the lock times out.
+        sqlx::query("update jobs set locked_at = now() - interval '1 day' where name = $1")
+            .bind(&name)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(worker.requeue_stale().await.unwrap(), 1);
+
+        let jobs: Vec<(i32, String, Option<String>)> = sqlx::query_as(
+            "select max_attempts, status, last_error from jobs where name = $1 order by max_attempts",
+        )
+        .bind(&name)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        sqlx::query("delete from jobs where name = $1")
+            .bind(&name)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(jobs[0].1, "dead");
+        assert!(jobs[0].2.as_deref().unwrap().contains("lock timed out"));
+        assert_eq!(
+            (jobs[1].1.as_str(), jobs[1].2.as_deref()),
+            ("pending", None)
+        );
+    }
+}
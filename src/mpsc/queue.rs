@@ -0,0 +1,157 @@
+//! A single enqueue API over the in-memory channel and the durable Postgres
+//! queue, so handlers do not care which backend is configured.
+
+use super::jobs::{Job, JobRequest};
+use super::TxMessage;
+use crate::error::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+pub type SharedJobQueue = Arc<dyn JobQueue>;
+
+#[derive(Debug, Clone, Default)]
+pub struct EnqueueOptions {
+    /// Do not run before this time; `None` runs as soon as possible.
+    pub run_at: Option<DateTime<Utc>>,
+    /// Skip enqueueing while a job with the same key is pending or running.
+    pub unique_key: Option<String>,
+    /// Overrides the configured maximum number of attempts.
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Enqueued {
+    Queued,
+    /// An equivalent job is already pending or running.
+    Duplicate,
+}
+
+#[async_trait]
+pub trait JobQueue: Send + Sync {
+    async fn push(&self, request: JobRequest, options: EnqueueOptions) -> Result<Enqueued, Error>;
+
+    /// Jobs waiting to be run.
+    async fn depth(&self) -> Result<u64, Error>;
+}
+
+/// Typed helpers for any [`JobQueue`], including `dyn JobQueue`.
+#[async_trait]
+pub trait JobQueueExt: JobQueue {
+    async fn enqueue<J: Job>(&self, payload: &J::Payload) -> Result<Enqueued, Error>
+    where
+        J::Payload: Sync,
+    {
+        self.enqueue_with::<J>(payload, EnqueueOptions::default())
+            .await
+    }
+
+    async fn enqueue_with<J: Job>(
+        &self,
+        payload: &J::Payload,
+        options: EnqueueOptions,
+    ) -> Result<Enqueued, Error>
+    where
+        J::Payload: Sync,
+    {
+        self.push(JobRequest::new::<J>(payload)?, options).await
+    }
+}
+
+impl<Q: JobQueue + ?Sized> JobQueueExt for Q {}
+
+/// Enqueues onto the channel consumed by
+/// [`ChannelReceiver`](super::ChannelReceiver). Jobs are lost on restart, and
+/// `unique_key` and `max_attempts` are ignored.
+#[derive(Debug, Clone)]
+pub struct MemoryQueue {
+    sender: mpsc::Sender<TxMessage>,
+}
+
+impl MemoryQueue {
+    pub fn new(sender: mpsc::Sender<TxMessage>) -> Self {
+        Self { sender }
+    }
+}
+
+#[async_trait]
+impl JobQueue for MemoryQueue {
+    async fn push(&self, request: JobRequest, options: EnqueueOptions) -> Result<Enqueued, Error> {
+        let delay = options
+            .run_at
+            .and_then(|run_at| (run_at - Utc::now()).to_std().ok());
+
+        match delay {
+            Some(delay) => {
+                let sender = self.sender.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    if let Err(err) = sender.send(TxMessage::Job(request)).await {
+                        tracing::error!("Job channel is closed; dropped delayed {}", err.0);
+                    }
+                });
+            }
+            None => {
+                self.sender
+                    .send(TxMessage::Job(request))
+                    .await
+                    .map_err(|err| {
+                        Error::new(format!("Job channel is closed; dropped {}", err.0))
+                    })?;
+            }
+        }
+
+        Ok(Enqueued::Queued)
+    }
+
+    /// Jobs in the channel; delayed jobs are not counted until they are due.
+    async fn depth(&self) -> Result<u64, Error> {
+        Ok((self.sender.max_capacity() - self.sender.capacity()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mpsc::jobs::JobContext;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    struct Noop;
+
+    #[async_trait]
+    impl Job for Noop {
+        const NAME: &'static str = "noop";
+        type Payload = String;
+
+        async fn run(&self, _ctx: &JobContext, _payload: String) -> Result<Value, Error> {
+            Ok(Value::Null)
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_queue_delays_scheduled_jobs() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let queue: SharedJobQueue = Arc::new(MemoryQueue::new(tx));
+
+        let options = EnqueueOptions {
+            run_at: Some(Utc::now() + chrono::Duration::milliseconds(50)),
+            ..Default::default()
+        };
+        queue
+            .enqueue_with::<Noop>(&"later".to_string(), options)
+            .await
+            .unwrap();
+        queue.enqueue::<Noop>(&"now".to_string()).await.unwrap();
+
+        let first = rx.recv().await.unwrap();
+        assert!(matches!(first, TxMessage::Job(ref r) if r.payload == "now"));
+
+        let second = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(second, TxMessage::Job(ref r) if r.payload == "later"));
+    }
+}
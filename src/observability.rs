@@ -0,0 +1,251 @@
+//! Logging, traces and metrics.
+//!
+//! [`init`] installs the `tracing` subscriber: log lines are written pretty or
+//! as JSON, filtered by `RUST_LOG` style directives. When an OTLP collector is
+//! configured, spans are exported to it as traces and the instruments in
+//! [`metrics`] are exported every `metrics_interval_ms`; without one, recording
+//! metrics costs next to nothing.
+
+use crate::config::layers::FromConfigValue;
+use crate::error::Error;
+use opentelemetry::metrics::{Meter, MeterProvider as _};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use std::time::Duration;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+pub mod metrics;
+
+#[cfg(test)]
+mod collector;
+
+/// Instrumentation scope of the app's own spans and metrics.
+pub const SCOPE: &str = "nosferatu";
+
+#[derive(Debug, Clone)]
+pub struct ObservabilityConfig {
+    pub log_format: LogFormat,
+    /// `RUST_LOG` style directives, e.g. `info,sqlx=warn`.
+    pub log_filter: String,
+    /// Base URL of an OTLP/HTTP collector, e.g. `http://localhost:4318`;
+    /// nothing is exported when unset.
+    pub otlp_endpoint: Option<String>,
+    /// The `service.name` traces and metrics are reported under.
+    pub service_name: String,
+    pub metrics_interval_ms: u64,
+}
+
+impl Default for ObservabilityConfig {
+    fn default() -> Self {
+        Self {
+            log_format: LogFormat::Pretty,
+            log_filter: "info".to_string(),
+            otlp_endpoint: None,
+            service_name: SCOPE.to_string(),
+            metrics_interval_ms: 60_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, with the file and line they were logged at.
+    Pretty,
+    /// One JSON object per line, for log shippers.
+    Json,
+}
+
+impl FromConfigValue for LogFormat {
+    fn from_text(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("expected \"pretty\" or \"json\", got {:?}", other)),
+        }
+    }
+}
+
+/// The OTLP pipelines; [`Telemetry::shutdown`] flushes what they still hold.
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    tracer_provider: Option<TracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl Telemetry {
+    /// Set up exporters to `config.otlp_endpoint`, if any. Must be called
+    /// within a Tokio runtime, which runs the exports.
+    pub fn new(config: &ObservabilityConfig) -> Result<Self, Error> {
+        let Some(endpoint) = &config.otlp_endpoint else {
+            return Ok(Self::default());
+        };
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+
+        let spans = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .build()
+            .map_err(Error::new)?;
+        let tracer_provider = TracerProvider::builder()
+            .with_batch_exporter(spans, runtime::Tokio)
+            .with_resource(resource.clone())
+            .build();
+
+        let metrics = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .build()
+            .map_err(Error::new)?;
+        let reader = PeriodicReader::builder(metrics, runtime::Tokio)
+            .with_interval(Duration::from_millis(config.metrics_interval_ms.max(1)))
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+
+        Ok(Self {
+            tracer_provider: Some(tracer_provider),
+            meter_provider: Some(meter_provider),
+        })
+    }
+
+    pub fn is_exporting(&self) -> bool {
+        self.tracer_provider.is_some()
+    }
+
+    /// Exports `tracing` spans as traces, when a collector is configured.
+    pub fn tracing_layer<S>(&self) -> Option<OpenTelemetryLayer<S, Tracer>>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        let tracer = self.tracer_provider.as_ref()?.tracer(SCOPE);
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
+    /// Where to create instruments; a no-op meter when nothing is exported.
+    pub fn meter(&self) -> Meter {
+        match &self.meter_provider {
+            Some(provider) => provider.meter(SCOPE),
+            None => global::meter(SCOPE),
+        }
+    }
+
+    /// Export finished spans and current metrics now.
+    pub fn force_flush(&self) {
+        if let Some(provider) = &self.tracer_provider {
+            for result in provider.force_flush() {
+                if let Err(err) = result {
+                    tracing::warn!("Unable to export traces: {}", err);
+                }
+            }
+        }
+        if let Some(provider) = &self.meter_provider {
+            if let Err(err) = provider.force_flush() {
+                tracing::warn!("Unable to export metrics: {}", err);
+            }
+        }
+    }
+
+    /// Flush and stop the exporters.
+    pub fn shutdown(self) {
+        if let Some(provider) = self.tracer_provider {
+            if let Err(err) = provider.shutdown() {
+                tracing::warn!("Unable to shut down trace export: {}", err);
+            }
+        }
+        if let Some(provider) = self.meter_provider {
+            if let Err(err) = provider.shutdown() {
+                tracing::warn!("Unable to shut down metrics export: {}", err);
+            }
+        }
+    }
+}
+
+/// Install the global `tracing` subscriber and meter provider.
+pub fn init(config: &ObservabilityConfig) -> Result<Telemetry, Error> {
+    let telemetry = Telemetry::new(config)?;
+    let filter = EnvFilter::try_new(&config.log_filter).map_err(Error::new)?;
+    let output = match config.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .with_file(true)
+            .with_line_number(true)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    Registry::default()
+        .with(telemetry.tracing_layer())
+        .with(output)
+        .with(filter)
+        .try_init()
+        .map_err(Error::new)?;
+    if let Some(provider) = &telemetry.meter_provider {
+        global::set_meter_provider(provider.clone());
+    }
+
+    Ok(telemetry)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use collector::MockCollector;
+    use opentelemetry_proto::tonic::common::v1::any_value::Value;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_spans_to_the_collector() {
+        let collector = MockCollector::start().await;
+        let config = ObservabilityConfig {
+            otlp_endpoint: Some(collector.endpoint()),
+            service_name: "nosferatu-test".to_string(),
+            ..ObservabilityConfig::default()
+        };
+        let telemetry = Telemetry::new(&config).unwrap();
+
+        let subscriber = Registry::default().with(telemetry.tracing_layer());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", request_id = "edge-42");
+            span.in_scope(|| tracing::info!("handled"));
+        });
+        telemetry.force_flush();
+
+        let traces = collector.traces();
+        let resource = traces[0].resource_spans[0].resource.as_ref().unwrap();
+        assert!(resource.attributes.iter().any(|attribute| {
+            attribute.key == "service.name"
+                && attribute.value.as_ref().unwrap().value
+                    == Some(Value::StringValue("nosferatu-test".to_string()))
+        }));
+
+        let span = &traces[0].resource_spans[0].scope_spans[0].spans[0];
+        assert_eq!(span.name, "request");
+        assert!(span.attributes.iter().any(|attribute| {
+            attribute.key == "request_id"
+                && attribute.value.as_ref().unwrap().value
+                    == Some(Value::StringValue("edge-42".to_string()))
+        }));
+        assert_eq!(span.events[0].name, "handled");
+
+        telemetry.shutdown();
+    }
+
+    #[test]
+    fn nothing_is_exported_without_an_endpoint() {
+        let telemetry = Telemetry::new(&ObservabilityConfig::default()).unwrap();
+        assert!(!telemetry.is_exporting());
+        assert!(telemetry.tracing_layer::<Registry>().is_none());
+    }
+}
@@ -0,0 +1,91 @@
+//! An in-process OTLP/HTTP collector keeping every export it receives.
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::Router;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::metrics::v1::{metric, Metric, NumberDataPoint};
+use prost::Message;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Received {
+    traces: Vec<ExportTraceServiceRequest>,
+    metrics: Vec<ExportMetricsServiceRequest>,
+}
+
+pub struct MockCollector {
+    addr: SocketAddr,
+    received: Arc<Mutex<Received>>,
+}
+
+impl MockCollector {
+    pub async fn start() -> Self {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let app = Router::new()
+            .route("/v1/traces", post(traces))
+            .route("/v1/metrics", post(metrics))
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Self { addr, received }
+    }
+
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn traces(&self) -> Vec<ExportTraceServiceRequest> {
+        self.received.lock().unwrap().traces.clone()
+    }
+
+    /// Every exported metric named `name`, latest export last.
+    pub fn metrics(&self, name: &str) -> Vec<Metric> {
+        self.received
+            .lock()
+            .unwrap()
+            .metrics
+            .iter()
+            .flat_map(|request| &request.resource_metrics)
+            .flat_map(|resource| &resource.scope_metrics)
+            .flat_map(|scope| &scope.metrics)
+            .filter(|metric| metric.name == name)
+            .cloned()
+            .collect()
+    }
+
+    /// The latest gauge, counter or up-down counter points named `name`.
+    pub fn points(&self, name: &str) -> Vec<NumberDataPoint> {
+        match self.metrics(name).pop().and_then(|metric| metric.data) {
+            Some(metric::Data::Gauge(gauge)) => gauge.data_points,
+            Some(metric::Data::Sum(sum)) => sum.data_points,
+            _ => Vec::new(),
+        }
+    }
+}
+
+async fn traces(State(received): State<Arc<Mutex<Received>>>, body: Bytes) -> StatusCode {
+    match ExportTraceServiceRequest::decode(body) {
+        Ok(request) => {
+            received.lock().unwrap().traces.push(request);
+            StatusCode::OK
+        }
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+async fn metrics(State(received): State<Arc<Mutex<Received>>>, body: Bytes) -> StatusCode {
+    match ExportMetricsServiceRequest::decode(body) {
+        Ok(request) => {
+            received.lock().unwrap().metrics.push(request);
+            StatusCode::OK
+        }
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
@@ -0,0 +1,252 @@
+//! Metrics for the HTTP server, the Postgres pool and the job queue, named
+//! after the OpenTelemetry semantic conventions where there is one.
+
+use crate::lifecycle::Shutdown;
+use crate::mpsc::queue::SharedJobQueue;
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::KeyValue;
+use sqlx::PgPool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Bucket bounds of the request duration histogram, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+pub struct HttpMetrics {
+    duration: Histogram<f64>,
+    responses: Counter<u64>,
+}
+
+impl HttpMetrics {
+    pub fn new(meter: &Meter) -> Self {
+        Self {
+            duration: meter
+                .f64_histogram("http.server.request.duration")
+                .with_description("Duration of HTTP server requests.")
+                .with_unit("s")
+                .with_boundaries(DURATION_BUCKETS.to_vec())
+                .build(),
+            responses: meter
+                .u64_counter("http.server.responses")
+                .with_description("HTTP responses sent, by status code.")
+                .build(),
+        }
+    }
+}
+
+/// Record the duration and status of every response.
+pub async fn record(
+    State(metrics): State<Arc<HttpMetrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = match request.method().as_str() {
+        method @ ("GET" | "HEAD" | "POST" | "PUT" | "PATCH" | "DELETE" | "OPTIONS" | "CONNECT"
+        | "TRACE") => method.to_string(),
+        // Any other method would make a series of its own.
+        _ => "_OTHER".to_string(),
+    };
+    // The route pattern rather than the path, to keep the series few.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(request).await;
+
+    let status = i64::from(response.status().as_u16());
+    let attributes = [
+        KeyValue::new("http.request.method", method),
+        KeyValue::new("http.route", route),
+        KeyValue::new("http.response.status_code", status),
+    ];
+    metrics
+        .duration
+        .record(started.elapsed().as_secs_f64(), &attributes);
+    metrics.responses.add(1, &attributes);
+
+    response
+}
+
+/// Report the size of `pool` whenever metrics are collected.
+pub fn observe_pool(meter: &Meter, pool: PgPool) {
+    let max = u64::from(pool.options().get_max_connections());
+    meter
+        .u64_observable_gauge("db.client.connection.count")
+        .with_description("Connections in the Postgres pool, by state.")
+        .with_callback(move |gauge| {
+            let (size, idle) = (u64::from(pool.size()), pool.num_idle() as u64);
+            gauge.observe(idle, &[KeyValue::new("db.client.connection.state", "idle")]);
+            gauge.observe(
+                size.saturating_sub(idle),
+                &[KeyValue::new("db.client.connection.state", "used")],
+            );
+        })
+        .build();
+    meter
+        .u64_observable_gauge("db.client.connection.max")
+        .with_description("Connections the Postgres pool may open.")
+        .with_callback(move |gauge| gauge.observe(max, &[]))
+        .build();
+}
+
+/// Poll the number of jobs waiting in `queue` every `interval` until
+/// `shutdown`, for the `jobs.queue.depth` gauge.
+pub async fn watch_queue(
+    meter: Meter,
+    queue: SharedJobQueue,
+    interval: Duration,
+    mut shutdown: Shutdown,
+) {
+    let depth = Arc::new(AtomicU64::new(0));
+    let observed = depth.clone();
+    meter
+        .u64_observable_gauge("jobs.queue.depth")
+        .with_description("Jobs waiting to run.")
+        .with_callback(move |gauge| gauge.observe(observed.load(Ordering::Relaxed), &[]))
+        .build();
+
+    while !shutdown.is_triggered() {
+        match queue.depth().await {
+            Ok(jobs) => depth.store(jobs, Ordering::Relaxed),
+            Err(err) => tracing::warn!("Unable to count queued jobs: {}", err),
+        }
+
+        tokio::select! {
+            _ = shutdown.wait() => {}
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lifecycle;
+    use crate::mpsc::jobs::JobRequest;
+    use crate::mpsc::queue::{EnqueueOptions, MemoryQueue};
+    use crate::observability::collector::MockCollector;
+    use crate::observability::{ObservabilityConfig, Telemetry};
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Router;
+    use opentelemetry_proto::tonic::common::v1::any_value;
+    use opentelemetry_proto::tonic::metrics::v1::{metric, number_data_point};
+    use serde_json::Value;
+    use sqlx::postgres::PgPoolOptions;
+    use tower::ServiceExt;
+
+    fn attribute(
+        attributes: &[opentelemetry_proto::tonic::common::v1::KeyValue],
+        key: &str,
+    ) -> String {
+        let value = attributes
+            .iter()
+            .find(|attribute| attribute.key == key)
+            .and_then(|attribute| attribute.value.clone()?.value);
+        match value {
+            Some(any_value::Value::StringValue(text)) => text,
+            Some(any_value::Value::IntValue(number)) => number.to_string(),
+            other => panic!("{} is {:?}", key, other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_http_pool_and_queue_metrics() {
+        let collector = MockCollector::start().await;
+        let config = ObservabilityConfig {
+            otlp_endpoint: Some(collector.endpoint()),
+            ..ObservabilityConfig::default()
+        };
+        let telemetry = Telemetry::new(&config).unwrap();
+        let meter = telemetry.meter();
+
+        let app = Router::new()
+            .route("/users/:id", get(|| async { "Orlok" }))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(HttpMetrics::new(&meter)),
+                record,
+            ));
+        for uri in ["/users/1", "/users/2", "/missing"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let pool = PgPoolOptions::new()
+            .max_connections(7)
+            .connect_lazy("postgres://localhost/nosferatu")
+            .unwrap();
+        observe_pool(&meter, pool);
+
+        let (tx, _rx) = tokio::sync::mpsc::channel(4);
+        let queue: SharedJobQueue = Arc::new(MemoryQueue::new(tx));
+        let request = JobRequest {
+            name: "noop".to_string(),
+            payload: Value::Null,
+            correlation_id: None,
+        };
+        queue
+            .push(request, EnqueueOptions::default())
+            .await
+            .unwrap();
+        let (shutdown, stopping) = lifecycle::shutdown_channel();
+        let watcher = tokio::spawn(watch_queue(
+            meter.clone(),
+            queue,
+            Duration::from_secs(60),
+            stopping,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.trigger();
+        watcher.await.unwrap();
+        telemetry.force_flush();
+
+        let Some(metric::Data::Histogram(histogram)) = collector
+            .metrics("http.server.request.duration")
+            .pop()
+            .and_then(|metric| metric.data)
+        else {
+            panic!("no request duration histogram");
+        };
+        let users = histogram
+            .data_points
+            .iter()
+            .find(|point| attribute(&point.attributes, "http.route") == "/users/:id")
+            .unwrap();
+        assert_eq!(users.count, 2);
+        assert_eq!(attribute(&users.attributes, "http.request.method"), "GET");
+        assert_eq!(
+            attribute(&users.attributes, "http.response.status_code"),
+            "200"
+        );
+
+        let responses = collector.points("http.server.responses");
+        let missing = responses
+            .iter()
+            .find(|point| attribute(&point.attributes, "http.route") == "unmatched")
+            .unwrap();
+        assert_eq!(
+            attribute(&missing.attributes, "http.response.status_code"),
+            StatusCode::NOT_FOUND.as_u16().to_string()
+        );
+        assert_eq!(missing.value, Some(number_data_point::Value::AsInt(1)));
+
+        let max = collector.points("db.client.connection.max");
+        assert_eq!(max[0].value, Some(number_data_point::Value::AsInt(7)));
+        assert_eq!(collector.points("db.client.connection.count").len(), 2);
+
+        let depth = collector.points("jobs.queue.depth");
+        assert_eq!(depth[0].value, Some(number_data_point::Value::AsInt(1)));
+
+        telemetry.shutdown();
+    }
+}
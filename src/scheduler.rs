@@ -0,0 +1,656 @@
+//! Cron-style recurring tasks.
+//!
+//! Each configured task is enqueued onto the job channel as a
+//! [`TxMessage::RunTask`] at its next fire time, computed in the configured
+//! timezone. A task is never enqueued again while its previous run is still in
+//! progress; each run has an ID, carried by its job as the correlation ID, so
+//! that only that run's [`JobResult`] ends it. When Postgres is available,
+//! last runs are stored in the `scheduled_tasks` table so that runs missed
+//! during downtime can be skipped or caught up on the next start.
+
+use crate::config::layers::FromConfigValue;
+use crate::error::Error;
+use crate::lifecycle::Shutdown;
+use crate::mpsc::jobs::JobResult;
+use crate::mpsc::TxMessage;
+use crate::utils::{self, logger};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+/// Longest the scheduler sleeps before re-checking, in case the clock jumps.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    pub enabled: bool,
+    pub timezone: Tz,
+    pub tasks: ScheduledTasks,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timezone: Tz::UTC,
+            tasks: ScheduledTasks::default(),
+        }
+    }
+}
+
+impl FromConfigValue for Tz {
+    fn from_text(text: &str) -> Result<Self, String> {
+        text.trim().parse::<Tz>().map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRuns {
+    /// Wait for the next fire time.
+    #[default]
+    Skip,
+    /// Run once straight away, then resume the schedule.
+    CatchUp,
+}
+
+impl FromConfigValue for MissedRuns {
+    fn from_text(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "skip" => Ok(MissedRuns::Skip),
+            "catch_up" | "catch-up" => Ok(MissedRuns::CatchUp),
+            other => Err(format!(
+                "expected \"skip\" or \"catch_up\", got {:?}",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledTask {
+    pub name: String,
+    pub cron: cron::Schedule,
+    /// The registered job to run; defaults to `name`.
+    pub job: String,
+    pub missed: MissedRuns,
+}
+
+/// Tasks are an array of tables in the config file, e.g.
+///
+/// ```toml
+/// [[scheduler.tasks]]
+/// name = "cleanup"
+/// cron = "0 */15 * * * *"
+/// missed = "catch_up"
+/// ```
+///
+/// and `name=cron;name=cron` pairs elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct ScheduledTasks(pub Vec<ScheduledTask>);
+
+impl FromConfigValue for ScheduledTasks {
+    fn from_text(text: &str) -> Result<Self, String> {
+        text.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (name, cron) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected name=cron, got {:?}", entry))?;
+
+                Ok(ScheduledTask {
+                    name: name.trim().to_string(),
+                    cron: parse_cron(cron)?,
+                    job: name.trim().to_string(),
+                    missed: MissedRuns::default(),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(ScheduledTasks)
+    }
+
+    fn from_toml(value: &toml::Value) -> Result<Self, String> {
+        let toml::Value::Array(items) = value else {
+            return Self::from_text(value.as_str().unwrap_or_default());
+        };
+
+        let mut tasks = Vec::new();
+        for item in items {
+            let field = |key: &str| item.get(key).and_then(toml::Value::as_str);
+            let name = field("name").ok_or("every task needs a `name`")?;
+            let cron = field("cron").ok_or_else(|| format!("task {:?} needs a `cron`", name))?;
+
+            tasks.push(ScheduledTask {
+                name: name.to_string(),
+                cron: parse_cron(cron).map_err(|err| format!("task {:?}: {}", name, err))?,
+                job: field("job").unwrap_or(name).to_string(),
+                missed: field("missed")
+                    .map(MissedRuns::from_text)
+                    .transpose()?
+                    .unwrap_or_default(),
+            });
+        }
+
+        Ok(ScheduledTasks(tasks))
+    }
+}
+
+/// Parse a cron expression with an optional leading seconds field; plain
+/// five-field crontab expressions fire at second zero.
+pub fn parse_cron(expression: &str) -> Result<cron::Schedule, String> {
+    let expression = expression.trim();
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+
+    cron::Schedule::from_str(&expression)
+        .map_err(|err| format!("invalid cron expression {:?}: {}", expression, err))
+}
+
+/// The first fire time strictly after `after`, evaluated in `timezone`.
+pub fn next_fire(
+    schedule: &cron::Schedule,
+    timezone: Tz,
+    after: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&after.with_timezone(&timezone))
+        .next()
+        .map(|at| at.with_timezone(&Utc))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleStatus {
+    pub name: String,
+    pub job: String,
+    pub cron: String,
+    pub timezone: String,
+    pub missed: MissedRuns,
+    pub last_run: Option<DateTime<Utc>>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_outcome: Option<Value>,
+    pub running: bool,
+    /// ID of the run in progress.
+    pub run_id: Option<String>,
+}
+
+/// Read-only view of the scheduler's state, shared with handlers.
+pub type ScheduleHandle = Arc<RwLock<Vec<ScheduleStatus>>>;
+
+pub struct Scheduler {
+    tasks: Vec<ScheduledTask>,
+    timezone: Tz,
+    sender: mpsc::Sender<TxMessage>,
+    results: broadcast::Receiver<JobResult>,
+    pool: Option<PgPool>,
+    status: ScheduleHandle,
+}
+
+impl Scheduler {
+    pub fn new(
+        config: &SchedulerConfig,
+        sender: mpsc::Sender<TxMessage>,
+        results: broadcast::Receiver<JobResult>,
+        pool: Option<PgPool>,
+    ) -> Self {
+        let status = config
+            .tasks
+            .0
+            .iter()
+            .map(|task| ScheduleStatus {
+                name: task.name.clone(),
+                job: task.job.clone(),
+                cron: task.cron.source().to_string(),
+                timezone: config.timezone.name().to_string(),
+                missed: task.missed,
+                last_run: None,
+                next_run: next_fire(&task.cron, config.timezone, Utc::now()),
+                last_outcome: None,
+                running: false,
+                run_id: None,
+            })
+            .collect();
+
+        Self {
+            tasks: config.tasks.0.clone(),
+            timezone: config.timezone,
+            sender,
+            results,
+            pool,
+            status: Arc::new(RwLock::new(status)),
+        }
+    }
+
+    pub fn handle(&self) -> ScheduleHandle {
+        self.status.clone()
+    }
+
+    /// Enqueue tasks as they fall due until `shutdown`, which drops this
+    /// scheduler's job sender.
+    pub async fn run(mut self, mut shutdown: Shutdown) -> Result<(), Error> {
+        if let Err(err) = self.restore(Utc::now()).await {
+            tracing::error!("Unable to restore scheduled task history: {}", err);
+        }
+
+        loop {
+            if !self.fire_due(Utc::now()).await {
+                return Ok(());
+            }
+
+            // With no task left to fire, wake up only to check again later.
+            let sleep_for = self.earliest_next_run().map_or(MAX_SLEEP, |next| {
+                (next - Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .min(MAX_SLEEP)
+            });
+
+            tokio::select! {
+                _ = shutdown.wait() => return Ok(()),
+                _ = tokio::time::sleep(sleep_for) => {}
+                result = self.results.recv() => match result {
+                    Ok(result) => self.record_result(result).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Scheduler missed {} job results", skipped);
+                        self.forget_running().await;
+                    }
+                    // The runner is gone, so nothing will be run anyway.
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Apply each task's missed-run policy, given its last run before startup.
+    async fn restore(&mut self, now: DateTime<Utc>) -> Result<(), Error> {
+        let Some(pool) = &self.pool else {
+            return Ok(());
+        };
+
+        let rows: Vec<(String, Option<DateTime<Utc>>, Option<Value>)> =
+            sqlx::query_as("select name, last_run_at, last_outcome from scheduled_tasks")
+                .fetch_all(pool)
+                .await
+                .map_err(Error::new)?;
+
+        let mut status = self.status.write().unwrap();
+        for (task, status) in self.tasks.iter().zip(status.iter_mut()) {
+            let Some((_, last_run, last_outcome)) = rows.iter().find(|row| row.0 == task.name)
+            else {
+                continue;
+            };
+
+            status.last_run = *last_run;
+            status.last_outcome = last_outcome.clone();
+            status.next_run = restored_next_run(task, self.timezone, *last_run, now);
+        }
+
+        Ok(())
+    }
+
+    /// Enqueue every task that is due at `now`. Returns `false` once the job
+    /// channel is closed.
+    async fn fire_due(&self, now: DateTime<Utc>) -> bool {
+        for (index, task) in self.tasks.iter().enumerate() {
+            let (due, running) = {
+                let status = &self.status.read().unwrap()[index];
+                (
+                    status.next_run.is_some_and(|next| next <= now),
+                    status.running,
+                )
+            };
+            if !due {
+                continue;
+            }
+
+            let next_run = next_fire(&task.cron, self.timezone, now);
+            if running {
+                logger::log(
+                    logger::Level::Warn,
+                    logger::Color(utils::ORANGE),
+                    logger::Tag("[ SCHEDULER ]"),
+                    logger::Text(
+                        format!(
+                            "Skipping {}; its previous run is still in progress",
+                            task.name
+                        )
+                        .as_str(),
+                    ),
+                );
+
+                let status = &mut self.status.write().unwrap()[index];
+                status.last_outcome = Some(json!({
+                    "status": "skipped",
+                    "reason": "previous run still in progress",
+                }));
+                status.next_run = next_run;
+                continue;
+            }
+
+            let run_id = Uuid::new_v4().to_string();
+            let msg = TxMessage::RunTask {
+                name: task.job.clone(),
+                timestamp: now.to_rfc3339(),
+                run_id: run_id.clone(),
+            };
+            if self.sender.send(msg).await.is_err() {
+                return false;
+            }
+
+            {
+                let status = &mut self.status.write().unwrap()[index];
+                status.running = true;
+                status.run_id = Some(run_id);
+                status.last_run = Some(now);
+                status.next_run = next_run;
+            }
+            self.persist(&task.name, Some(now), None).await;
+        }
+
+        true
+    }
+
+    /// End the run `result` is for; results of jobs enqueued elsewhere, even
+    /// of the same job, are ignored.
+    async fn record_result(&self, result: JobResult) {
+        let Some(run_id) = result.correlation_id.as_deref() else {
+            return;
+        };
+        let outcome = serde_json::to_value(&result.outcome).ok();
+        self.finish_runs(|status| status.run_id.as_deref() == Some(run_id), outcome)
+            .await;
+    }
+
+    /// End every run in progress, after results were dropped; a task whose
+    /// result was among them would otherwise never run again.
+    async fn forget_running(&self) {
+        let outcome = json!({
+            "status": "unknown",
+            "reason": "the result of the run was missed",
+        });
+        self.finish_runs(|status| status.running, Some(outcome))
+            .await;
+    }
+
+    async fn finish_runs(&self, matches: impl Fn(&ScheduleStatus) -> bool, outcome: Option<Value>) {
+        let mut finished = Vec::new();
+        {
+            let mut status = self.status.write().unwrap();
+            for status in status.iter_mut().filter(|s| s.running && matches(s)) {
+                status.running = false;
+                status.run_id = None;
+                status.last_outcome = outcome.clone();
+                finished.push((status.name.clone(), status.last_run));
+            }
+        }
+
+        for (name, last_run) in finished {
+            self.persist(&name, last_run, outcome.clone()).await;
+        }
+    }
+
+    async fn persist(&self, name: &str, last_run: Option<DateTime<Utc>>, outcome: Option<Value>) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+
+        let result = sqlx::query(
+            r#"
+            insert into scheduled_tasks (name, last_run_at, last_outcome)
+            values ($1, $2, $3)
+            on conflict (name) do update
+                set last_run_at = excluded.last_run_at,
+                    last_outcome = coalesce(excluded.last_outcome, scheduled_tasks.last_outcome)
+            "#,
+        )
+        .bind(name)
+        .bind(last_run)
+        .bind(outcome)
+        .execute(pool)
+        .await;
+
+        if let Err(err) = result {
+            tracing::error!("Unable to record run of scheduled task {}: {}", name, err);
+        }
+    }
+
+    fn earliest_next_run(&self) -> Option<DateTime<Utc>> {
+        self.status
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|status| status.next_run)
+            .min()
+    }
+}
+
+/// The next run of `task` after a restart, given when it last ran.
+fn restored_next_run(
+    task: &ScheduledTask,
+    timezone: Tz,
+    last_run: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let missed = last_run
+        .and_then(|last_run| next_fire(&task.cron, timezone, last_run))
+        .is_some_and(|missed| missed <= now);
+
+    match (missed, task.missed) {
+        (true, MissedRuns::CatchUp) => Some(now),
+        _ => next_fire(&task.cron, timezone, now),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn task(cron: &str, missed: MissedRuns) -> ScheduledTask {
+        ScheduledTask {
+            name: "digest".to_string(),
+            cron: parse_cron(cron).unwrap(),
+            job: "send_digest".to_string(),
+            missed,
+        }
+    }
+
+    #[test]
+    fn next_fire_uses_the_configured_timezone() {
+        // 09:00 in London during BST is 08:00 UTC.
+        let schedule = parse_cron("0 9 * * *").unwrap();
+        let next = next_fire(&schedule, Tz::Europe__London, at("2024-06-01T10:00:00Z"));
+        assert_eq!(
+            next,
+            Some(Utc.with_ymd_and_hms(2024, 6, 2, 8, 0, 0).unwrap())
+        );
+
+        let next = next_fire(&schedule, Tz::UTC, at("2024-06-01T10:00:00Z"));
+        assert_eq!(
+            next,
+            Some(Utc.with_ymd_and_hms(2024, 6, 2, 9, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn missed_runs_are_skipped_or_caught_up() {
+        let now = at("2024-06-01T12:30:00Z");
+        let last_run = Some(at("2024-06-01T09:00:00Z"));
+
+        let skip = task("0 0 * * * *", MissedRuns::Skip);
+        assert_eq!(
+            restored_next_run(&skip, Tz::UTC, last_run, now),
+            Some(at("2024-06-01T13:00:00Z"))
+        );
+
+        let catch_up = task("0 0 * * * *", MissedRuns::CatchUp);
+        assert_eq!(
+            restored_next_run(&catch_up, Tz::UTC, last_run, now),
+            Some(now)
+        );
+
+        // Nothing was missed since the last run.
+        let recent = Some(at("2024-06-01T12:00:00Z"));
+        assert_eq!(
+            restored_next_run(&catch_up, Tz::UTC, recent, now),
+            Some(at("2024-06-01T13:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn tasks_from_toml_and_env() {
+        let value: toml::Table = toml::from_str(
+            r#"
+            tasks = [
+                { name = "cleanup", cron = "0 */15 * * * *", missed = "catch_up" },
+                { name = "digest", cron = "0 9 * * Mon", job = "send_digest" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let tasks = ScheduledTasks::from_toml(&value["tasks"]).unwrap().0;
+        assert_eq!(tasks[0].job, "cleanup");
+        assert_eq!(tasks[0].missed, MissedRuns::CatchUp);
+        assert_eq!(tasks[1].job, "send_digest");
+
+        let tasks = ScheduledTasks::from_text("a=0 0 * * * *; b=*/5 * * * *")
+            .unwrap()
+            .0;
+        assert_eq!(tasks.len(), 2);
+
+        assert!(ScheduledTasks::from_text("a=not a cron").is_err());
+    }
+
+    fn result(name: &str, correlation_id: Option<&str>) -> JobResult {
+        JobResult {
+            id: 0,
+            name: name.to_string(),
+            correlation_id: correlation_id.map(str::to_string),
+            attempts: 1,
+            elapsed_ms: 1,
+            outcome: crate::mpsc::jobs::JobOutcome::Succeeded {
+                output: Value::Null,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_overlap_runs_of_the_same_task() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let (_results_tx, results) = broadcast::channel(8);
+        let config = SchedulerConfig {
+            enabled: true,
+            timezone: Tz::UTC,
+            tasks: ScheduledTasks(vec![task("* * * * * *", MissedRuns::Skip)]),
+        };
+        let scheduler = Scheduler::new(&config, tx, results, None);
+
+        let later = Utc::now() + chrono::Duration::seconds(5);
+        assert!(scheduler.fire_due(later).await);
+        let Ok(TxMessage::RunTask { name, run_id, .. }) = rx.try_recv() else {
+            panic!("the task was not enqueued");
+        };
+        assert_eq!(name, "send_digest");
+
+        // Still running, so the next tick is skipped.
+        assert!(
+            scheduler
+                .fire_due(later + chrono::Duration::seconds(2))
+                .await
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            scheduler.handle().read().unwrap()[0]
+                .last_outcome
+                .as_ref()
+                .unwrap()["status"],
+            "skipped"
+        );
+
+        // Another run of the same job, e.g. enqueued by hand, ends nothing.
+        scheduler
+            .record_result(result("send_digest", Some("manual")))
+            .await;
+        scheduler.record_result(result("send_digest", None)).await;
+        assert!(scheduler.handle().read().unwrap()[0].running);
+
+        scheduler
+            .record_result(result("send_digest", Some(&run_id)))
+            .await;
+        assert!(!scheduler.handle().read().unwrap()[0].running);
+
+        assert!(
+            scheduler
+                .fire_due(later + chrono::Duration::seconds(4))
+                .await
+        );
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn missed_results_end_every_run_in_progress() {
+        let (tx, _rx) = mpsc::channel(8);
+        let (results_tx, results) = broadcast::channel(1);
+        let config = SchedulerConfig {
+            enabled: true,
+            timezone: Tz::UTC,
+            tasks: ScheduledTasks(vec![task("* * * * * *", MissedRuns::Skip)]),
+        };
+        let mut scheduler = Scheduler::new(&config, tx, results, None);
+
+        let later = Utc::now() + chrono::Duration::seconds(5);
+        assert!(scheduler.fire_due(later).await);
+        assert!(scheduler.handle().read().unwrap()[0].running);
+
+        // The task's result is among those dropped by the lagging receiver.
+        let run_id = scheduler.handle().read().unwrap()[0].run_id.clone();
+        results_tx
+            .send(result("send_digest", run_id.as_deref()))
+            .unwrap();
+        results_tx.send(result("other", None)).unwrap();
+        assert!(matches!(
+            scheduler.results.recv().await,
+            Err(broadcast::error::RecvError::Lagged(1))
+        ));
+        scheduler.forget_running().await;
+
+        let status = scheduler.handle().read().unwrap()[0].clone();
+        assert!(!status.running);
+        assert_eq!(status.last_outcome.as_ref().unwrap()["status"], "unknown");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleeps_when_nothing_is_scheduled() {
+        let (tx, _rx) = mpsc::channel(8);
+        let (_results_tx, results) = broadcast::channel(8);
+        let scheduler = Scheduler::new(&SchedulerConfig::default(), tx, results, None);
+        let (shutdown, stopping) = crate::lifecycle::shutdown_channel();
+        let running = tokio::spawn(scheduler.run(stopping));
+
+        // Paused time only moves on while every task waits, so this returns
+        // only if the loop yields instead of spinning.
+        let started = tokio::time::Instant::now();
+        tokio::time::sleep(MAX_SLEEP * 3).await;
+        assert!(started.elapsed() >= MAX_SLEEP * 3);
+        assert!(!running.is_finished());
+
+        shutdown.trigger();
+        running.await.unwrap().unwrap();
+    }
+}
@@ -1,106 +1,216 @@
-use crate::{mpsc::TxMessage, AppConfig};
+use crate::{
+    auth::{csrf::CsrfLayer, permissions::RequirePermission, Sessions},
+    error::Error,
+    mpsc::queue::SharedJobQueue,
+    observability::metrics::{self, HttpMetrics},
+    scheduler::ScheduleHandle,
+    AppConfig,
+};
 use axum::{
+    error_handling::HandleErrorLayer,
     extract::{DefaultBodyLimit, Extension},
-    http::HeaderValue,
+    http::{header, Request},
     response::{IntoResponse, Response},
-    routing::{get, Router},
+    routing::{get, post, Router},
+    ServiceExt,
 };
-use hyper::StatusCode;
-use std::fmt;
-use std::net::IpAddr;
+use axum_server::Handle;
+use hyper::body::Incoming;
 use std::net::SocketAddr;
-use tokio::sync::mpsc;
-use tower::ServiceBuilder;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tower::{
+    limit::GlobalConcurrencyLimitLayer, util::MapRequestLayer, BoxError, Layer, ServiceBuilder,
+};
 use tower_http::{
-    catch_panic::CatchPanicLayer,
-    cors::{AllowOrigin, Any, CorsLayer},
-    trace,
-    trace::TraceLayer,
+    catch_panic::CatchPanicLayer, set_header::SetResponseHeaderLayer, trace, trace::TraceLayer,
 };
 use tracing::Level;
 
+pub mod admin;
+pub mod auth;
 pub mod common;
+pub mod compression;
+pub mod cors;
+pub mod errors;
 pub mod handlers;
+pub mod locale;
+pub mod panics;
 pub mod public;
+pub mod rate_limit;
+pub mod security;
+pub mod tls;
 
+/// Shared handles the server is built with; all but `metrics` are available
+/// to every handler as an `Extension`.
+#[derive(Clone)]
+pub struct Services {
+    pub queue: SharedJobQueue,
+    pub schedules: ScheduleHandle,
+    pub sessions: Sessions,
+    pub metrics: Arc<HttpMetrics>,
+}
+
+/// Serve the API until `handle` is told to shut down.
 pub async fn serve(
     config: &AppConfig,
     addr: common::NetworkAddr<'_>,
-    handle: mpsc::Sender<TxMessage>,
-) {
+    services: Services,
+    handle: Handle,
+) -> Result<(), Error> {
     let mut app = api_router();
+    if config.server.single_port {
+        app = app.merge(public::assets(&config.assets));
+    }
 
-    app = allow_cors(app);
-    app = add_middleware(config, app, handle);
-
-    let ip: IpAddr = addr.host().parse().unwrap();
-    let addr = SocketAddr::from((ip, addr.port()));
-    axum_server::bind(addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    app = allow_cors(config, app);
+    app = add_middleware(config, app, services);
+    // Locale prefixes are stripped before the router sees the path.
+    let i18n = config.i18n.clone();
+    let app = MapRequestLayer::new(move |request| locale::route_prefix(&i18n, request)).layer(app);
+
+    let listener = addr.bind()?;
+    let Some(rustls) = tls::rustls_config(&config.tls).await? else {
+        axum_server::from_tcp(listener)
+            .handle(handle)
+            .serve(
+                ServiceExt::<Request<Incoming>>::into_make_service_with_connect_info::<SocketAddr>(
+                    app,
+                ),
+            )
+            .await?;
+
+        return Ok(());
+    };
+
+    let (cert, key) = config.tls.paths().expect("TLS is enabled");
+    let watcher = tokio::spawn(tls::watch(
+        rustls.clone(),
+        cert.to_path_buf(),
+        key.to_path_buf(),
+        Duration::from_secs(config.tls.reload_interval.max(1)),
+    ));
+    let served = axum_server::from_tcp_rustls(listener, rustls)
+        .handle(handle)
+        .serve(
+            ServiceExt::<Request<Incoming>>::into_make_service_with_connect_info::<SocketAddr>(app),
+        )
+        .await;
+    watcher.abort();
+    served?;
+
+    Ok(())
 }
 
-pub fn get_middleware(config: &AppConfig, handle: mpsc::Sender<TxMessage>) -> Router {
+pub fn get_middleware(config: &AppConfig, services: Services) -> Router {
     let mut app = api_router();
-    app = add_middleware(config, app, handle);
+    app = add_middleware(config, app, services);
 
     app
 }
 
-struct CorsOrigins<'a>(pub(crate) &'a Vec<HeaderValue>);
-
-impl From<CorsOrigins<'_>> for AllowOrigin {
-    fn from(value: CorsOrigins<'_>) -> Self {
-        AllowOrigin::list(value.0.to_owned())
-    }
-}
-
-impl<'a> fmt::Display for CorsOrigins<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.iter().fold(Ok(()), |result, origin| {
-            result.and_then(|_| writeln!(f, "{:?}", origin))
-        })
-    }
-}
-
-fn allow_cors(router: Router) -> Router {
-    let origins = [
-        "http://localhost:9001".parse().unwrap(),
-        "http://10.2.40.53:9001".parse().unwrap(), // iPhone iOS testing locally
-    ];
-
-    let cors = CorsLayer::new()
-        .allow_origin(origins)
-        .allow_headers(Any)
-        .allow_methods(Any);
+fn allow_cors(config: &AppConfig, router: Router) -> Router {
+    tracing::debug!("CORS profile: {}", config.cors.profile);
+    let policy = Arc::new(cors::CorsPolicy::new(&config.cors));
 
-    router.layer(cors)
+    router.layer(axum::middleware::from_fn_with_state(policy, cors::apply))
 }
 
-fn add_middleware(config: &AppConfig, router: Router, handle: mpsc::Sender<TxMessage>) -> Router {
+fn add_middleware(config: &AppConfig, router: Router, services: Services) -> Router {
     router.layer(
         ServiceBuilder::new()
+            .layer(axum::middleware::from_fn(errors::tag_request))
             .layer(
                 TraceLayer::new_for_http()
-                    .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                    .make_span_with(errors::request_span)
                     .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
             )
-            .layer(CatchPanicLayer::custom(PanicLayerResponse {}))
+            .layer(axum::middleware::from_fn_with_state(
+                services.metrics,
+                metrics::record,
+            ))
+            .layer(HandleErrorLayer::new(shed))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(
+                match config.rate_limit.max_in_flight {
+                    0 => Semaphore::MAX_PERMITS,
+                    max => max,
+                },
+            ))
+            .layer(compression::layer(&config.compression))
+            .option_layer(config.tls.hsts_header().map(|value| {
+                SetResponseHeaderLayer::if_not_present(header::STRICT_TRANSPORT_SECURITY, value)
+            }))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(security::SecurityHeaders::new(
+                    &config.security,
+                    &config.assets.base_url,
+                )),
+                security::apply,
+            ))
+            .layer(CatchPanicLayer::custom(panics::PanicLayerResponse::new(
+                config.pg_pool.clone(),
+            )))
             .layer(Extension(config.clone()))
-            .layer(Extension(handle))
-            .layer(DefaultBodyLimit::max(20971520)),
+            .layer(Extension(services.queue))
+            .layer(Extension(services.schedules))
+            .layer(Extension(services.sessions.clone()))
+            .layer(axum::middleware::from_fn(locale::negotiate))
+            .layer(axum::middleware::from_fn(errors::render_errors))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(rate_limit::RateLimiter::new(
+                    &config.rate_limit,
+                    config.pg_pool.clone(),
+                )),
+                rate_limit::limit,
+            ))
+            .layer(
+                CsrfLayer::new(services.sessions, config.server.body_limit)
+                    .allow_bearer("/api/")
+                    .exempt(security::REPORT_PATH),
+            )
+            .layer(DefaultBodyLimit::max(config.server.body_limit)),
     )
 }
 
+/// Refuse a request beyond `rate_limit.max_in_flight`.
+async fn shed(_: BoxError) -> Response {
+    Error::overloaded().into_response()
+}
+
 fn api_router() -> Router {
     Router::new()
         .route("/health", get(crate::server::common::handle_health_get))
         .route("/", get(handlers::render_index))
         .route("/about", get(handlers::render_about))
-        // FIXME: This is for local testing only
-        .route("/panic", get(lets_panic))
-        .fallback(crate::content::templates::error_404_template)
+        .route("/login", get(auth::render_login).post(auth::login))
+        .route("/logout", post(auth::logout))
+        .route(
+            security::REPORT_PATH,
+            post(security::csp_report).layer(DefaultBodyLimit::max(64 * 1024)),
+        )
+        .route(
+            "/admin/schedules",
+            get(admin::list_schedules).route_layer(RequirePermission("admin.read")),
+        )
+        .route(
+            "/admin/incidents",
+            get(admin::list_incidents).route_layer(RequirePermission("admin.read")),
+        )
+        .route(
+            "/admin/translations",
+            get(admin::list_translations).route_layer(RequirePermission("admin.read")),
+        )
+        .route(
+            "/admin/translations/reload",
+            post(admin::reload_translations).route_layer(RequirePermission("translations.reload")),
+        )
+        .route(
+            "/panic",
+            get(lets_panic).route_layer(RequirePermission("debug.panic")),
+        )
+        .fallback(handlers::not_found)
 }
 
 async fn lets_panic() -> impl IntoResponse {
@@ -109,27 +219,7 @@ async fn lets_panic() -> impl IntoResponse {
 
 // Force never type (`!`) to degrade to `()`
 // Ref: https://github.com/rust-lang/rust/issues/123748
+#[allow(clippy::unused_unit)]
 fn do_panic() -> () {
     panic!("panic like it's 1999...")
 }
-
-#[derive(Clone)]
-struct PanicLayerResponse {}
-
-impl tower_http::catch_panic::ResponseForPanic for PanicLayerResponse {
-    type ResponseBody = String;
-
-    fn response_for_panic(
-        &mut self,
-        _err: Box<dyn std::any::Any + Send + 'static>,
-    ) -> hyper::Response<Self::ResponseBody> {
-        let template = crate::content::templates::panic_error_template();
-
-        let resp = Response::builder()
-            // RA block
-            .status(StatusCode::OK)
-            .body(template.into());
-
-        resp.expect("Unable to unwrap panic template!")
-    }
-}
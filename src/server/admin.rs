@@ -0,0 +1,59 @@
+use super::common;
+use crate::config::AppConfig;
+use crate::content::templates::I18N_STATIC_CONTENT;
+use crate::content::translations;
+use crate::error::Error;
+use crate::models::incidents;
+use crate::scheduler::ScheduleHandle;
+use axum::extract::Extension;
+use nosferatu::prelude::axum_prelude::*;
+use serde_json::json;
+
+/// Every recurring task with its last and next run and last outcome.
+pub async fn list_schedules(
+    Extension(schedules): Extension<ScheduleHandle>,
+) -> Result<Response, Error> {
+    let schedules = schedules
+        .read()
+        .map_err(|_| Error::new("Scheduler state is poisoned"))?
+        .clone();
+
+    Ok(common::return_json(json!({ "schedules": schedules }), None)?.into_response())
+}
+
+/// Panics from the last 30 days, grouped by where they happened.
+pub async fn list_incidents(Extension(config): Extension<AppConfig>) -> Result<Response, Error> {
+    let pool = config
+        .pg_pool
+        .as_ref()
+        .ok_or_else(|| Error::new("Postgres is not configured"))?;
+    let groups = incidents::recent_by_location(pool, 30).await?;
+
+    Ok(common::return_json(json!({ "incidents": groups }), None)?.into_response())
+}
+
+/// Translated key counts per locale, and keys rendered without a translation
+/// since the last reload.
+pub async fn list_translations() -> Result<Response, Error> {
+    let bundle = I18N_STATIC_CONTENT.load();
+    let body = json!({
+        "default_locale": bundle.default_locale(),
+        "locales": bundle.locales(),
+        "missing": bundle.missing(),
+    });
+
+    Ok(common::return_json(body, None)?.into_response())
+}
+
+/// Reload translations from the database without a restart.
+pub async fn reload_translations(
+    Extension(config): Extension<AppConfig>,
+) -> Result<Response, Error> {
+    let pool = config
+        .pg_pool
+        .as_ref()
+        .ok_or_else(|| Error::new("Postgres is not configured"))?;
+    let loaded = translations::reload(pool, &config.i18n).await?;
+
+    Ok(common::return_json(json!({ "loaded": loaded }), None)?.into_response())
+}
@@ -0,0 +1,70 @@
+use crate::auth::{MaybeUser, Sessions};
+use crate::content::templates::page::PageContext;
+use crate::content::templates::{t, HtmlTemplate, LoginTemplate};
+use crate::error::Error;
+use axum::extract::{Extension, Form};
+use axum::http::HeaderMap;
+use axum::response::Redirect;
+use nosferatu::prelude::axum_prelude::*;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    pub email: String,
+    pub password: String,
+}
+
+pub async fn render_login(MaybeUser(user): MaybeUser, page: PageContext) -> Response {
+    if user.is_some() {
+        return Redirect::to("/").into_response();
+    }
+
+    HtmlTemplate(LoginTemplate {
+        user,
+        page: login_page(page),
+        email: String::default(),
+        error: None,
+    })
+    .into_response()
+}
+
+/// Start a session and redirect home, or show the form again on bad
+/// credentials.
+pub async fn login(
+    Extension(sessions): Extension<Sessions>,
+    headers: HeaderMap,
+    page: PageContext,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, Error> {
+    let jar = sessions.jar(&headers);
+
+    match sessions.login(jar, &form.email, &form.password).await? {
+        Some((jar, user)) => {
+            tracing::info!("User {} logged in", user.user_id);
+            Ok((jar, Redirect::to("/")).into_response())
+        }
+        None => {
+            let template = LoginTemplate {
+                user: None,
+                page: login_page(page),
+                email: form.email,
+                error: Some("Incorrect email or password.".to_string()),
+            };
+
+            Ok((StatusCode::UNAUTHORIZED, HtmlTemplate(template)).into_response())
+        }
+    }
+}
+
+fn login_page(page: PageContext) -> PageContext {
+    page.route("login").title(t("page-login")).noindex()
+}
+
+pub async fn logout(
+    Extension(sessions): Extension<Sessions>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let jar = sessions.logout(sessions.jar(&headers)).await?;
+
+    Ok((jar, Redirect::to("/")).into_response())
+}
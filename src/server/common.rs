@@ -1,8 +1,10 @@
 use crate::error::Error;
+use axum::http::{header, HeaderMap};
 use nosferatu::prelude::axum_prelude::*;
 use serde_json::json;
 use serde_json::Value;
 use std::fmt;
+use std::net::{IpAddr, SocketAddr, TcpListener};
 
 pub async fn handle_health_get() -> Result<Response, Error> {
     Ok(return_json(json!({ "status": "success" }), None)?.into_response())
@@ -20,6 +22,16 @@ pub fn return_json(json: Value, status: Option<StatusCode>) -> Result<Response<B
     Ok(resp)
 }
 
+/// Whether the client asked for JSON rather than HTML, going by `Accept`.
+pub fn wants_json(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    accept.contains("json") && !accept.contains("text/html")
+}
+
 pub struct NetworkAddr<'a>(&'a str, u16);
 
 impl<'a> NetworkAddr<'a> {
@@ -34,6 +46,20 @@ impl<'a> NetworkAddr<'a> {
     pub fn port(&'a self) -> u16 {
         self.1
     }
+
+    /// Bind a listener, so that a bad host or a port in use is reported
+    /// before serving starts.
+    pub fn bind(&self) -> Result<TcpListener, Error> {
+        let ip: IpAddr = self
+            .0
+            .parse()
+            .map_err(|err| Error::new(format!("Invalid host {}: {}", self.0, err)))?;
+        let listener = TcpListener::bind(SocketAddr::from((ip, self.1)))
+            .map_err(|err| Error::new(format!("Unable to bind {}: {}", self, err)))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(listener)
+    }
 }
 
 impl<'a> fmt::Display for NetworkAddr<'a> {
@@ -0,0 +1,181 @@
+//! Response compression.
+//!
+//! Dynamic responses, such as rendered pages and JSON, are compressed on the
+//! fly with brotli, zstd or gzip, whichever `Accept-Encoding` prefers. Static
+//! files are compressed ahead of time: `compress-assets` writes `.br` and
+//! `.gz` sidecars next to each text asset, and the public server sends those
+//! to clients that accept them.
+
+use crate::content::files;
+use crate::error::Error;
+use axum::http::{Extensions, HeaderMap, StatusCode, Version};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Bodies smaller than this many bytes are sent as they are; compressing
+    /// them saves less than the headers cost.
+    pub min_size: u16,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: 1024,
+        }
+    }
+}
+
+/// Extensions of the static files worth compressing; images and fonts are
+/// compressed already.
+const COMPRESSIBLE: &[&str] = &[
+    "css", "js", "mjs", "map", "html", "htm", "svg", "json", "txt", "xml", "wasm",
+];
+
+/// Compress dynamic responses of at least `config.min_size` bytes. When
+/// compression is turned off the layer passes every response through.
+pub fn layer(config: &CompressionConfig) -> CompressionLayer<impl Predicate> {
+    let enabled = config.enabled;
+
+    CompressionLayer::new().compress_when(
+        SizeAbove::new(config.min_size)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE)
+            .and(move |_: StatusCode, _: Version, _: &HeaderMap, _: &Extensions| enabled),
+    )
+}
+
+/// Write `.br` and `.gz` sidecars for every compressible file under `dir` of
+/// at least `min_size` bytes, returning the files compressed. Sidecars newer
+/// than their file are left alone.
+pub fn compress_dir(dir: &Path, min_size: u64) -> Result<Vec<PathBuf>, Error> {
+    let mut compressed = Vec::new();
+    for path in files(dir)? {
+        let compressible = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| COMPRESSIBLE.contains(&extension));
+        let metadata = std::fs::metadata(&path)?;
+        if !compressible || metadata.len() < min_size {
+            continue;
+        }
+
+        let modified = metadata.modified()?;
+        let (br, gz) = (sidecar(&path, "br"), sidecar(&path, "gz"));
+        let fresh = |sidecar: &Path| {
+            std::fs::metadata(sidecar)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|written| written >= modified)
+        };
+        if fresh(&br) && fresh(&gz) {
+            continue;
+        }
+
+        let contents = std::fs::read(&path)?;
+        write_brotli(&br, &contents)?;
+        write_gzip(&gz, &contents)?;
+        compressed.push(path);
+    }
+
+    Ok(compressed)
+}
+
+fn sidecar(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+
+    PathBuf::from(name)
+}
+
+fn write_brotli(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let file = std::fs::File::create(path)?;
+    let mut writer = brotli::CompressorWriter::new(file, 4096, 11, 22);
+    writer.write_all(contents)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_gzip(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let file = std::fs::File::create(path)?;
+    let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::best());
+    encoder.write_all(contents)?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::{self, Body};
+    use axum::http::{header, Request};
+    use axum::{routing::get, Router};
+    use std::io::Read;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn compresses_large_dynamic_responses() {
+        let config = CompressionConfig {
+            enabled: true,
+            min_size: 100,
+        };
+        let app = Router::new()
+            .route("/small", get(|| async { "tiny" }))
+            .route("/large", get(|| async { "page ".repeat(100) }))
+            .layer(layer(&config));
+
+        let get = |uri: &str| {
+            app.clone().oneshot(
+                Request::get(uri)
+                    .header(header::ACCEPT_ENCODING, "gzip, br;q=0.9, zstd;q=0.5")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("/large").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "page ".repeat(100));
+
+        let response = get("/small").await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn writes_sidecars_for_large_text_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("css")).unwrap();
+        let css = dir.path().join("css/output.css");
+        std::fs::write(&css, "body { margin: 0 }\n".repeat(100)).unwrap();
+        std::fs::write(dir.path().join("small.js"), "run()").unwrap();
+        std::fs::write(dir.path().join("hero.png"), vec![0; 4096]).unwrap();
+
+        let compressed = compress_dir(dir.path(), 1024).unwrap();
+        assert_eq!(compressed, vec![css.clone()]);
+
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(sidecar(&css, "gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "body { margin: 0 }\n".repeat(100));
+        assert!(sidecar(&css, "br").exists());
+
+        assert!(compress_dir(dir.path(), 1024).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,540 @@
+//! Cross-origin resource sharing.
+//!
+//! Policies are named profiles in the config file, one of which applies to
+//! the whole app, e.g. `development` locally and `production` when deployed:
+//!
+//! ```toml
+//! [cors]
+//! profile = "production"
+//!
+//! [cors.profiles.production]
+//! origins = ["https://example.com", "https://*.example.com"]
+//! origin_patterns = ['^https://pr-\d+\.preview\.example\.com$']
+//! allow_credentials = true
+//! methods = ["GET", "POST"]
+//! headers = ["content-type", "x-csrf-token"]
+//! max_age = 3600
+//!
+//! [[cors.routes]]
+//! path = "/health"
+//! profile = "public"
+//! ```
+//!
+//! `cors.routes` gives paths under a prefix another profile, such as an open
+//! one for public endpoints. Without a `default` profile, one is made from
+//! `server.cors_origins`.
+
+use crate::config::layers::FromConfigValue;
+use axum::extract::{Request, State};
+use axum::http::{header, request::Parts, HeaderName, HeaderValue, Method};
+use axum::middleware::Next;
+use axum::response::Response;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::{Layer, ServiceExt};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// The profile for routes without an override.
+    pub profile: String,
+    pub profiles: CorsProfiles,
+    pub routes: CorsRoutes,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            profile: "default".to_string(),
+            profiles: CorsProfiles::default(),
+            routes: CorsRoutes::default(),
+        }
+    }
+}
+
+/// An allowed origin: `*`, an exact origin, or a pattern such as
+/// `https://*.example.com` matching any subdomain.
+#[derive(Debug, Clone)]
+pub enum OriginRule {
+    Any,
+    Exact(HeaderValue),
+    Pattern(Regex),
+}
+
+impl OriginRule {
+    fn matches(&self, origin: &HeaderValue) -> bool {
+        match self {
+            OriginRule::Any => true,
+            OriginRule::Exact(allowed) => allowed == origin,
+            OriginRule::Pattern(pattern) => {
+                origin.to_str().is_ok_and(|origin| pattern.is_match(origin))
+            }
+        }
+    }
+
+    /// A regex given as is, for `origin_patterns`.
+    fn regex(text: &str) -> Result<Self, String> {
+        Regex::new(text.trim())
+            .map(OriginRule::Pattern)
+            .map_err(|err| format!("invalid origin pattern {:?}: {}", text, err))
+    }
+}
+
+impl FromConfigValue for OriginRule {
+    fn from_text(text: &str) -> Result<Self, String> {
+        let text = text.trim().trim_end_matches('/');
+        if text == "*" {
+            return Ok(OriginRule::Any);
+        }
+        if !text.contains('*') {
+            return HeaderValue::from_text(text).map(OriginRule::Exact);
+        }
+
+        // Each `*` stands for one or more subdomain labels.
+        let pattern = text
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(r"[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*");
+        Self::regex(&format!("^{}$", pattern))
+    }
+}
+
+impl FromConfigValue for Method {
+    fn from_text(text: &str) -> Result<Self, String> {
+        Method::from_bytes(text.trim().to_ascii_uppercase().as_bytes())
+            .map_err(|err| format!("invalid method {:?}: {}", text, err))
+    }
+}
+
+impl FromConfigValue for HeaderName {
+    fn from_text(text: &str) -> Result<Self, String> {
+        HeaderName::from_bytes(text.trim().as_bytes())
+            .map_err(|err| format!("invalid header name {:?}: {}", text, err))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsProfile {
+    pub origins: Vec<OriginRule>,
+    /// Send `Access-Control-Allow-Credentials`, letting browsers include
+    /// cookies; not allowed together with a `*` origin.
+    pub allow_credentials: bool,
+    pub methods: Vec<Method>,
+    /// Request headers clients may send.
+    pub headers: Vec<HeaderName>,
+    /// Response headers scripts may read.
+    pub expose_headers: Vec<HeaderName>,
+    /// Seconds browsers may cache a preflight response.
+    pub max_age: Option<u64>,
+}
+
+impl CorsProfile {
+    /// A profile allowing `origins` the usual methods and headers.
+    pub fn with_origins(origins: Vec<HeaderValue>) -> Self {
+        Self {
+            origins: origins.into_iter().map(OriginRule::Exact).collect(),
+            allow_credentials: false,
+            methods: vec![
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ],
+            headers: vec![
+                header::ACCEPT,
+                header::ACCEPT_LANGUAGE,
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                crate::auth::csrf::CSRF_HEADER.clone(),
+            ],
+            expose_headers: Vec::new(),
+            max_age: Some(600),
+        }
+    }
+
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        self.origins.iter().any(|rule| rule.matches(origin))
+    }
+
+    fn allows_any(&self) -> bool {
+        self.origins
+            .iter()
+            .any(|rule| matches!(rule, OriginRule::Any))
+    }
+
+    fn from_table(value: &toml::Value) -> Result<Self, String> {
+        let toml::Value::Table(table) = value else {
+            return Err(format!("expected a table, got {}", value.type_str()));
+        };
+        if let Some(key) = table
+            .keys()
+            .find(|key| !PROFILE_KEYS.contains(&key.as_str()))
+        {
+            return Err(format!("unknown key {:?}", key));
+        }
+
+        let defaults = Self::with_origins(Vec::new());
+        let mut origins: Vec<OriginRule> = field(table, "origins")?.unwrap_or_default();
+        for pattern in field::<Vec<String>>(table, "origin_patterns")?.unwrap_or_default() {
+            origins.push(OriginRule::regex(&pattern)?);
+        }
+
+        let profile = Self {
+            origins,
+            allow_credentials: field(table, "allow_credentials")?
+                .unwrap_or(defaults.allow_credentials),
+            methods: field(table, "methods")?.unwrap_or(defaults.methods),
+            headers: field(table, "headers")?.unwrap_or(defaults.headers),
+            expose_headers: field(table, "expose_headers")?.unwrap_or(defaults.expose_headers),
+            max_age: match table.get("max_age") {
+                Some(value) => Some(u64::from_toml(value)?),
+                None => defaults.max_age,
+            },
+        };
+        if profile.allow_credentials && profile.allows_any() {
+            return Err("allow_credentials cannot be combined with a \"*\" origin".to_string());
+        }
+
+        Ok(profile)
+    }
+
+    /// The layer enforcing this profile; origins it turns away are logged,
+    /// unless the request is same-origin.
+    pub fn layer(self: &Arc<Self>, name: &str) -> CorsLayer {
+        let allow_origin = if self.allows_any() {
+            AllowOrigin::any()
+        } else {
+            let profile = Arc::clone(self);
+            let name = name.to_string();
+            AllowOrigin::predicate(move |origin: &HeaderValue, parts: &Parts| {
+                let allowed = profile.allows(origin);
+                if !allowed && !same_origin(origin, parts) {
+                    tracing::warn!(
+                        origin = ?origin,
+                        method = %parts.method,
+                        path = %parts.uri.path(),
+                        profile = %name,
+                        "Rejected CORS origin"
+                    );
+                }
+                allowed
+            })
+        };
+
+        let mut layer = CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(self.methods.clone())
+            .allow_headers(self.headers.clone())
+            .expose_headers(self.expose_headers.clone())
+            .allow_credentials(self.allow_credentials);
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(Duration::from_secs(max_age));
+        }
+
+        layer
+    }
+}
+
+const PROFILE_KEYS: &[&str] = &[
+    "origins",
+    "origin_patterns",
+    "allow_credentials",
+    "methods",
+    "headers",
+    "expose_headers",
+    "max_age",
+];
+
+fn field<T: FromConfigValue>(table: &toml::Table, key: &str) -> Result<Option<T>, String> {
+    table
+        .get(key)
+        .map(|value| T::from_toml(value).map_err(|err| format!("{}: {}", key, err)))
+        .transpose()
+}
+
+/// Whether `origin` is the host the request was sent to; browsers send
+/// `Origin` on same-origin form posts too.
+fn same_origin(origin: &HeaderValue, parts: &Parts) -> bool {
+    let host = parts.headers.get(header::HOST);
+    let origin_host = origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .map(|(_, host)| host);
+
+    matches!((origin_host, host), (Some(origin), Some(host)) if host == origin)
+}
+
+/// Profiles by name; a TOML table of tables in the config file, and the same
+/// written as a TOML document elsewhere, e.g.
+/// `CORS_PROFILES='public = { origins = ["*"] }'`.
+#[derive(Debug, Clone, Default)]
+pub struct CorsProfiles(pub BTreeMap<String, CorsProfile>);
+
+impl FromConfigValue for CorsProfiles {
+    fn from_text(text: &str) -> Result<Self, String> {
+        let table = text
+            .parse::<toml::Table>()
+            .map_err(|err| format!("expected TOML profiles: {}", err))?;
+
+        Self::from_toml(&toml::Value::Table(table))
+    }
+
+    fn from_toml(value: &toml::Value) -> Result<Self, String> {
+        let toml::Value::Table(table) = value else {
+            return Self::from_text(value.as_str().unwrap_or_default());
+        };
+
+        table
+            .iter()
+            .map(|(name, profile)| {
+                CorsProfile::from_table(profile)
+                    .map(|profile| (name.clone(), profile))
+                    .map_err(|err| format!("profile {:?}: {}", name, err))
+            })
+            .collect::<Result<_, _>>()
+            .map(CorsProfiles)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsRoute {
+    /// Matches this path and everything below it.
+    pub path: String,
+    pub profile: String,
+}
+
+impl CorsRoute {
+    fn matches(&self, path: &str) -> bool {
+        let prefix = self.path.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+            None => false,
+        }
+    }
+}
+
+/// Per-route overrides; an array of tables in the config file, e.g.
+///
+/// ```toml
+/// [[cors.routes]]
+/// path = "/api/public"
+/// profile = "public"
+/// ```
+///
+/// and `path=profile;path=profile` pairs elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct CorsRoutes(pub Vec<CorsRoute>);
+
+impl FromConfigValue for CorsRoutes {
+    fn from_text(text: &str) -> Result<Self, String> {
+        text.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (path, profile) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected path=profile, got {:?}", entry))?;
+
+                Ok(CorsRoute {
+                    path: path.trim().to_string(),
+                    profile: profile.trim().to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(CorsRoutes)
+    }
+
+    fn from_toml(value: &toml::Value) -> Result<Self, String> {
+        let toml::Value::Array(items) = value else {
+            return Self::from_text(value.as_str().unwrap_or_default());
+        };
+
+        let mut routes = Vec::new();
+        for item in items {
+            let field = |key: &str| item.get(key).and_then(toml::Value::as_str);
+            let path = field("path").ok_or("every route needs a `path`")?;
+            let profile =
+                field("profile").ok_or_else(|| format!("route {:?} needs a `profile`", path))?;
+
+            routes.push(CorsRoute {
+                path: path.to_string(),
+                profile: profile.to_string(),
+            });
+        }
+
+        Ok(CorsRoutes(routes))
+    }
+}
+
+/// The layer each request gets: the most specific route override, or the
+/// configured profile.
+#[derive(Clone)]
+pub struct CorsPolicy {
+    default: CorsLayer,
+    routes: Vec<(CorsRoute, CorsLayer)>,
+}
+
+impl CorsPolicy {
+    /// Build the layers of `config`, whose profile names were checked when it
+    /// was loaded; an unknown name allows no origins.
+    pub fn new(config: &CorsConfig) -> Self {
+        let layers: BTreeMap<&String, CorsLayer> = config
+            .profiles
+            .0
+            .iter()
+            .map(|(name, profile)| (name, Arc::new(profile.clone()).layer(name)))
+            .collect();
+        let layer = |name: &String| match layers.get(name) {
+            Some(layer) => layer.clone(),
+            None => {
+                tracing::warn!("CORS profile {:?} is not defined", name);
+                Arc::new(CorsProfile::with_origins(Vec::new())).layer(name)
+            }
+        };
+
+        let mut routes: Vec<(CorsRoute, CorsLayer)> = config
+            .routes
+            .0
+            .iter()
+            .map(|route| (route.clone(), layer(&route.profile)))
+            .collect();
+        routes.sort_by_key(|(route, _)| std::cmp::Reverse(route.path.len()));
+
+        Self {
+            default: layer(&config.profile),
+            routes,
+        }
+    }
+
+    fn for_path(&self, path: &str) -> &CorsLayer {
+        self.routes
+            .iter()
+            .find(|(route, _)| route.matches(path))
+            .map_or(&self.default, |(_, layer)| layer)
+    }
+}
+
+/// Middleware applying the [`CorsPolicy`] for the request's path.
+pub async fn apply(
+    State(policy): State<Arc<CorsPolicy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let cors = policy.for_path(request.uri().path()).layer(next);
+    match cors.oneshot(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use axum::{routing::get, Router};
+
+    fn app(config: &CorsConfig) -> Router {
+        Router::new()
+            .route("/login", get(|| async { "login" }))
+            .route("/health", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(CorsPolicy::new(config)),
+                apply,
+            ))
+    }
+
+    async fn allowed_origin(app: &Router, path: &str, origin: &str) -> Option<String> {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get(path)
+                    .header(header::ORIGIN, origin)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn applies_profiles_by_route() {
+        let profiles = CorsProfiles::from_text(
+            r#"
+            production = { origins = ["https://example.com", "https://*.example.com"], origin_patterns = ['^https://pr-\d+\.preview\.test$'], allow_credentials = true }
+            public = { origins = ["*"], methods = ["get"] }
+            "#,
+        )
+        .unwrap();
+        let config = CorsConfig {
+            profile: "production".to_string(),
+            profiles,
+            routes: CorsRoutes::from_text("/health=public").unwrap(),
+        };
+        let app = app(&config);
+
+        for origin in [
+            "https://example.com",
+            "https://shop.example.com",
+            "https://a.b.example.com",
+            "https://pr-12.preview.test",
+        ] {
+            assert_eq!(
+                allowed_origin(&app, "/login", origin).await.as_deref(),
+                Some(origin)
+            );
+        }
+        for origin in [
+            "https://evil.com",
+            "https://example.com.evil.com",
+            "http://shop.example.com",
+        ] {
+            assert_eq!(allowed_origin(&app, "/login", origin).await, None);
+        }
+        assert_eq!(
+            allowed_origin(&app, "/health", "https://evil.com")
+                .await
+                .as_deref(),
+            Some("*")
+        );
+
+        let preflight = app
+            .oneshot(
+                Request::options("/login")
+                    .header(header::ORIGIN, "https://example.com")
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let headers = preflight.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap()
+            .contains("x-csrf-token"));
+    }
+
+    #[test]
+    fn credentials_need_explicit_origins() {
+        let err =
+            CorsProfiles::from_text(r#"open = { origins = ["*"], allow_credentials = true }"#)
+                .unwrap_err();
+        assert!(err.contains("allow_credentials"), "{}", err);
+
+        let err = CorsProfiles::from_text(r#"typo = { origin = ["https://a.test"] }"#).unwrap_err();
+        assert!(err.contains("unknown key \"origin\""), "{}", err);
+    }
+}
@@ -0,0 +1,385 @@
+//! Renders [`Error`](crate::error::Error) responses for the client.
+//!
+//! Handlers return errors as bare problem documents; this middleware turns
+//! those into an HTML page for browsers or an `application/problem+json` body
+//! for API clients, quoting the correlation ID given to every request by
+//! [`tag_request`].
+//!
+//! The correlation ID is the client's `X-Request-Id` when it sends a usable
+//! one, and is echoed in that header on every response. It is recorded on the
+//! request's tracing span, and jobs enqueued while handling the request carry
+//! it, see [`JobRequest::new`](crate::mpsc::jobs::JobRequest::new).
+
+use super::common::wants_json;
+use crate::auth::Sessions;
+use crate::config::AppConfig;
+use crate::content::templates::page::PageContext;
+use crate::content::templates::{Error403Template, Error404Template, ErrorTemplate, HtmlTemplate};
+use crate::error::{ErrorKind, ErrorReport};
+use async_trait::async_trait;
+use axum::extract::{Extension, FromRequestParts, Request};
+use axum::http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Identifies a request in logs, error responses and the jobs it enqueues.
+/// Handlers can extract it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorrelationId(pub String);
+
+impl CorrelationId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// The `X-Request-Id` a client or proxy sent, if it is short and free of
+    /// characters that could forge log lines.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let id = headers.get(&REQUEST_ID)?.to_str().ok()?;
+        let valid = !id.is_empty()
+            && id.len() <= 128
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+
+        valid.then(|| Self(id.to_string()))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CorrelationId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<CorrelationId>()
+            .cloned()
+            .or_else(|| RequestContext::current().map(|context| context.correlation_id))
+            .unwrap_or_default())
+    }
+}
+
+impl Default for CorrelationId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+tokio::task_local! {
+    static CURRENT_REQUEST: RequestContext;
+}
+
+/// What is known about the request being handled, for code that cannot see
+/// it, such as the panic handler.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub method: String,
+    pub path: String,
+    pub correlation_id: CorrelationId,
+    pub wants_json: bool,
+}
+
+impl RequestContext {
+    pub fn current() -> Option<RequestContext> {
+        CURRENT_REQUEST.try_with(|context| context.clone()).ok()
+    }
+}
+
+/// Give the request a correlation ID and make its [`RequestContext`] available
+/// to everything that handles it.
+pub async fn tag_request(mut request: Request, next: Next) -> Response {
+    let context = RequestContext {
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        correlation_id: CorrelationId::from_headers(request.headers()).unwrap_or_default(),
+        wants_json: wants_json(request.headers()),
+    };
+    request
+        .extensions_mut()
+        .insert(context.correlation_id.clone());
+    let value = HeaderValue::from_str(&context.correlation_id.0).ok();
+
+    let mut response = CURRENT_REQUEST.scope(context, next.run(request)).await;
+    if let Some(value) = value {
+        response.headers_mut().insert(REQUEST_ID.clone(), value);
+    }
+
+    response
+}
+
+/// The span `TraceLayer` logs a request in, recording its correlation ID.
+pub fn request_span<B>(request: &axum::http::Request<B>) -> tracing::Span {
+    let id = request
+        .extensions()
+        .get::<CorrelationId>()
+        .map(|id| id.0.as_str())
+        .unwrap_or_default();
+
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id = id,
+    )
+}
+
+pub async fn render_errors(
+    Extension(config): Extension<AppConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let correlation_id = request
+        .extensions()
+        .get::<CorrelationId>()
+        .cloned()
+        .unwrap_or_default();
+    let headers = request.headers().clone();
+    let path = request.uri().path().to_string();
+    let sessions = request.extensions().get::<Sessions>().cloned();
+
+    let response = next.run(request).await;
+    let Some(report) = response.extensions().get::<Arc<ErrorReport>>().cloned() else {
+        return response;
+    };
+
+    if report.status().is_server_error() {
+        tracing::error!("{} {} failed [{}]", report.status(), path, correlation_id);
+    }
+
+    let debug = config.server.debug_errors;
+    let mut rendered = if wants_json(&headers) {
+        report.response(report.problem(Some(&path), Some(&correlation_id.0), debug))
+    } else {
+        render_html(&report, &headers, sessions, &correlation_id, debug).await
+    };
+
+    // Keep headers the handler set, such as `Retry-After` or cookies.
+    let own: HashSet<HeaderName> = rendered.headers().keys().cloned().collect();
+    for (name, value) in response.headers() {
+        if name != header::CONTENT_LENGTH && !own.contains(name) {
+            rendered.headers_mut().append(name, value.clone());
+        }
+    }
+    rendered
+}
+
+async fn render_html(
+    report: &ErrorReport,
+    headers: &HeaderMap,
+    sessions: Option<Sessions>,
+    correlation_id: &CorrelationId,
+    debug: bool,
+) -> Response {
+    // The nav shows who is logged in; look them up only now that we need to.
+    let user = match sessions {
+        Some(sessions) => sessions
+            .user(&sessions.jar(headers))
+            .await
+            .unwrap_or_default(),
+        None => None,
+    };
+    let correlation_id = correlation_id.0.clone();
+    let context = PageContext::new("error").title(report.title()).noindex();
+
+    let page = match &report.kind {
+        ErrorKind::Forbidden => HtmlTemplate(Error403Template {
+            user,
+            page: context,
+            correlation_id,
+        })
+        .into_response(),
+        ErrorKind::NotFound => HtmlTemplate(Error404Template {
+            user,
+            page: context,
+            correlation_id,
+        })
+        .into_response(),
+        kind => HtmlTemplate(ErrorTemplate {
+            user,
+            page: context,
+            status: report.status().as_u16(),
+            title: report.title().to_string(),
+            detail: report.detail.clone(),
+            fields: match kind {
+                ErrorKind::Validation(fields) => fields.clone(),
+                _ => Vec::new(),
+            },
+            correlation_id,
+            chain: if debug {
+                report.chain.clone()
+            } else {
+                Vec::new()
+            },
+        })
+        .into_response(),
+    };
+
+    let mut page = (report.status(), page).into_response();
+    page.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    page.extensions_mut().insert(Arc::new(report.clone()));
+
+    page
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::PruneSessions;
+    use crate::error::{Error, FieldError};
+    use crate::mpsc::jobs::JobRequest;
+    use crate::mpsc::queue::{JobQueueExt, MemoryQueue, SharedJobQueue};
+    use axum::body::{self, Body};
+    use axum::http::StatusCode;
+    use axum::{routing::get, Router};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    fn app(debug: bool) -> Router {
+        let mut config = AppConfig::default();
+        config.server.debug_errors = debug;
+
+        Router::new()
+            .route(
+                "/invalid",
+                get(|| async {
+                    Err::<(), _>(Error::validation(vec![FieldError::new(
+                        "email",
+                        "is not an email address",
+                    )]))
+                }),
+            )
+            .route(
+                "/broken",
+                get(|| async {
+                    Err::<(), _>(Error::new(
+                        anyhow::anyhow!("disk on fire").context("saving"),
+                    ))
+                }),
+            )
+            .layer(axum::middleware::from_fn(render_errors))
+            .layer(Extension(config))
+            .layer(axum::middleware::from_fn(tag_request))
+    }
+
+    async fn get_json(app: Router, uri: &str) -> (StatusCode, HeaderMap, Value) {
+        let request = Request::get(uri)
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, headers, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn api_clients_get_problem_details() {
+        let (status, headers, problem) = get_json(app(false), "/invalid").await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/problem+json");
+        assert_eq!(problem["status"], 422);
+        assert_eq!(problem["instance"], "/invalid");
+        assert_eq!(problem["errors"][0]["field"], "email");
+        assert_eq!(
+            problem["correlation_id"],
+            headers[&REQUEST_ID].to_str().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn internal_details_need_debug_errors() {
+        let (status, _, problem) = get_json(app(false), "/broken").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!problem["detail"].as_str().unwrap().contains("saving"));
+        assert!(problem.get("chain").is_none());
+
+        let (_, _, problem) = get_json(app(true), "/broken").await;
+        assert_eq!(problem["chain"][0], "saving");
+        assert_eq!(problem["chain"][1], "disk on fire");
+    }
+
+    #[tokio::test]
+    async fn request_ids_reach_responses_and_jobs() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let queue: SharedJobQueue = Arc::new(MemoryQueue::new(tx));
+        let app = Router::new()
+            .route(
+                "/enqueue",
+                get(
+                    |id: CorrelationId, Extension(queue): Extension<SharedJobQueue>| async move {
+                        queue.enqueue::<PruneSessions>(&()).await.unwrap();
+                        id.0
+                    },
+                ),
+            )
+            .layer(Extension(queue))
+            .layer(axum::middleware::from_fn(tag_request));
+
+        let request = Request::get("/enqueue")
+            .header(&REQUEST_ID, "edge-42")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[&REQUEST_ID], "edge-42");
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "edge-42");
+        let job = JobRequest::from(rx.recv().await.unwrap());
+        assert_eq!(job.correlation_id.as_deref(), Some("edge-42"));
+
+        let request = Request::get("/enqueue")
+            .header(&REQUEST_ID, "forged id")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let generated = response.headers()[&REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(Uuid::parse_str(&generated).is_ok());
+        let job = JobRequest::from(rx.recv().await.unwrap());
+        assert_eq!(job.correlation_id, Some(generated));
+    }
+
+    #[tokio::test]
+    async fn browsers_get_html() {
+        let request = Request::get("/invalid")
+            .header(header::ACCEPT, "text/html,application/xhtml+xml")
+            .body(Body::empty())
+            .unwrap();
+        let response = app(false).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("is not an email address"));
+    }
+}
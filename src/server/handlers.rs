@@ -1,14 +1,27 @@
-use crate::content::templates::{AboutTemplate, HtmlTemplate, IndexTemplate};
+use crate::auth::MaybeUser;
+use crate::content::templates::page::PageContext;
+use crate::content::templates::{t, AboutTemplate, HtmlTemplate, IndexTemplate};
+use crate::error::Error;
 use axum::response::IntoResponse;
 
-pub async fn render_index() -> impl IntoResponse {
-    let template = IndexTemplate {};
+pub async fn render_index(MaybeUser(user): MaybeUser, page: PageContext) -> impl IntoResponse {
+    let page = page.route("index");
+    let template = IndexTemplate { user, page };
 
     HtmlTemplate(template)
 }
 
-pub async fn render_about() -> impl IntoResponse {
-    let template = AboutTemplate {};
+pub async fn render_about(MaybeUser(user): MaybeUser, page: PageContext) -> impl IntoResponse {
+    let page = page
+        .route("about")
+        .title(t("page-about"))
+        .breadcrumb(t("page-home"), "/")
+        .breadcrumb(t("page-about"), "/about");
+    let template = AboutTemplate { user, page };
 
     HtmlTemplate(template)
 }
+
+pub async fn not_found() -> Error {
+    Error::not_found("No page matches this URL")
+}
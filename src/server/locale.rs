@@ -0,0 +1,254 @@
+//! Chooses the locale of each request.
+//!
+//! In order of priority: a supported locale prefixing the path (`/fr/about`),
+//! the `nosferatu_locale` cookie, then the best `Accept-Language` match,
+//! falling back to `i18n.default_locale`. [`route_prefix`] strips the prefix
+//! before routing; [`negotiate`] settles on a [`Locale`], which handlers can
+//! extract and templates read through [`Locale::current`].
+
+use crate::config::AppConfig;
+use crate::content::templates::I18N_STATIC_CONTENT;
+use crate::content::translations::I18nConfig;
+use async_trait::async_trait;
+use axum::extract::{Extension, FromRequestParts, Request};
+use axum::http::{header, request::Parts, HeaderMap, HeaderValue, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_extra::extract::cookie::CookieJar;
+use std::convert::Infallible;
+use std::fmt;
+
+pub const LOCALE_COOKIE: &str = "nosferatu_locale";
+
+tokio::task_local! {
+    static CURRENT_LOCALE: Locale;
+}
+
+/// The locale chosen for the request being handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locale(pub String);
+
+impl Locale {
+    /// The current request's locale, if it passed through [`negotiate`].
+    pub fn current() -> Option<Locale> {
+        CURRENT_LOCALE.try_with(|locale| locale.clone()).ok()
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Falls back to the configured default locale on routes [`negotiate`] does
+/// not wrap.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Locale {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(locale) = parts.extensions.get::<Locale>() {
+            return Ok(locale.clone());
+        }
+
+        Ok(Locale(match parts.extensions.get::<AppConfig>() {
+            Some(config) => config.i18n.default_locale.clone(),
+            None => I18N_STATIC_CONTENT.load().default_locale().to_string(),
+        }))
+    }
+}
+
+/// A locale given as the first path segment, which [`route_prefix`] removed.
+#[derive(Debug, Clone)]
+struct PathLocale(String);
+
+/// Strip a supported locale from the front of the path, so that `/fr/about`
+/// routes like `/about`. Must wrap the router, since it changes the URI.
+pub fn route_prefix<B>(config: &I18nConfig, mut request: Request<B>) -> Request<B> {
+    if let Some((locale, uri)) = split_prefix(config, request.uri()) {
+        *request.uri_mut() = uri;
+        request.extensions_mut().insert(PathLocale(locale));
+    }
+
+    request
+}
+
+fn split_prefix(config: &I18nConfig, uri: &Uri) -> Option<(String, Uri)> {
+    let path = uri.path().strip_prefix('/')?;
+    let (segment, rest) = match path.split_once('/') {
+        Some((segment, rest)) => (segment, rest),
+        None => (path, ""),
+    };
+    let locale = supported(config, segment)?;
+
+    let stripped = match uri.query() {
+        Some(query) => format!("/{}?{}", rest, query),
+        None => format!("/{}", rest),
+    };
+
+    Some((locale, stripped.parse().ok()?))
+}
+
+/// Settle on the request's locale and make it available to handlers and
+/// templates.
+pub async fn negotiate(
+    Extension(config): Extension<AppConfig>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let locale = choose(
+        &config.i18n,
+        request.extensions().get::<PathLocale>(),
+        request.headers(),
+    );
+    request.extensions_mut().insert(locale.clone());
+
+    let mut response = CURRENT_LOCALE
+        .scope(locale.clone(), next.run(request))
+        .await;
+
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&locale.0) {
+        headers.insert(header::CONTENT_LANGUAGE, value);
+    }
+    headers.append(header::VARY, HeaderValue::from_static("accept-language"));
+
+    response
+}
+
+fn choose(config: &I18nConfig, path: Option<&PathLocale>, headers: &HeaderMap) -> Locale {
+    let from_cookie = || {
+        CookieJar::from_headers(headers)
+            .get(LOCALE_COOKIE)
+            .and_then(|cookie| supported(config, cookie.value()))
+    };
+    let from_header = || {
+        headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                accept_language(value)
+                    .into_iter()
+                    .find_map(|(tag, _)| supported(config, &tag))
+            })
+    };
+
+    Locale(
+        path.map(|path| path.0.clone())
+            .or_else(from_cookie)
+            .or_else(from_header)
+            .unwrap_or_else(|| config.default_locale.clone()),
+    )
+}
+
+/// The configured locale matching `tag`, ignoring case. A regional tag such as
+/// `fr-CA` also matches plain `fr`.
+fn supported(config: &I18nConfig, tag: &str) -> Option<String> {
+    let find = |tag: &str| {
+        config
+            .locales
+            .iter()
+            .find(|locale| locale.eq_ignore_ascii_case(tag))
+            .cloned()
+    };
+
+    find(tag).or_else(|| find(tag.split_once('-')?.0))
+}
+
+/// Language tags from an `Accept-Language` header, most preferred first.
+/// Tags with `q=0` or a malformed weight are dropped.
+pub fn accept_language(header: &str) -> Vec<(String, f32)> {
+    let mut tags: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty() && *tag != "*")?;
+            let weight = match parts.find_map(|param| param.strip_prefix("q=")) {
+                Some(weight) => weight.parse::<f32>().ok()?,
+                None => 1.0,
+            };
+
+            (weight > 0.0 && weight <= 1.0).then(|| (tag.to_string(), weight))
+        })
+        .collect();
+
+    // Stable, so equally weighted tags keep the client's order.
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::{self, Body};
+    use axum::{routing::get, Router};
+    use tower::util::MapRequestLayer;
+    use tower::{Layer, ServiceExt};
+
+    #[test]
+    fn orders_accept_language_by_weight() {
+        let tags =
+            accept_language("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.7, *;q=0.5, es;q=0, x;q=oops");
+        let tags: Vec<&str> = tags.iter().map(|(tag, _)| tag.as_str()).collect();
+
+        assert_eq!(tags, vec!["fr-CH", "fr", "en", "de"]);
+        assert_eq!(accept_language("en;q=0.2, pt-BR")[0].0, "pt-BR");
+    }
+
+    async fn locale_for(uri: &str, cookie: Option<&str>, accept: Option<&str>) -> String {
+        let mut config = AppConfig::default();
+        config.i18n.locales = vec!["en".to_string(), "fr".to_string(), "pt-BR".to_string()];
+
+        let router = Router::new()
+            .route("/about", get(|locale: Locale| async move { locale.0 }))
+            .layer(axum::middleware::from_fn(negotiate))
+            .layer(Extension(config.clone()));
+        let app =
+            MapRequestLayer::new(move |request| route_prefix(&config.i18n, request)).layer(router);
+
+        let mut request = Request::get(uri);
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, format!("{}={}", LOCALE_COOKIE, cookie));
+        }
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT_LANGUAGE, accept);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = body::to_bytes(response.into_body(), 64).await.unwrap();
+
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn prefix_then_cookie_then_header() {
+        assert_eq!(locale_for("/fr/about", Some("en"), Some("pt")).await, "fr");
+        assert_eq!(locale_for("/about", Some("FR"), Some("en")).await, "fr");
+        assert_eq!(
+            locale_for("/about", Some("xx"), Some("de, pt-br;q=0.5")).await,
+            "pt-BR"
+        );
+        assert_eq!(
+            locale_for("/about", None, Some("fr-CA;q=0.9, en")).await,
+            "en"
+        );
+        assert_eq!(locale_for("/about", None, None).await, "en");
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_configured_default() {
+        let mut config = AppConfig::default();
+        config.i18n.default_locale = "fr".to_string();
+        let app = Router::new()
+            .route("/about", get(|locale: Locale| async move { locale.0 }))
+            .layer(Extension(config));
+
+        let request = Request::get("/about").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = body::to_bytes(response.into_body(), 64).await.unwrap();
+        assert_eq!(body, "fr");
+    }
+}
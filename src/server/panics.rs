@@ -0,0 +1,208 @@
+//! Turns panics in handlers into incidents.
+//!
+//! A panic hook, chained in front of color-eyre's, notes where the panic
+//! happened and captures a backtrace. [`PanicLayerResponse`] then pairs that
+//! with the request, saves it to the `incidents` table and answers with a 500
+//! quoting the incident ID.
+
+use super::errors::RequestContext;
+use crate::content::templates::panic_error_template;
+use crate::error::PROBLEM_JSON;
+use crate::models::incidents::{self, Incident};
+use crate::mpsc::jobs::panic_message;
+use axum::http::{header, Response, StatusCode};
+use serde_json::json;
+use sqlx::PgPool;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Default)]
+struct PanicDetails {
+    location: Option<String>,
+    backtrace: String,
+}
+
+thread_local! {
+    // Read back by the catching layer, which unwinds on the thread that panicked.
+    static LAST_PANIC: RefCell<Option<PanicDetails>> = const { RefCell::new(None) };
+}
+
+/// Record the location and backtrace of every panic, then run the previous
+/// hook. Call once, after `color_eyre::install`.
+pub fn install_hook() {
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        let details = PanicDetails {
+            location: info.location().map(|location| location.to_string()),
+            backtrace: Backtrace::force_capture().to_string(),
+        };
+        LAST_PANIC.with(|last| *last.borrow_mut() = Some(details));
+
+        previous(info);
+    }));
+}
+
+#[derive(Clone)]
+pub struct PanicLayerResponse {
+    pool: Option<PgPool>,
+}
+
+impl PanicLayerResponse {
+    /// Without a `pool`, incidents are only logged.
+    pub fn new(pool: Option<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    /// The hook hands over the panic's location and backtrace through a
+    /// thread-local, which works because `CatchPanic` catches a handler's
+    /// panic on the thread it unwinds on. A panic raised on another thread and
+    /// resumed in the handler, e.g. from a `JoinError` of a spawned task, is
+    /// recorded with an "unknown location" and an empty backtrace instead.
+    fn incident(&self, err: &(dyn Any + Send)) -> (Incident, Option<RequestContext>) {
+        let details = LAST_PANIC
+            .with(|last| last.borrow_mut().take())
+            .unwrap_or_default();
+        let context = RequestContext::current();
+
+        let incident = Incident {
+            incident_id: Uuid::new_v4(),
+            message: panic_message(err),
+            location: details.location,
+            backtrace: details.backtrace,
+            method: context
+                .as_ref()
+                .map(|context| context.method.clone())
+                .unwrap_or_default(),
+            path: context
+                .as_ref()
+                .map(|context| context.path.clone())
+                .unwrap_or_default(),
+            correlation_id: context
+                .as_ref()
+                .map(|context| context.correlation_id.0.clone())
+                .unwrap_or_default(),
+        };
+
+        (incident, context)
+    }
+}
+
+impl tower_http::catch_panic::ResponseForPanic for PanicLayerResponse {
+    type ResponseBody = String;
+
+    fn response_for_panic(
+        &mut self,
+        err: Box<dyn Any + Send + 'static>,
+    ) -> Response<Self::ResponseBody> {
+        let (incident, context) = self.incident(&*err);
+        tracing::error!(
+            "Incident {} [{}]: {} {} panicked at {}: {}",
+            incident.incident_id,
+            incident.correlation_id,
+            incident.method,
+            incident.path,
+            incident.location.as_deref().unwrap_or("unknown location"),
+            incident.message
+        );
+
+        let incident_id = incident.incident_id;
+        if let Some(pool) = self.pool.clone() {
+            tokio::spawn(async move {
+                if let Err(err) = incidents::insert(&pool, &incident).await {
+                    tracing::error!("Unable to save incident {}: {}", incident.incident_id, err);
+                }
+            });
+        }
+
+        let (content_type, body) = match context {
+            Some(context) if context.wants_json => {
+                let problem = json!({
+                    "type": "about:blank",
+                    "title": "Internal Server Error",
+                    "status": 500,
+                    "detail": "The server was unable to complete your request.",
+                    "instance": context.path,
+                    "correlation_id": context.correlation_id.0,
+                    "incident_id": incident_id,
+                });
+                (PROBLEM_JSON, problem.to_string())
+            }
+            _ => (
+                "text/html; charset=utf-8",
+                panic_error_template(&incident_id.to_string()),
+            ),
+        };
+
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body)
+            .expect("Unable to build the panic response!")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::errors::tag_request;
+    use axum::body::{self, Body};
+    use axum::extract::Request;
+    use axum::{routing::get, Router};
+    use serde_json::Value;
+    use tower::ServiceExt;
+    use tower_http::catch_panic::CatchPanicLayer;
+
+    async fn boom() -> &'static str {
+        panic!("kaboom")
+    }
+
+    #[tokio::test]
+    async fn panics_become_incidents() {
+        install_hook();
+        let app = Router::new()
+            .route("/boom", get(boom))
+            .layer(CatchPanicLayer::custom(PanicLayerResponse::new(None)))
+            .layer(axum::middleware::from_fn(tag_request));
+
+        let layer = PanicLayerResponse::new(None);
+        let result = std::panic::catch_unwind(|| panic!("direct {}", 1));
+        let (incident, _) = layer.incident(&*result.unwrap_err());
+        assert_eq!(incident.message, "direct 1");
+        assert!(incident.location.unwrap().contains("panics.rs"));
+
+        let request = Request::get("/boom")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["instance"], "/boom");
+        assert!(problem["incident_id"]
+            .as_str()
+            .unwrap()
+            .parse::<Uuid>()
+            .is_ok());
+    }
+
+    #[test]
+    fn panics_from_other_threads_have_no_details() {
+        install_hook();
+        let layer = PanicLayerResponse::new(None);
+        let err = std::thread::spawn(|| panic!("elsewhere"))
+            .join()
+            .unwrap_err();
+
+        let (incident, _) = layer.incident(&*err);
+        assert_eq!(incident.message, "elsewhere");
+        assert_eq!(incident.location, None);
+        assert!(incident.backtrace.is_empty());
+    }
+}
@@ -1,45 +1,241 @@
+//! The barebones server exposing the asset directory under `/public`. With
+//! `server.single_port` the main app serves [`assets`] itself instead.
+//!
+//! Fingerprinted URLs from the [asset manifest](crate::content::assets) are
+//! served as the file they name with an immutable, year-long `Cache-Control`,
+//! as long as that file is unchanged since it was hashed. Plain URLs, and
+//! fingerprinted ones whose file has changed, must be revalidated against an
+//! `ETag` from the manifest or the `Last-Modified` time. Clients accepting
+//! brotli or gzip get the `.br` or `.gz` sidecar written by `compress-assets`,
+//! when there is one; the `ETag` is weak since every encoding shares it.
+
 use super::common;
+use crate::content::assets::{AssetConfig, AssetManifest, ASSETS};
+use crate::error::Error;
+use axum::extract::{Request, State};
 use axum::handler::HandlerWithoutStateExt;
+use axum::http::{header, HeaderValue};
+use axum::middleware::{self, Next};
+use axum_server::Handle;
 use nosferatu::prelude::axum_prelude::*;
 use nosferatu::prelude::*;
-use std::net::IpAddr;
-use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tower::ServiceBuilder;
 use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
 
-pub fn public_dir() -> Router {
+const IMMUTABLE: HeaderValue = HeaderValue::from_static("public, max-age=31536000, immutable");
+const REVALIDATE: HeaderValue = HeaderValue::from_static("no-cache");
+const ACCEPT_ENCODING: HeaderValue = HeaderValue::from_static("accept-encoding");
+
+/// The barebones server's routes: the asset directory under `/public` and a
+/// health check.
+pub fn public_dir(config: &AssetConfig) -> Router {
+    async fn handle_400() -> (StatusCode, &'static str) {
+        (StatusCode::BAD_REQUEST, "")
+    }
+
+    assets(config)
+        .route("/health", get(common::handle_health_get))
+        .fallback_service(handle_400.into_service())
+}
+
+/// Just the asset directory under `/public`, for the main app to nest in
+/// single-port mode.
+pub fn assets(config: &AssetConfig) -> Router {
+    serve_assets(config, None)
+}
+
+/// Where [`cache_headers`] finds the files and the manifest describing them.
+#[derive(Clone)]
+struct AssetFiles {
+    dir: PathBuf,
+    /// The installed [`ASSETS`] when `None`.
+    manifest: Option<Arc<AssetManifest>>,
+}
+
+impl AssetFiles {
+    fn manifest(&self) -> Arc<AssetManifest> {
+        self.manifest.clone().unwrap_or_else(|| ASSETS.load_full())
+    }
+}
+
+fn serve_assets(config: &AssetConfig, manifest: Option<Arc<AssetManifest>>) -> Router {
     async fn handle_404() -> (StatusCode, &'static str) {
         (StatusCode::NOT_FOUND, "Not found")
     }
     let service_404 = handle_404.into_service();
 
-    async fn handle_400() -> (StatusCode, &'static str) {
-        (StatusCode::BAD_REQUEST, "")
+    let path = std::env::current_dir().expect("Unable to get current dir!");
+    let files = AssetFiles {
+        dir: path.join(&config.dir),
+        manifest,
+    };
+
+    let serve_dir = ServiceBuilder::new()
+        .layer(middleware::from_fn_with_state(files.clone(), cache_headers))
+        .service(
+            ServeDir::new(&files.dir)
+                .precompressed_br()
+                .precompressed_gzip()
+                .not_found_service(service_404),
+        );
+
+    Router::new().nest_service("/public", serve_dir)
+}
+
+/// Resolve fingerprinted paths and set caching headers for files in the
+/// manifest.
+async fn cache_headers(
+    State(files): State<AssetFiles>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let (dir, manifest) = (&files.dir, files.manifest());
+    let mut path = request.uri().path().trim_start_matches('/').to_string();
+
+    if let Some(asset) = manifest.by_fingerprint(&path) {
+        if let Ok(uri) = format!("/{}", asset.path).parse() {
+            *request.uri_mut() = uri;
+        }
+
+        if asset.is_current(dir) {
+            let etag = asset.etag.clone();
+            let mut response = next.run(request).await;
+            if response.status().is_success() {
+                let headers = response.headers_mut();
+                headers.insert(header::CACHE_CONTROL, IMMUTABLE);
+                headers.append(header::VARY, ACCEPT_ENCODING);
+                if let Ok(etag) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                    headers.insert(header::ETAG, etag);
+                }
+            }
+            return response;
+        }
+
+        // The file changed since it was hashed, so the URL no longer names
+        // its content: serve it like the plain URL.
+        path = asset.path.clone();
     }
 
-    let path = std::env::current_dir().expect("Unable to get current dir!");
-    let public_dir = path.join("public/");
+    // A file changed since startup keeps only `Last-Modified`, which
+    // `ServeDir` derives from the file itself.
+    let etag = manifest
+        .get(&path)
+        .filter(|asset| asset.is_current(dir))
+        .map(|asset| asset.etag.clone());
+    drop(manifest);
 
-    let serve_dir = ServeDir::new(public_dir).not_found_service(service_404);
+    let not_modified = etag.as_ref().is_some_and(|etag| {
+        request
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value.split(',').any(|tag| {
+                    let tag = tag.trim();
+                    tag == "*" || tag.trim_start_matches("W/") == etag
+                })
+            })
+    });
 
-    Router::new()
-        .route("/health", get(common::handle_health_get))
-        .nest_service("/public", serve_dir)
-        .fallback_service(handle_400.into_service())
+    let mut response = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        next.run(request).await
+    };
+    if response.status().is_success() || not_modified {
+        let headers = response.headers_mut();
+        headers.insert(header::CACHE_CONTROL, REVALIDATE);
+        headers.append(header::VARY, ACCEPT_ENCODING);
+        if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok())
+        {
+            headers.insert(header::ETAG, etag);
+        }
+    }
+
+    response
 }
 
-pub async fn serve_barebones(app: Router, addr: common::NetworkAddr<'_>) {
-    let ip: IpAddr = addr.host().parse().unwrap();
-    let addr = SocketAddr::from((ip, addr.port()));
+/// Serve `app` until `handle` is told to shut down.
+pub async fn serve_barebones(
+    app: Router,
+    addr: common::NetworkAddr<'_>,
+    handle: Handle,
+) -> Result<(), Error> {
+    let listener = addr.bind()?;
     logger::log(
         logger::Level::Info,
         logger::Color(utils::YELLOW),
         logger::Tag("[ OK ]"),
-        logger::Text(format!("Listening on {:?} exposing ./public", &addr).as_str()),
+        logger::Text(format!("Listening on {} exposing ./public", addr).as_str()),
     );
 
-    axum_server::bind(addr)
+    axum_server::from_tcp(listener)
+        .handle(handle)
         .serve(app.layer(TraceLayer::new_for_http()).into_make_service())
-        .await
-        .unwrap();
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn caches_fingerprinted_assets_forever() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("app.js"), "run()").unwrap();
+        let config = AssetConfig {
+            dir: dir.path().to_path_buf(),
+            base_url: "/public".to_string(),
+        };
+        // Passed in rather than installed, which would race other tests.
+        let manifest = Arc::new(AssetManifest::build(&config).unwrap());
+        let asset = manifest.get("app.js").unwrap().clone();
+        let app = serve_assets(&config, Some(manifest));
+
+        let get = |uri: &str, etag: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get(&format!("/public/{}", asset.fingerprinted), None)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], IMMUTABLE);
+
+        let response = get("/public/app.js", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], REVALIDATE);
+        assert_eq!(
+            response.headers()[header::ETAG],
+            format!("W/{}", asset.etag).as_str()
+        );
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+
+        let response = get("/public/app.js", Some(&format!("W/{}", asset.etag)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Rebuilt since startup: the old hash must not be cached forever.
+        std::fs::write(dir.path().join("app.js"), "run(again)").unwrap();
+        let response = get(&format!("/public/{}", asset.fingerprinted), None)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], REVALIDATE);
+        assert!(!response.headers().contains_key(header::ETAG));
+    }
 }
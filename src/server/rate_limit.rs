@@ -0,0 +1,604 @@
+//! Rate limiting and load shedding.
+//!
+//! Each request is matched against `rate_limit.policies` in order, and the
+//! first policy matching its path and method counts it, per client IP or per
+//! logged in user. Counting uses GCRA: a policy of `limit` requests per
+//! `period` seconds lets one through every `period / limit` seconds, with up
+//! to `burst` at once. Refused requests get a 429 with `Retry-After`.
+//!
+//! State lives in memory, which suits a single node, or in the `rate_limits`
+//! table when several nodes must share it. IPv6 clients are counted per /64,
+//! the block a single host is usually given.
+//!
+//! Separately, requests beyond `rate_limit.max_in_flight` at a time are shed
+//! with a 503 instead of queueing.
+
+use crate::auth::MaybeUser;
+use crate::config::layers::FromConfigValue;
+use crate::error::Error;
+use crate::models::rate_limits;
+use crate::mpsc::jobs::{Job, JobContext};
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::{request::Parts, Method};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use memory::MemoryStore;
+use serde_json::Value;
+use sqlx::PgPool;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod memory;
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub store: RateLimitStore,
+    /// Proxies whose `X-Forwarded-For` is believed.
+    pub trusted_proxies: Vec<IpNetwork>,
+    pub policies: RateLimitPolicies,
+    /// Requests handled at once before more are shed; 0 for no limit.
+    pub max_in_flight: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            store: RateLimitStore::Memory,
+            trusted_proxies: vec![
+                IpNetwork::from_text("127.0.0.1").expect("The address is valid"),
+                IpNetwork::from_text("::1").expect("The address is valid"),
+            ],
+            policies: RateLimitPolicies::default(),
+            max_in_flight: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitStore {
+    /// Per process; each node counts on its own.
+    Memory,
+    /// The `rate_limits` table, shared by every node.
+    Postgres,
+}
+
+impl FromConfigValue for RateLimitStore {
+    fn from_text(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "memory" => Ok(RateLimitStore::Memory),
+            "postgres" => Ok(RateLimitStore::Postgres),
+            other => Err(format!(
+                "expected \"memory\" or \"postgres\", got {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// An address or a CIDR block, such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromConfigValue for IpNetwork {
+    fn from_text(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|err| format!("invalid address {:?}: {}", text, err))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", text))?,
+            None => max,
+        };
+
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+/// Who a policy counts requests for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKey {
+    Ip,
+    /// The logged in user, or the IP for anonymous requests.
+    User,
+}
+
+impl FromConfigValue for RateLimitKey {
+    fn from_text(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "ip" => Ok(RateLimitKey::Ip),
+            "user" => Ok(RateLimitKey::User),
+            other => Err(format!("expected \"ip\" or \"user\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitPolicy {
+    pub name: String,
+    /// Matches this path and everything below it.
+    pub path: String,
+    /// Empty for every method.
+    pub methods: Vec<Method>,
+    pub key: RateLimitKey,
+    pub limit: u32,
+    /// Seconds.
+    pub period: u64,
+    /// Requests allowed at once; defaults to `limit`.
+    pub burst: u32,
+}
+
+impl RateLimitPolicy {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let prefix = self.path.trim_end_matches('/');
+        let under = match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+            None => false,
+        };
+
+        under && (self.methods.is_empty() || self.methods.contains(method))
+    }
+
+    /// Seconds between requests at the sustained rate.
+    fn interval(&self) -> f64 {
+        self.period as f64 / f64::from(self.limit.max(1))
+    }
+}
+
+/// Policies are an array of tables in the config file, e.g.
+///
+/// ```toml
+/// [[rate_limit.policies]]
+/// name = "login"
+/// path = "/login"
+/// methods = ["POST"]
+/// key = "ip"
+/// limit = 5
+/// period = 60
+/// ```
+///
+/// and `path=limit/period` pairs, counted per IP, elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitPolicies(pub Vec<RateLimitPolicy>);
+
+impl Default for RateLimitPolicies {
+    fn default() -> Self {
+        Self(vec![
+            RateLimitPolicy {
+                name: "login".to_string(),
+                path: "/login".to_string(),
+                methods: vec![Method::POST],
+                key: RateLimitKey::Ip,
+                limit: 5,
+                period: 60,
+                burst: 5,
+            },
+            // Anyone can post reports, and each is written to Postgres.
+            RateLimitPolicy {
+                name: "csp-report".to_string(),
+                path: super::security::REPORT_PATH.to_string(),
+                methods: vec![Method::POST],
+                key: RateLimitKey::Ip,
+                limit: 10,
+                period: 60,
+                burst: 10,
+            },
+            RateLimitPolicy {
+                name: "pages".to_string(),
+                path: "/".to_string(),
+                methods: Vec::new(),
+                key: RateLimitKey::User,
+                limit: 600,
+                period: 60,
+                burst: 100,
+            },
+        ])
+    }
+}
+
+impl FromConfigValue for RateLimitPolicies {
+    fn from_text(text: &str) -> Result<Self, String> {
+        text.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let parsed = entry.split_once('=').and_then(|(path, rate)| {
+                    let (limit, period) = rate.split_once('/')?;
+                    Some((
+                        path.trim(),
+                        limit.trim().parse().ok().filter(|limit: &u32| *limit > 0)?,
+                        period.trim().parse().ok()?,
+                    ))
+                });
+                let (path, limit, period) =
+                    parsed.ok_or_else(|| format!("expected path=limit/period, got {:?}", entry))?;
+
+                Ok(RateLimitPolicy {
+                    name: path.to_string(),
+                    path: path.to_string(),
+                    methods: Vec::new(),
+                    key: RateLimitKey::Ip,
+                    limit,
+                    period,
+                    burst: limit,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(RateLimitPolicies)
+    }
+
+    fn from_toml(value: &toml::Value) -> Result<Self, String> {
+        let toml::Value::Array(items) = value else {
+            return Self::from_text(value.as_str().unwrap_or_default());
+        };
+
+        let mut policies = Vec::new();
+        for item in items {
+            let path = item
+                .get("path")
+                .and_then(toml::Value::as_str)
+                .ok_or("every policy needs a `path`")?;
+            let field = |key: &str| item.get(key);
+            let number = |key: &str| -> Result<Option<u64>, String> {
+                field(key)
+                    .map(|value| {
+                        u64::from_toml(value).map_err(|err| format!("policy {:?}: {}", path, err))
+                    })
+                    .transpose()
+            };
+            let limit =
+                number("limit")?.ok_or_else(|| format!("policy {:?} needs a `limit`", path))?;
+            let limit = u32::try_from(limit).map_err(|err| err.to_string())?;
+            if limit == 0 {
+                return Err(format!("policy {:?} needs a `limit` above 0", path));
+            }
+
+            policies.push(RateLimitPolicy {
+                name: field("name")
+                    .and_then(toml::Value::as_str)
+                    .unwrap_or(path)
+                    .to_string(),
+                path: path.to_string(),
+                methods: field("methods")
+                    .map(Vec::<Method>::from_toml)
+                    .transpose()?
+                    .unwrap_or_default(),
+                key: field("key")
+                    .map(RateLimitKey::from_toml)
+                    .transpose()?
+                    .unwrap_or(RateLimitKey::Ip),
+                limit,
+                period: number("period")?.unwrap_or(60).max(1),
+                burst: match number("burst")? {
+                    Some(burst) => u32::try_from(burst).map_err(|err| err.to_string())?.max(1),
+                    None => limit,
+                },
+            });
+        }
+
+        Ok(RateLimitPolicies(policies))
+    }
+}
+
+/// The address of the client, after trusted proxies; available to handlers
+/// as an extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// The address the request came from. The peer's `X-Forwarded-For` is used
+/// when the peer is a trusted proxy, walking it back to the first address
+/// that is not; without a known peer the address is unspecified.
+pub fn client_ip(parts: &Parts, trusted_proxies: &[IpNetwork]) -> IpAddr {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+    let Some(mut ip) = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical())
+    else {
+        return IpAddr::from([0, 0, 0, 0]);
+    };
+
+    let forwarded = parts
+        .headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().parse::<IpAddr>().map(|hop| hop.to_canonical()))
+        .collect::<Vec<_>>();
+    for hop in forwarded.into_iter().rev() {
+        if !trusted(ip) {
+            break;
+        }
+        match hop {
+            Ok(hop) => ip = hop,
+            // A garbled hop cannot be trusted further back.
+            Err(_) => break,
+        }
+    }
+
+    ip
+}
+
+/// What requests from `ip` are counted under: the address itself for IPv4,
+/// and its /64 for IPv6, since one host can pick any address in it.
+fn client_block(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let block = Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64));
+            format!("{}/64", block)
+        }
+    }
+}
+
+/// Decide whether another request fits under the GCRA limit, given the
+/// theoretical arrival time `tat` of the next one. Returns the new `tat`, or
+/// how long to wait.
+fn gcra(
+    tat: Option<Instant>,
+    now: Instant,
+    interval: Duration,
+    burst: u32,
+) -> Result<Instant, Duration> {
+    let tat = tat.map_or(now, |tat| tat.max(now));
+    let allowed_until = now + interval * burst.saturating_sub(1);
+    if tat <= allowed_until {
+        Ok(tat + interval)
+    } else {
+        Err(tat - allowed_until)
+    }
+}
+
+/// The policies and the state counting requests against them.
+pub struct RateLimiter {
+    enabled: bool,
+    policies: Vec<RateLimitPolicy>,
+    trusted_proxies: Vec<IpNetwork>,
+    /// `Some` for the Postgres store.
+    pool: Option<PgPool>,
+    memory: MemoryStore,
+}
+
+impl RateLimiter {
+    /// Without a pool the Postgres store falls back to memory.
+    pub fn new(config: &RateLimitConfig, pool: Option<PgPool>) -> Self {
+        let pool = match config.store {
+            RateLimitStore::Postgres if pool.is_none() => {
+                tracing::warn!("Postgres is not configured; rate limits are kept in memory");
+                None
+            }
+            RateLimitStore::Postgres => pool,
+            RateLimitStore::Memory => None,
+        };
+
+        Self {
+            enabled: config.enabled,
+            policies: config.policies.0.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            pool,
+            memory: MemoryStore::new(memory::MAX_KEYS),
+        }
+    }
+
+    fn policy(&self, method: &Method, path: &str) -> Option<&RateLimitPolicy> {
+        self.policies
+            .iter()
+            .find(|policy| policy.matches(method, path))
+    }
+
+    /// Count a request against `key`; `Err` with the time to wait when over.
+    async fn take(
+        &self,
+        key: &str,
+        policy: &RateLimitPolicy,
+    ) -> Result<Result<(), Duration>, Error> {
+        if let Some(pool) = &self.pool {
+            let wait = rate_limits::take(pool, key, policy.interval(), policy.burst).await?;
+            return Ok(wait.map_or(Ok(()), |wait| Err(Duration::from_secs_f64(wait))));
+        }
+
+        let interval = Duration::from_secs_f64(policy.interval());
+        Ok(self.memory.take(key, interval, policy.burst))
+    }
+}
+
+/// Middleware counting each request against the first matching policy. The
+/// limiter failing lets requests through rather than taking the site down.
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let ip = client_ip(&parts, &limiter.trusted_proxies);
+    parts.extensions.insert(ClientIp(ip));
+
+    let policy = match limiter.enabled {
+        true => limiter.policy(&parts.method, parts.uri.path()),
+        false => None,
+    };
+    let Some(policy) = policy else {
+        return next.run(Request::from_parts(parts, body)).await;
+    };
+
+    let subject = match policy.key {
+        // The session is kept in the request's extensions, so the layers and
+        // handlers after this one reuse it rather than looking it up again.
+        RateLimitKey::User => match MaybeUser::from_request_parts(&mut parts, &()).await {
+            Ok(MaybeUser(Some(user))) => format!("user:{}", user.user_id),
+            _ => format!("ip:{}", client_block(ip)),
+        },
+        RateLimitKey::Ip => format!("ip:{}", client_block(ip)),
+    };
+    let key = format!("{}:{}", policy.name, subject);
+
+    match limiter.take(&key, policy).await {
+        Ok(Ok(())) => {}
+        Ok(Err(wait)) => {
+            tracing::warn!(
+                "Rate limited {} {} for {} by policy {}",
+                parts.method,
+                parts.uri.path(),
+                subject,
+                policy.name
+            );
+            return Error::rate_limited(Some(wait)).into_response();
+        }
+        Err(err) => tracing::error!("Rate limiter failed, letting {} through: {}", key, err),
+    }
+
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Delete refilled rate limit buckets from Postgres; schedule it as a
+/// recurring task when using the Postgres store.
+pub struct PruneRateLimits;
+
+#[async_trait]
+impl Job for PruneRateLimits {
+    const NAME: &'static str = "prune_rate_limits";
+    type Payload = ();
+
+    async fn run(&self, ctx: &JobContext, _payload: ()) -> Result<Value, Error> {
+        let pool = ctx
+            .config
+            .pg_pool
+            .as_ref()
+            .ok_or_else(|| Error::new("Postgres is not configured"))?;
+        let deleted = rate_limits::delete_expired(pool).await?;
+
+        Ok(Value::from(deleted))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{header, StatusCode};
+    use axum::{routing::get, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn gcra_allows_bursts_then_the_sustained_rate() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(10);
+
+        let mut tat = None;
+        for _ in 0..3 {
+            tat = Some(gcra(tat, start, interval, 3).unwrap());
+        }
+        assert_eq!(gcra(tat, start, interval, 3), Err(interval));
+
+        let later = start + Duration::from_secs(4);
+        assert_eq!(gcra(tat, later, interval, 3), Err(Duration::from_secs(6)));
+        let later = start + interval;
+        assert!(gcra(tat, later, interval, 3).is_ok());
+    }
+
+    #[test]
+    fn trusts_forwarded_for_only_from_proxies() {
+        let proxies = vec![
+            IpNetwork::from_text("10.0.0.0/8").unwrap(),
+            IpNetwork::from_text("::1").unwrap(),
+        ];
+        let parts = |peer: &str, forwarded: &str| {
+            let (mut parts, _) = Request::get("/")
+                .header("x-forwarded-for", forwarded)
+                .body(())
+                .unwrap()
+                .into_parts();
+            parts
+                .extensions
+                .insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+            parts
+        };
+        let ip = |peer, forwarded| client_ip(&parts(peer, forwarded), &proxies).to_string();
+
+        assert_eq!(ip("10.1.2.3:80", "203.0.113.9, 10.0.0.7"), "203.0.113.9");
+        assert_eq!(ip("[::1]:80", "198.51.100.1, 203.0.113.9"), "203.0.113.9");
+        assert_eq!(ip("192.0.2.1:80", "203.0.113.9"), "192.0.2.1");
+        assert_eq!(ip("10.1.2.3:80", "garbage, 10.0.0.7"), "10.0.0.7");
+    }
+
+    #[test]
+    fn counts_ipv6_clients_per_64() {
+        let block = |ip: &str| client_block(ip.parse().unwrap());
+
+        assert_eq!(block("203.0.113.9"), "203.0.113.9");
+        assert_eq!(block("2001:db8:1:2:aaaa::1"), "2001:db8:1:2::/64");
+        assert_eq!(block("2001:db8:1:2:ffff::9"), block("2001:db8:1:2::1"));
+        assert_ne!(block("2001:db8:1:3::1"), block("2001:db8:1:2::1"));
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_over_the_policy() {
+        let config = RateLimitConfig {
+            policies: RateLimitPolicies::from_text("/login=2/60").unwrap(),
+            ..RateLimitConfig::default()
+        };
+        let app = Router::new()
+            .route("/login", get(|| async { "login" }))
+            .route("/about", get(|| async { "about" }))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(RateLimiter::new(&config, None)),
+                limit,
+            ));
+        let status = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                (
+                    response.status(),
+                    response.headers().get(header::RETRY_AFTER).cloned(),
+                )
+            }
+        };
+
+        assert_eq!(status("/login").await.0, StatusCode::OK);
+        assert_eq!(status("/login").await.0, StatusCode::OK);
+        let (refused, retry_after) = status("/login").await;
+        assert_eq!(refused, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(retry_after.unwrap(), "30");
+        assert_eq!(status("/about").await.0, StatusCode::OK);
+    }
+}
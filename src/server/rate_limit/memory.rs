@@ -0,0 +1,157 @@
+//! The in-memory store: GCRA state per key, split over shards so requests
+//! for different keys rarely wait on the same lock.
+//!
+//! Each shard holds a bounded number of keys. Touching a shard first drops a
+//! few of its least recently used keys whose bucket has refilled, so expired
+//! keys are swept a little at a time rather than all at once; a shard that is
+//! still full evicts its least recently used key, which then starts over with
+//! a full bucket.
+
+use super::gcra;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keys kept in memory, across every shard.
+pub const MAX_KEYS: usize = 100_000;
+
+const SHARDS: usize = 16;
+
+/// Refilled keys swept from a shard each time it is used.
+const SWEEP_STEP: usize = 4;
+
+pub struct MemoryStore {
+    shards: [Mutex<Shard>; SHARDS],
+    hasher: RandomState,
+    /// Keys each shard may hold.
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct Shard {
+    /// Each key's theoretical arrival time, and its place in `recent`.
+    tats: HashMap<String, (Instant, u64)>,
+    /// Keys by last use, least recent first.
+    recent: BTreeMap<u64, String>,
+    /// Counts uses, to order `recent`.
+    clock: u64,
+}
+
+impl MemoryStore {
+    /// A store of at most `max_keys` keys.
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Mutex::new(Shard::default())),
+            hasher: RandomState::new(),
+            capacity: max_keys.div_ceil(SHARDS).max(1),
+        }
+    }
+
+    /// Count a request against `key`; `Err` with the time to wait when over.
+    pub fn take(&self, key: &str, interval: Duration, burst: u32) -> Result<(), Duration> {
+        let shard = &self.shards[self.hasher.hash_one(key) as usize % SHARDS];
+        let mut shard = shard.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        shard.sweep(now);
+
+        let tat = gcra(
+            shard.tats.get(key).map(|(tat, _)| *tat),
+            now,
+            interval,
+            burst,
+        )?;
+        shard.touch(key, tat);
+        while shard.tats.len() > self.capacity {
+            shard.evict();
+        }
+
+        Ok(())
+    }
+
+    /// Keys held, across every shard.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .tats
+                    .len()
+            })
+            .sum()
+    }
+}
+
+impl Shard {
+    fn touch(&mut self, key: &str, tat: Instant) {
+        self.clock += 1;
+        if let Some((_, used)) = self.tats.insert(key.to_string(), (tat, self.clock)) {
+            self.recent.remove(&used);
+        }
+        self.recent.insert(self.clock, key.to_string());
+    }
+
+    /// Drop up to [`SWEEP_STEP`] of the least recently used keys, stopping at
+    /// the first whose bucket has not refilled yet.
+    fn sweep(&mut self, now: Instant) {
+        for _ in 0..SWEEP_STEP {
+            let Some(entry) = self.recent.first_entry() else {
+                return;
+            };
+            if self
+                .tats
+                .get(entry.get())
+                .is_some_and(|(tat, _)| *tat > now)
+            {
+                return;
+            }
+            let key = entry.remove();
+            self.tats.remove(&key);
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.recent.pop_first() {
+            self.tats.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn holds_at_most_max_keys() {
+        let store = MemoryStore::new(SHARDS * 2);
+        let interval = Duration::from_secs(60);
+        for n in 0..1_000 {
+            assert!(store.take(&format!("ip:{}", n), interval, 1).is_ok());
+        }
+        assert!(store.len() <= SHARDS * 2);
+
+        // The most recent key is kept, and still limited.
+        assert!(store.take("ip:999", interval, 1).is_err());
+    }
+
+    #[test]
+    fn sweeps_a_few_refilled_keys_at_a_time() {
+        let mut shard = Shard::default();
+        let now = Instant::now();
+        for n in 0..10 {
+            shard.touch(&format!("ip:{}", n), now);
+        }
+        shard.touch("ip:busy", now + Duration::from_secs(60));
+
+        shard.sweep(now);
+        assert_eq!(shard.tats.len(), 11 - SWEEP_STEP);
+        shard.sweep(now);
+        shard.sweep(now);
+        assert_eq!(shard.tats.keys().collect::<Vec<_>>(), vec!["ip:busy"]);
+        assert_eq!(shard.recent.len(), 1);
+    }
+}
@@ -0,0 +1,449 @@
+//! Security headers.
+//!
+//! Every response gets a Content-Security-Policy along with X-Frame-Options,
+//! Referrer-Policy, Permissions-Policy, X-Content-Type-Options and the
+//! cross-origin isolation headers, unless the handler set its own. `{nonce}`
+//! in the policy is replaced with a fresh value per request, which templates
+//! put on inline scripts and styles:
+//!
+//! ```html
+//! <script nonce="{{ self::csp_nonce() }}">...</script>
+//! ```
+//!
+//! Browsers send violations to [`REPORT_PATH`], which saves them to Postgres;
+//! [`PruneCspReports`] deletes them once `csp_report_retention_days` old.
+//! With `csp_report_only` the policy is reported but not enforced, to try a
+//! stricter one without breaking pages.
+
+use crate::config::AppConfig;
+use crate::error::{Error, FieldError};
+use crate::models::csp_reports::{self, CspReport};
+use crate::mpsc::jobs::{Job, JobContext};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{Extension, Request, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Where browsers post violation reports.
+pub const REPORT_PATH: &str = "/csp-report";
+
+/// The name of [`REPORT_PATH`] in `Reporting-Endpoints`.
+const REPORT_GROUP: &str = "csp-endpoint";
+
+/// Violations saved from one report body; browsers batch a few at most.
+const MAX_REPORTS: usize = 10;
+
+tokio::task_local! {
+    static CURRENT_NONCE: CspNonce;
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityConfig {
+    pub enabled: bool,
+    /// The policy; `{nonce}` becomes the request's nonce and `{assets}` the
+    /// origin assets are served from, when not this server. Empty leaves the
+    /// header out.
+    pub csp: String,
+    /// Send the policy as `Content-Security-Policy-Report-Only`.
+    pub csp_report_only: bool,
+    /// Ask browsers to post violations to [`REPORT_PATH`].
+    pub csp_report: bool,
+    /// Days [`PruneCspReports`] keeps reports for; 0 keeps them forever.
+    pub csp_report_retention_days: u32,
+    /// The remaining headers are sent as they are; empty leaves one out.
+    pub frame_options: HeaderValue,
+    pub referrer_policy: HeaderValue,
+    pub permissions_policy: HeaderValue,
+    pub cross_origin_opener_policy: HeaderValue,
+    pub cross_origin_embedder_policy: HeaderValue,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            csp: "default-src 'self'; script-src 'self' {assets} 'nonce-{nonce}'; \
+                  style-src 'self' {assets} 'nonce-{nonce}'; img-src 'self' {assets} data:; \
+                  font-src 'self' {assets}; object-src 'none'; base-uri 'self'; \
+                  form-action 'self'; frame-ancestors 'none'"
+                .to_string(),
+            csp_report_only: false,
+            csp_report: true,
+            csp_report_retention_days: 30,
+            frame_options: HeaderValue::from_static("DENY"),
+            referrer_policy: HeaderValue::from_static("strict-origin-when-cross-origin"),
+            permissions_policy: HeaderValue::from_static(
+                "camera=(), microphone=(), geolocation=(), payment=()",
+            ),
+            cross_origin_opener_policy: HeaderValue::from_static("same-origin"),
+            // `require-corp` blocks assets from the public server, which sends
+            // no Cross-Origin-Resource-Policy.
+            cross_origin_embedder_policy: HeaderValue::from_static(""),
+        }
+    }
+}
+
+/// A random value allowing the inline scripts and styles that carry it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(pub String);
+
+impl CspNonce {
+    fn generate() -> Self {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+
+        CspNonce(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// The nonce of the request being handled, if it passed through
+    /// [`apply`].
+    pub fn current() -> Option<CspNonce> {
+        CURRENT_NONCE.try_with(|nonce| nonce.clone()).ok()
+    }
+}
+
+/// The headers of a [`SecurityConfig`], ready to add to responses.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    enabled: bool,
+    /// The policy with `{assets}` filled in.
+    csp: Option<String>,
+    csp_header: HeaderName,
+    fixed: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl SecurityHeaders {
+    /// `asset_base_url` is `assets.base_url`, whose origin `{assets}` stands for.
+    pub fn new(config: &SecurityConfig, asset_base_url: &str) -> Self {
+        let mut csp = config.csp.replace(
+            "{assets}",
+            &asset_origin(asset_base_url).unwrap_or_default(),
+        );
+        if config.csp_report {
+            csp = format!(
+                "{}; report-uri {}; report-to {}",
+                csp.trim_end_matches([';', ' ']),
+                REPORT_PATH,
+                REPORT_GROUP
+            );
+        }
+
+        let mut fixed = vec![(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        )];
+        if config.csp_report && !config.csp.is_empty() {
+            let endpoint = format!("{}=\"{}\"", REPORT_GROUP, REPORT_PATH);
+            fixed.push((
+                HeaderName::from_static("reporting-endpoints"),
+                HeaderValue::from_str(&endpoint).expect("The endpoint is a valid header"),
+            ));
+        }
+        for (name, value) in [
+            (header::X_FRAME_OPTIONS, &config.frame_options),
+            (header::REFERRER_POLICY, &config.referrer_policy),
+            (
+                HeaderName::from_static("permissions-policy"),
+                &config.permissions_policy,
+            ),
+            (
+                HeaderName::from_static("cross-origin-opener-policy"),
+                &config.cross_origin_opener_policy,
+            ),
+            (
+                HeaderName::from_static("cross-origin-embedder-policy"),
+                &config.cross_origin_embedder_policy,
+            ),
+        ] {
+            if !value.is_empty() {
+                fixed.push((name, value.clone()));
+            }
+        }
+
+        Self {
+            enabled: config.enabled,
+            csp: (!config.csp.is_empty()).then_some(csp),
+            csp_header: match config.csp_report_only {
+                true => header::CONTENT_SECURITY_POLICY_REPORT_ONLY,
+                false => header::CONTENT_SECURITY_POLICY,
+            },
+            fixed,
+        }
+    }
+
+    /// The policy for `nonce`, or why it is not a valid header.
+    pub fn csp(&self, nonce: &CspNonce) -> Result<Option<HeaderValue>, String> {
+        let Some(csp) = &self.csp else {
+            return Ok(None);
+        };
+
+        HeaderValue::from_str(&csp.replace("{nonce}", &nonce.0))
+            .map(Some)
+            .map_err(|err| format!("invalid policy: {}", err))
+    }
+
+    fn add_to(&self, headers: &mut HeaderMap, nonce: &CspNonce) {
+        if let Ok(Some(csp)) = self.csp(nonce) {
+            headers.entry(self.csp_header.clone()).or_insert(csp);
+        }
+        for (name, value) in &self.fixed {
+            headers.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+/// `https://cdn.example.com` for `https://cdn.example.com/public`; `None` for
+/// a path on this server.
+fn asset_origin(base_url: &str) -> Option<String> {
+    let (scheme, rest) = base_url.split_once("://")?;
+    let host = rest.split('/').next().filter(|host| !host.is_empty())?;
+
+    Some(format!("{}://{}", scheme, host))
+}
+
+/// Give the request a [`CspNonce`], available to templates while it is
+/// handled and as an extension, and add the security headers to its response.
+/// Does nothing when the headers are disabled.
+pub async fn apply(
+    State(headers): State<Arc<SecurityHeaders>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !headers.enabled {
+        return next.run(request).await;
+    }
+
+    let nonce = CspNonce::generate();
+    request.extensions_mut().insert(nonce.clone());
+
+    let mut response = CURRENT_NONCE.scope(nonce.clone(), next.run(request)).await;
+    headers.add_to(response.headers_mut(), &nonce);
+
+    response
+}
+
+/// Save the violations in a report sent by a browser.
+pub async fn csp_report(
+    Extension(config): Extension<AppConfig>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, Error> {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let reports = parse_reports(&body)
+        .map_err(|message| Error::validation(vec![FieldError::new("body", message)]))?;
+
+    for mut report in reports {
+        tracing::warn!(
+            "CSP violation of {} by {:?} on {}",
+            report.effective_directive,
+            report.blocked_uri,
+            report.document_uri
+        );
+        report.user_agent.clone_from(&user_agent);
+        if let Some(pool) = &config.pg_pool {
+            csp_reports::insert(pool, &report).await?;
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Violations from a `report-uri` body, `{"csp-report": {...}}`, or a
+/// Reporting API one, `[{"type": "csp-violation", "body": {...}}]`. Only the
+/// first [`MAX_REPORTS`] are kept.
+fn parse_reports(body: &[u8]) -> Result<Vec<CspReport>, String> {
+    let value: Value =
+        serde_json::from_slice(body).map_err(|err| format!("expected JSON: {}", err))?;
+
+    if let Some(report) = value.get("csp-report") {
+        let field = |key: &str| report.get(key);
+        return Ok(vec![CspReport {
+            document_uri: text(field("document-uri")).unwrap_or_default(),
+            blocked_uri: text(field("blocked-uri")).unwrap_or_default(),
+            effective_directive: text(field("effective-directive"))
+                .or_else(|| text(field("violated-directive")))
+                .unwrap_or_default(),
+            disposition: text(field("disposition")).unwrap_or_else(|| "enforce".to_string()),
+            source_file: text(field("source-file")),
+            line_number: number(field("line-number")),
+            column_number: number(field("column-number")),
+            sample: text(field("script-sample")),
+            user_agent: None,
+            body: report.clone(),
+        }]);
+    }
+
+    let Value::Array(items) = value else {
+        return Err("expected a csp-report object or an array of reports".to_string());
+    };
+    Ok(items
+        .iter()
+        .filter(|item| item.get("type").and_then(Value::as_str) == Some("csp-violation"))
+        .filter_map(|item| item.get("body"))
+        .take(MAX_REPORTS)
+        .map(|report| {
+            let field = |key: &str| report.get(key);
+            CspReport {
+                document_uri: text(field("documentURL")).unwrap_or_default(),
+                blocked_uri: text(field("blockedURL")).unwrap_or_default(),
+                effective_directive: text(field("effectiveDirective")).unwrap_or_default(),
+                disposition: text(field("disposition")).unwrap_or_else(|| "enforce".to_string()),
+                source_file: text(field("sourceFile")),
+                line_number: number(field("lineNumber")),
+                column_number: number(field("columnNumber")),
+                sample: text(field("sample")),
+                user_agent: None,
+                body: report.clone(),
+            }
+        })
+        .collect())
+}
+
+/// Delete CSP reports older than `security.csp_report_retention_days`;
+/// schedule it as a recurring task.
+pub struct PruneCspReports;
+
+#[async_trait]
+impl Job for PruneCspReports {
+    const NAME: &'static str = "prune_csp_reports";
+    type Payload = ();
+
+    async fn run(&self, ctx: &JobContext, _payload: ()) -> Result<Value, Error> {
+        let days = ctx.config.security.csp_report_retention_days;
+        if days == 0 {
+            return Ok(Value::from(0));
+        }
+        let pool = ctx
+            .config
+            .pg_pool
+            .as_ref()
+            .ok_or_else(|| Error::new("Postgres is not configured"))?;
+        let deleted = csp_reports::delete_older_than(pool, days).await?;
+
+        Ok(Value::from(deleted))
+    }
+}
+
+fn text(value: Option<&Value>) -> Option<String> {
+    value
+        .and_then(Value::as_str)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string)
+}
+
+fn number(value: Option<&Value>) -> Option<i32> {
+    value
+        .and_then(Value::as_i64)
+        .and_then(|number| i32::try_from(number).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use axum::{routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn adds_headers_with_the_template_nonce() {
+        let config = SecurityConfig {
+            csp_report_only: true,
+            ..SecurityConfig::default()
+        };
+        let headers = Arc::new(SecurityHeaders::new(
+            &config,
+            "http://localhost:9002/public",
+        ));
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { crate::content::templates::csp_nonce() }),
+            )
+            .route(
+                "/framed",
+                get(|| async { ([(header::X_FRAME_OPTIONS, "SAMEORIGIN")], "") }),
+            )
+            .layer(axum::middleware::from_fn_with_state(headers, apply));
+
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/").await.unwrap();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let nonce = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(nonce.len(), 32);
+
+        assert!(!headers.contains_key(header::CONTENT_SECURITY_POLICY));
+        let csp = headers[header::CONTENT_SECURITY_POLICY_REPORT_ONLY]
+            .to_str()
+            .unwrap();
+        assert!(csp.contains(&format!(
+            "script-src 'self' http://localhost:9002 'nonce-{}'",
+            nonce
+        )));
+        assert!(csp.ends_with("; report-uri /csp-report; report-to csp-endpoint"));
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers["cross-origin-opener-policy"], "same-origin");
+        assert!(!headers.contains_key("cross-origin-embedder-policy"));
+
+        let response = get("/framed").await.unwrap();
+        assert_eq!(response.headers()[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        let csp = response.headers()[header::CONTENT_SECURITY_POLICY_REPORT_ONLY]
+            .to_str()
+            .unwrap();
+        assert!(!csp.contains(&nonce));
+    }
+
+    #[test]
+    fn parses_both_report_formats() {
+        let legacy = br#"{"csp-report": {
+            "document-uri": "https://example.com/about",
+            "blocked-uri": "inline",
+            "violated-directive": "script-src-elem",
+            "line-number": 12,
+            "script-sample": "alert(1)"
+        }}"#;
+        let reports = parse_reports(legacy).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].effective_directive, "script-src-elem");
+        assert_eq!(reports[0].disposition, "enforce");
+        assert_eq!(reports[0].line_number, Some(12));
+        assert_eq!(reports[0].sample.as_deref(), Some("alert(1)"));
+
+        let reporting_api = br#"[
+            {"type": "csp-violation", "body": {
+                "documentURL": "https://example.com/",
+                "blockedURL": "https://evil.test/x.js",
+                "effectiveDirective": "script-src-elem",
+                "disposition": "report"
+            }},
+            {"type": "deprecation", "body": {}}
+        ]"#;
+        let reports = parse_reports(reporting_api).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].blocked_uri, "https://evil.test/x.js");
+        assert_eq!(reports[0].disposition, "report");
+
+        assert!(parse_reports(b"{}").is_err());
+
+        let flood = format!(
+            "[{}]",
+            vec![r#"{"type": "csp-violation", "body": {}}"#; 50].join(",")
+        );
+        assert_eq!(parse_reports(flood.as_bytes()).unwrap().len(), MAX_REPORTS);
+    }
+}
@@ -0,0 +1,285 @@
+//! Optional HTTPS for the API server.
+//!
+//! Setting `tls.cert_path` and `tls.key_path` serves the API over rustls. The
+//! PEM files are checked for changes every `tls.reload_interval` seconds and
+//! reloaded in place, so renewed certificates are picked up without a
+//! restart. A plain HTTP listener on `tls.redirect_port` can send browsers to
+//! HTTPS, and responses carry `Strict-Transport-Security`.
+
+use super::common::NetworkAddr;
+use crate::error::Error;
+use axum::http::{header, uri::Authority, HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain; HTTPS is enabled when this and `key_path` are set.
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    /// Port of a plain HTTP listener redirecting to HTTPS; none when unset.
+    pub redirect_port: Option<u16>,
+    /// Seconds browsers should insist on HTTPS; 0 leaves the header out.
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    /// Seconds between checks of the certificate files for changes.
+    pub reload_interval: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            redirect_port: None,
+            hsts_max_age: 365 * 24 * 60 * 60,
+            hsts_include_subdomains: false,
+            reload_interval: 30,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.paths().is_some()
+    }
+
+    pub fn paths(&self) -> Option<(&Path, &Path)> {
+        match (&self.cert_path, &self.key_path) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => None,
+        }
+    }
+
+    /// The `Strict-Transport-Security` value to send, if any.
+    pub fn hsts_header(&self) -> Option<HeaderValue> {
+        if !self.enabled() || self.hsts_max_age == 0 {
+            return None;
+        }
+
+        let mut value = format!("max-age={}", self.hsts_max_age);
+        if self.hsts_include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+
+        HeaderValue::from_str(&value).ok()
+    }
+}
+
+/// Load the configured certificate, or `None` when TLS is disabled.
+pub async fn rustls_config(config: &TlsConfig) -> Result<Option<RustlsConfig>, Error> {
+    let Some((cert, key)) = config.paths() else {
+        return Ok(None);
+    };
+
+    RustlsConfig::from_pem_file(cert, key)
+        .await
+        .map(Some)
+        .map_err(|err| {
+            Error::new(format!(
+                "Unable to load TLS certificate {} with key {}: {}",
+                cert.display(),
+                key.display(),
+                err
+            ))
+        })
+}
+
+/// Reload `rustls` whenever the certificate or key file changes, until the
+/// task is aborted. A failed reload keeps serving the previous certificate.
+pub async fn watch(rustls: RustlsConfig, cert: PathBuf, key: PathBuf, every: Duration) {
+    let mut seen = fingerprint(&cert, &key);
+    let mut ticker = tokio::time::interval(every);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        ticker.tick().await;
+        let current = fingerprint(&cert, &key);
+        if current == seen {
+            continue;
+        }
+        // Remember the change even if it fails to load, so that a half-written
+        // pair is retried only once the other file changes too.
+        seen = current;
+
+        match rustls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => tracing::info!("Reloaded TLS certificate {}", cert.display()),
+            Err(err) => tracing::error!(
+                "Unable to reload TLS certificate {}, keeping the previous one: {}",
+                cert.display(),
+                err
+            ),
+        }
+    }
+}
+
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn fingerprint(cert: &Path, key: &Path) -> (FileStamp, FileStamp) {
+    let stamp = |path: &Path| {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    };
+
+    (stamp(cert), stamp(key))
+}
+
+/// Redirect every plain HTTP request to the same URL on `https_port`, until
+/// `handle` is told to shut down.
+pub async fn serve_redirect(
+    addr: NetworkAddr<'_>,
+    https_port: u16,
+    handle: Handle,
+) -> Result<(), Error> {
+    let listener = addr.bind()?;
+    tracing::info!("Redirecting HTTP on {} to HTTPS", addr);
+
+    let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        redirect(&headers, &uri, https_port)
+    });
+    axum_server::from_tcp(listener)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    match https_url(headers, uri, https_port) {
+        Some(url) => Redirect::permanent(&url).into_response(),
+        None => (StatusCode::BAD_REQUEST, "Missing or invalid Host header").into_response(),
+    }
+}
+
+fn https_url(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Option<String> {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Authority>().ok())?;
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+
+    Some(match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::CertifiedKey;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    fn self_signed(name: &str) -> CertifiedKey {
+        rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap()
+    }
+
+    fn write_pair(dir: &Path, pair: &CertifiedKey) -> (PathBuf, PathBuf) {
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, pair.cert.pem()).unwrap();
+        std::fs::write(&key, pair.key_pair.serialize_pem()).unwrap();
+
+        (cert, key)
+    }
+
+    /// Handshake with the server, trusting `roots`, and return the certificate
+    /// it presented.
+    async fn presented(port: u16, roots: &[&CertifiedKey]) -> CertificateDer<'static> {
+        let mut store = rustls::RootCertStore::empty();
+        for root in roots {
+            store.add(root.cert.der().clone()).unwrap();
+        }
+        let client = rustls::ClientConfig::builder()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let tls = TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        tls.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn reloads_certificates_when_the_files_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (self_signed("localhost"), self_signed("localhost"));
+        let (cert, key) = write_pair(dir.path(), &first);
+
+        let config = TlsConfig {
+            cert_path: Some(cert.clone()),
+            key_path: Some(key.clone()),
+            ..TlsConfig::default()
+        };
+        let rustls = rustls_config(&config).await.unwrap().unwrap();
+        let watcher = tokio::spawn(watch(rustls.clone(), cert, key, Duration::from_millis(20)));
+
+        let listener = NetworkAddr::new("127.0.0.1", 0).bind().unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = Handle::new();
+        let server = tokio::spawn(
+            axum_server::from_tcp_rustls(listener, rustls)
+                .handle(handle.clone())
+                .serve(Router::new().into_make_service()),
+        );
+
+        let roots = [&first, &second];
+        assert_eq!(&presented(port, &roots).await, first.cert.der());
+
+        write_pair(dir.path(), &second);
+        let mut reloaded = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if &presented(port, &roots).await == second.cert.der() {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded, "the new certificate was never served");
+
+        watcher.abort();
+        handle.shutdown();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn redirects_to_the_https_port() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("example.com:8080"));
+        let uri: Uri = "/about?lang=fr".parse().unwrap();
+
+        assert_eq!(
+            https_url(&headers, &uri, 443).unwrap(),
+            "https://example.com/about?lang=fr"
+        );
+        assert_eq!(
+            https_url(&headers, &uri, 8443).unwrap(),
+            "https://example.com:8443/about?lang=fr"
+        );
+        assert!(https_url(&HeaderMap::new(), &uri, 443).is_none());
+    }
+
+    #[test]
+    fn hsts_only_with_tls() {
+        let mut config = TlsConfig::default();
+        assert!(config.hsts_header().is_none());
+
+        config.cert_path = Some("cert.pem".into());
+        config.key_path = Some("key.pem".into());
+        config.hsts_include_subdomains = true;
+        assert_eq!(
+            config.hsts_header().unwrap(),
+            "max-age=31536000; includeSubDomains"
+        );
+    }
+}
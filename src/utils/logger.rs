@@ -4,6 +4,7 @@ use tracing;
 
 pub enum Level {
     Info,
+    Warn,
     Error,
 }
 
@@ -26,6 +27,9 @@ pub fn log(l: Level, c: impl ColorTrait, tag: Tag, message: Text) {
         Level::Info => {
             tracing::info!("{}: {}", colored(color, tag.0), message.0);
         }
+        Level::Warn => {
+            tracing::warn!("{}: {}", colored(color, tag.0), message.0);
+        }
         Level::Error => {
             tracing::error!("{}: {}", colored(color, tag.0), message.0);
         }